@@ -0,0 +1,521 @@
+//! libp2p identities: Ed25519 / secp256k1 keypairs, the protobuf public-key
+//! encoding from the peer-id spec, and the `PeerId` derived from it.
+
+use std::{fmt, fs, io::Write, path::Path, str::FromStr};
+
+use ed25519_dalek::{
+    Signer as _, Verifier as _,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, spki::der::pem::LineEnding},
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    protobuf::{self, FieldValue},
+    varint,
+};
+
+/// Multihash code of the identity "hash" (the digest is the input itself).
+const MULTIHASH_IDENTITY: u64 = 0x00;
+/// Multihash code of sha2-256.
+const MULTIHASH_SHA256: u64 = 0x12;
+/// Encoded public keys up to this size are inlined into the PeerId.
+const MAX_INLINE_KEY_LEN: usize = 42;
+/// Multicodec of `libp2p-key`, used in the CID form of a PeerId.
+const LIBP2P_KEY_CODEC: u64 = 0x72;
+const CID_VERSION: u64 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IdentityError {
+    #[error("unsupported key type {0}")]
+    UnsupportedKeyType(u64),
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("malformed protobuf: {0}")]
+    Protobuf(#[from] protobuf::DecodeError),
+    #[error("invalid peer id: {0}")]
+    InvalidPeerId(String),
+    #[error("invalid PEM/PKCS#8 key: {0}")]
+    Pem(String),
+    #[error("key file i/o error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// On-disk formats for a node's private key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// The libp2p `PrivateKey { Type, Data }` protobuf (binary).
+    Protobuf,
+    /// PKCS#8 in a PEM envelope; Ed25519 only.
+    Pem,
+}
+
+impl KeyFormat {
+    /// `.pem` files hold PEM, anything else the libp2p protobuf.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("pem") => KeyFormat::Pem,
+            _ => KeyFormat::Protobuf,
+        }
+    }
+}
+
+/// Key types from the `KeyType` enum of the libp2p key protobuf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyType {
+    Ed25519 = 1,
+    Secp256k1 = 2,
+}
+
+impl KeyType {
+    fn from_protobuf(value: u64) -> Result<Self, IdentityError> {
+        match value {
+            1 => Ok(KeyType::Ed25519),
+            2 => Ok(KeyType::Secp256k1),
+            other => Err(IdentityError::UnsupportedKeyType(other)),
+        }
+    }
+}
+
+/// A node's long-term identity keypair.
+#[derive(Clone)]
+pub enum Keypair {
+    Ed25519(ed25519_dalek::SigningKey),
+    Secp256k1(k256::ecdsa::SigningKey),
+}
+
+impl Keypair {
+    pub fn generate_ed25519() -> Self {
+        let mut secret = [0u8; 32];
+        rand::rng().fill_bytes(&mut secret);
+        Keypair::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret))
+    }
+
+    pub fn generate_secp256k1() -> Self {
+        // Rejection-sample until the bytes form a valid scalar (0 < k < n).
+        loop {
+            let mut secret = [0u8; 32];
+            rand::rng().fill_bytes(&mut secret);
+            if let Ok(key) = k256::ecdsa::SigningKey::from_slice(&secret) {
+                return Keypair::Secp256k1(key);
+            }
+        }
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            Keypair::Ed25519(_) => KeyType::Ed25519,
+            Keypair::Secp256k1(_) => KeyType::Secp256k1,
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        match self {
+            Keypair::Ed25519(key) => PublicKey::Ed25519(key.verifying_key()),
+            Keypair::Secp256k1(key) => PublicKey::Secp256k1(*key.verifying_key()),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.public().to_peer_id()
+    }
+
+    /// Encode as the libp2p `PrivateKey` protobuf. Ed25519 keys carry the
+    /// 64-byte `secret || public` form, secp256k1 keys the 32-byte scalar.
+    pub fn to_protobuf_encoding(&self) -> Vec<u8> {
+        let data = match self {
+            Keypair::Ed25519(key) => key.to_keypair_bytes().to_vec(),
+            Keypair::Secp256k1(key) => key.to_bytes().to_vec(),
+        };
+        let mut out = Vec::new();
+        protobuf::put_varint_field(&mut out, 1, self.key_type() as u64);
+        protobuf::put_bytes_field(&mut out, 2, &data);
+        out
+    }
+
+    pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<Self, IdentityError> {
+        let (key_type, data) = decode_key_protobuf(bytes)?;
+        match key_type {
+            KeyType::Ed25519 => {
+                let key = match data.len() {
+                    64 => ed25519_dalek::SigningKey::from_keypair_bytes(
+                        data.try_into().expect("length checked"),
+                    )
+                    .map_err(|e| IdentityError::InvalidKey(e.to_string()))?,
+                    32 => ed25519_dalek::SigningKey::from_bytes(
+                        data.try_into().expect("length checked"),
+                    ),
+                    n => {
+                        return Err(IdentityError::InvalidKey(format!(
+                            "ed25519 private key of {n} bytes"
+                        )));
+                    }
+                };
+                Ok(Keypair::Ed25519(key))
+            }
+            KeyType::Secp256k1 => k256::ecdsa::SigningKey::from_slice(data)
+                .map(Keypair::Secp256k1)
+                .map_err(|e| IdentityError::InvalidKey(e.to_string())),
+        }
+    }
+
+    /// PKCS#8 PEM encoding; only Ed25519 keys have one.
+    pub fn to_pkcs8_pem(&self) -> Result<String, IdentityError> {
+        match self {
+            Keypair::Ed25519(key) => key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map(|pem| pem.to_string())
+                .map_err(|e| IdentityError::Pem(e.to_string())),
+            Keypair::Secp256k1(_) => Err(IdentityError::Pem(
+                "PEM export is only supported for ed25519 keys".to_string(),
+            )),
+        }
+    }
+
+    pub fn from_pkcs8_pem(pem: &str) -> Result<Self, IdentityError> {
+        ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+            .map(Keypair::Ed25519)
+            .map_err(|e| IdentityError::Pem(e.to_string()))
+    }
+
+    /// Load a key written by `save_to_file`. PEM is recognised by its
+    /// `-----BEGIN` header, anything else is parsed as the libp2p protobuf.
+    pub fn load_from_file(path: &Path) -> Result<Self, IdentityError> {
+        warn_if_readable_by_others(path);
+        let bytes = fs::read(path)?;
+        if bytes.starts_with(b"-----BEGIN") {
+            let pem = std::str::from_utf8(&bytes).map_err(|e| IdentityError::Pem(e.to_string()))?;
+            Self::from_pkcs8_pem(pem)
+        } else {
+            Self::from_protobuf_encoding(&bytes)
+        }
+    }
+
+    /// Write the key to a new file that only the owner can read. Refuses to
+    /// overwrite an existing file.
+    pub fn save_to_file(&self, path: &Path, format: KeyFormat) -> Result<(), IdentityError> {
+        let encoded = match format {
+            KeyFormat::Protobuf => self.to_protobuf_encoding(),
+            KeyFormat::Pem => self.to_pkcs8_pem()?.into_bytes(),
+        };
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Load the key at `path`, or generate an Ed25519 key and save it there
+    /// (in the format implied by the extension) on first run.
+    pub fn load_or_generate(path: &Path) -> Result<Self, IdentityError> {
+        if path.exists() {
+            return Self::load_from_file(path);
+        }
+        let keypair = Keypair::generate_ed25519();
+        keypair.save_to_file(path, KeyFormat::for_path(path))?;
+        Ok(keypair)
+    }
+
+    /// Sign `msg`. Ed25519 signs the message directly; secp256k1 produces a
+    /// DER-encoded ECDSA signature over `sha256(msg)`, as libp2p expects.
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            Keypair::Ed25519(key) => key.sign(msg).to_bytes().to_vec(),
+            Keypair::Secp256k1(key) => {
+                let sig: k256::ecdsa::Signature = k256::ecdsa::signature::Signer::sign(key, msg);
+                sig.to_der().as_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The public half of a `Keypair`.
+#[derive(Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Secp256k1(k256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1,
+        }
+    }
+
+    /// Raw key bytes: 32 bytes for Ed25519, the 33-byte compressed point for secp256k1.
+    pub fn to_raw_bytes(&self) -> Vec<u8> {
+        match self {
+            PublicKey::Ed25519(key) => key.to_bytes().to_vec(),
+            PublicKey::Secp256k1(key) => key.to_encoded_point(true).as_bytes().to_vec(),
+        }
+    }
+
+    pub fn from_raw_bytes(key_type: KeyType, bytes: &[u8]) -> Result<Self, IdentityError> {
+        match key_type {
+            KeyType::Ed25519 => {
+                let bytes: [u8; 32] = bytes.try_into().map_err(|_| {
+                    IdentityError::InvalidKey(format!("ed25519 key of {} bytes", bytes.len()))
+                })?;
+                ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                    .map(PublicKey::Ed25519)
+                    .map_err(|e| IdentityError::InvalidKey(e.to_string()))
+            }
+            KeyType::Secp256k1 => k256::ecdsa::VerifyingKey::from_sec1_bytes(bytes)
+                .map(PublicKey::Secp256k1)
+                .map_err(|e| IdentityError::InvalidKey(e.to_string())),
+        }
+    }
+
+    /// Encode as the libp2p `PublicKey { Type, Data }` protobuf.
+    pub fn encode_protobuf(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        protobuf::put_varint_field(&mut out, 1, self.key_type() as u64);
+        protobuf::put_bytes_field(&mut out, 2, &self.to_raw_bytes());
+        out
+    }
+
+    pub fn try_decode_protobuf(bytes: &[u8]) -> Result<Self, IdentityError> {
+        let (key_type, data) = decode_key_protobuf(bytes)?;
+        Self::from_raw_bytes(key_type, data)
+    }
+
+    /// Check `sig` over `msg`, see `Keypair::sign` for the signature formats.
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        match self {
+            PublicKey::Ed25519(key) => ed25519_dalek::Signature::from_slice(sig)
+                .map(|sig| key.verify(msg, &sig).is_ok())
+                .unwrap_or(false),
+            PublicKey::Secp256k1(key) => k256::ecdsa::Signature::from_der(sig)
+                .map(|sig| k256::ecdsa::signature::Verifier::verify(key, msg, &sig).is_ok())
+                .unwrap_or(false),
+        }
+    }
+
+    pub fn to_peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PublicKey::{:?}({})",
+            self.key_type(),
+            hex(&self.to_raw_bytes())
+        )
+    }
+}
+
+/// Split a libp2p key protobuf (public or private, same layout) into its parts.
+pub(crate) fn decode_key_protobuf(bytes: &[u8]) -> Result<(KeyType, &[u8]), IdentityError> {
+    let mut key_type = None;
+    let mut data = None;
+    for field in protobuf::fields(bytes) {
+        match field? {
+            (1, FieldValue::Varint(v)) => key_type = Some(KeyType::from_protobuf(v)?),
+            (2, FieldValue::Bytes(b)) => data = Some(b),
+            _ => {}
+        }
+    }
+    match (key_type, data) {
+        (Some(key_type), Some(data)) => Ok((key_type, data)),
+        _ => Err(IdentityError::InvalidKey(
+            "missing Type or Data field".to_string(),
+        )),
+    }
+}
+
+/// Identifier of a peer: the multihash of its protobuf-encoded public key.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId {
+    multihash: Vec<u8>,
+}
+
+impl PeerId {
+    pub fn from_public_key(key: &PublicKey) -> Self {
+        let encoded = key.encode_protobuf();
+        let mut multihash = Vec::with_capacity(2 + 32);
+        if encoded.len() <= MAX_INLINE_KEY_LEN {
+            varint::encode(MULTIHASH_IDENTITY, &mut multihash);
+            varint::encode(encoded.len() as u64, &mut multihash);
+            multihash.extend_from_slice(&encoded);
+        } else {
+            let digest = Sha256::digest(&encoded);
+            varint::encode(MULTIHASH_SHA256, &mut multihash);
+            varint::encode(digest.len() as u64, &mut multihash);
+            multihash.extend_from_slice(&digest);
+        }
+        PeerId { multihash }
+    }
+
+    /// Parse the binary multihash form.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, IdentityError> {
+        let invalid = |msg: &str| IdentityError::InvalidPeerId(msg.to_string());
+
+        let (code, n) = varint::decode(bytes).map_err(|e| invalid(&e.to_string()))?;
+        let (len, m) = varint::decode(&bytes[n..]).map_err(|e| invalid(&e.to_string()))?;
+        let digest = &bytes[n + m..];
+        if digest.len() as u64 != len {
+            return Err(invalid("multihash length mismatch"));
+        }
+        match code {
+            MULTIHASH_IDENTITY if digest.len() <= MAX_INLINE_KEY_LEN => {}
+            MULTIHASH_SHA256 if digest.len() == 32 => {}
+            MULTIHASH_IDENTITY | MULTIHASH_SHA256 => return Err(invalid("bad digest length")),
+            _ => return Err(invalid("unsupported multihash")),
+        }
+        Ok(PeerId {
+            multihash: bytes.to_vec(),
+        })
+    }
+
+    /// The binary multihash form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.multihash.clone()
+    }
+
+    /// The public key, when it is inlined with the identity multihash.
+    pub fn as_public_key(&self) -> Option<PublicKey> {
+        let (code, n) = varint::decode(&self.multihash).ok()?;
+        let (_, m) = varint::decode(&self.multihash[n..]).ok()?;
+        if code != MULTIHASH_IDENTITY {
+            return None;
+        }
+        PublicKey::try_decode_protobuf(&self.multihash[n + m..]).ok()
+    }
+
+    pub fn is_public_key(&self, key: &PublicKey) -> bool {
+        *self == key.to_peer_id()
+    }
+
+    /// Legacy base58btc form, e.g. `12D3KooW...` or `Qm...`.
+    pub fn to_base58(&self) -> String {
+        bs58::encode(&self.multihash).into_string()
+    }
+
+    /// CIDv1 form (`libp2p-key` codec, base32 multibase), e.g. `bafzaa...`.
+    pub fn to_cid_string(&self) -> String {
+        let mut cid = Vec::with_capacity(2 + self.multihash.len());
+        varint::encode(CID_VERSION, &mut cid);
+        varint::encode(LIBP2P_KEY_CODEC, &mut cid);
+        cid.extend_from_slice(&self.multihash);
+        format!("b{}", base32_encode(&cid))
+    }
+
+    fn from_cid_str(s: &str) -> Result<Self, IdentityError> {
+        let invalid = |msg: &str| IdentityError::InvalidPeerId(msg.to_string());
+
+        let bytes = base32_decode(s).ok_or_else(|| invalid("invalid base32"))?;
+        let (version, n) = varint::decode(&bytes).map_err(|e| invalid(&e.to_string()))?;
+        let (codec, m) = varint::decode(&bytes[n..]).map_err(|e| invalid(&e.to_string()))?;
+        if version != CID_VERSION || codec != LIBP2P_KEY_CODEC {
+            return Err(invalid("not a CIDv1 libp2p-key"));
+        }
+        Self::from_bytes(&bytes[n + m..])
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base58())
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({self})")
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = IdentityError;
+
+    /// Accepts both the base58btc form and the CIDv1 (`b...`) form.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(cid) = s.strip_prefix('b') {
+            return Self::from_cid_str(cid);
+        }
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|e| IdentityError::InvalidPeerId(e.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// RFC 4648 base32, lowercase and unpadded, as used by multibase `b`.
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &byte in data {
+        acc = (acc << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = (acc << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    // Leftover bits are only padding for the last byte: fewer than one
+    // character's worth and all zero, so each byte string has one encoding.
+    (bits < 5 && acc == 0).then_some(out)
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = fs::metadata(path)
+        && meta.permissions().mode() & 0o077 != 0
+    {
+        eprintln!(
+            "[identity] warning: key file {} is accessible by other users (mode {:o})",
+            path.display(),
+            meta.permissions().mode() & 0o777
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
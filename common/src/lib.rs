@@ -1,80 +1,590 @@
-use std::{net::SocketAddr, sync::Arc};
+pub mod identity;
+pub mod multiaddr;
+pub mod noise;
+pub mod protobuf;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod varint;
+
+use std::{
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
 
-use snow::TransportState;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf, ReadHalf,
+        WriteHalf,
+    },
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
     sync::Mutex,
 };
 
+use crate::noise::{CipherState, NoiseTransport, TAG_LEN};
+
+/// Largest Noise transport message, ciphertext and tag included.
+pub const MAX_NOISE_FRAME_LEN: usize = 65535;
+/// Largest plaintext that fits in one Noise frame (the frame minus its 16-byte tag).
+pub const MAX_PLAINTEXT_LEN: usize = MAX_NOISE_FRAME_LEN - TAG_LEN;
+
+/// Reading side of a connection whose transport is only known at runtime.
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+/// Writing side of a connection whose transport is only known at runtime.
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A Noise-secured channel over any byte stream, framed as
+/// `u16 BE length || ciphertext`.
+///
+/// It can be used message-wise through `&self` (`send`, `recv`, `read`,
+/// `read_exact`), which lets one `Arc` be shared by a reader task and
+/// writers, or as a plain byte stream through `AsyncRead`/`AsyncWrite`.
+/// Each direction has its own cipher state, so readers and writers never
+/// wait on each other; `into_split` hands the two halves out separately.
 #[derive(Debug)]
-pub struct EncryptedStream {
-    pub noise: Mutex<TransportState>,
-    pub writer: Mutex<OwnedWriteHalf>,
-    pub reader: Mutex<OwnedReadHalf>,
+pub struct EncryptedStream<R = OwnedReadHalf, W = OwnedWriteHalf> {
+    reader: Mutex<EncryptedReadHalf<R>>,
+    writer: Mutex<EncryptedWriteHalf<W>>,
 }
 
-impl EncryptedStream {
-    pub async fn send(&self, msg: &[u8]) -> tokio::io::Result<()> {
-        println!("[send] Preparing to send message: {:?}", msg);
+impl<R, W> EncryptedStream<R, W> {
+    pub fn new(noise: NoiseTransport, reader: R, writer: W) -> Self {
+        Self {
+            reader: Mutex::new(EncryptedReadHalf::new(noise.recv, reader)),
+            writer: Mutex::new(EncryptedWriteHalf::new(noise.send, writer)),
+        }
+    }
 
-        let mut buf = [0u8; 4096];
-        let len;
-        {
-            println!("[send] Locking noise state for encryption");
-            let mut lock = self.noise.lock().await;
-            len = lock
-                .write_message(msg, &mut buf)
-                .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::Other, e))?;
-            println!("[send] Encrypted message length: {len}");
+    /// Turn on in-band rekeying and rekey the outgoing direction
+    /// automatically according to `policy`.
+    ///
+    /// This is not part of the libp2p Noise spec: an empty frame announces
+    /// the key switch, which a spec-compliant peer neither sends with that
+    /// meaning nor understands. Only enable it when both peers have agreed
+    /// to; without it, empty frames are ignored and `rekey` fails.
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.reader.get_mut().follow_rekeys = true;
+        self.writer.get_mut().rekey_policy = Some(policy);
+        self
+    }
+
+    /// Split into halves that can be owned by different tasks.
+    pub fn into_split(self) -> (EncryptedReadHalf<R>, EncryptedWriteHalf<W>) {
+        (self.reader.into_inner(), self.writer.into_inner())
+    }
+}
+
+impl<R, W> EncryptedStream<R, W>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    /// Erase the transport types, so code above the security layer can be
+    /// written once for every transport. Cipher and framing state carry over.
+    pub fn boxed(self) -> EncryptedStream<BoxedReader, BoxedWriter> {
+        let (reader, writer) = self.into_split();
+        EncryptedStream {
+            reader: Mutex::new(reader.map_io(|io| Box::new(io) as BoxedReader)),
+            writer: Mutex::new(writer.map_io(|io| Box::new(io) as BoxedWriter)),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> EncryptedStream<ReadHalf<S>, WriteHalf<S>> {
+    /// Secure a single duplex stream, e.g. a `TcpStream` or an in-memory pipe.
+    pub fn from_stream(noise: NoiseTransport, io: S) -> Self {
+        let (reader, writer) = tokio::io::split(io);
+        Self::new(noise, reader, writer)
+    }
+}
+
+impl<R, W> EncryptedStream<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// See `EncryptedWriteHalf::send`.
+    pub async fn send(&self, msg: &[u8]) -> io::Result<()> {
+        self.writer.lock().await.send(msg).await
+    }
+
+    /// See `EncryptedWriteHalf::rekey`.
+    pub async fn rekey(&self) -> io::Result<()> {
+        self.writer.lock().await.rekey().await
+    }
+
+    /// See `EncryptedReadHalf::recv`.
+    pub async fn recv(&self) -> io::Result<Vec<u8>> {
+        self.reader.lock().await.recv().await
+    }
+
+    /// See `EncryptedReadHalf::read`.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.lock().await.read(buf).await
+    }
+
+    /// See `EncryptedReadHalf::read_exact`.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.lock().await.read_exact(buf).await
+    }
+}
+
+impl<R, W> AsyncRead for EncryptedStream<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().reader.get_mut()).poll_read(cx, buf)
+    }
+}
+
+impl<R, W> AsyncWrite for EncryptedStream<R, W>
+where
+    R: Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.get_mut().writer.get_mut()).poll_write(cx, data)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().writer.get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().writer.get_mut()).poll_shutdown(cx)
+    }
+}
+
+/// Receiving half of an `EncryptedStream`.
+#[derive(Debug)]
+pub struct EncryptedReadHalf<R = OwnedReadHalf> {
+    frames: FrameReader<R>,
+    cipher: CipherState,
+    /// Whether an empty frame announces a rekey rather than carrying nothing.
+    follow_rekeys: bool,
+    /// Decrypted bytes handed out by neither `recv` nor `read` yet.
+    read_buf: Vec<u8>,
+}
+
+impl<R> EncryptedReadHalf<R> {
+    fn new(cipher: CipherState, io: R) -> Self {
+        Self {
+            frames: FrameReader::new(io),
+            cipher,
+            follow_rekeys: false,
+            read_buf: Vec::new(),
         }
+    }
 
-        println!("[send] Locking writer to send encrypted data");
-        let mut lock = self.writer.lock().await;
-        // len is a u16, so we can use `to_be_bytes()`
-        let len_bytes = (len as u16).to_be_bytes();
+    fn map_io<T>(self, f: impl FnOnce(R) -> T) -> EncryptedReadHalf<T> {
+        EncryptedReadHalf {
+            frames: self.frames.map_io(f),
+            cipher: self.cipher,
+            follow_rekeys: self.follow_rekeys,
+            read_buf: self.read_buf,
+        }
+    }
+}
 
-        // First, send the length of the upcoming message
-        println!("[send] Sending message length: {len}");
-        lock.write_all(&len_bytes).await?;
+impl<R: AsyncRead + Unpin> EncryptedReadHalf<R> {
+    /// Receive the next chunk of plaintext: whatever is left over from a
+    /// partial `read`, or else the payload of the next Noise frame. A message
+    /// larger than `MAX_PLAINTEXT_LEN` arrives over several calls; use
+    /// `read_exact` when the length is known up front.
+    pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        if !self.read_buf.is_empty() {
+            return Ok(std::mem::take(&mut self.read_buf));
+        }
+        self.recv_frame().await
+    }
 
-        // Then, send the encrypted message itself
-        println!("[send] Sending encrypted bytes");
-        lock.write_all(&buf[..len]).await?;
+    /// Read up to `buf.len()` bytes of plaintext, keeping the rest of the
+    /// frame for the next call. Returns 0 only if `buf` is empty.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_buf.is_empty() {
+            self.read_buf = self.recv_frame().await?;
+        }
+        let n = buf.len().min(self.read_buf.len());
+        buf[..n].copy_from_slice(&self.read_buf[..n]);
+        self.read_buf.drain(..n);
+        Ok(n)
+    }
 
-        println!("[send] Successfully sent {len} bytes");
+    /// Fill `buf` completely, reassembling it from as many frames as needed.
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += self.read(&mut buf[filled..]).await?;
+        }
         Ok(())
     }
 
-    pub async fn recv(&self) -> tokio::io::Result<Vec<u8>> {
-        println!("[recv] Waiting to read data from stream");
+    /// Read and decrypt the next Noise frame that carries data, skipping
+    /// empty ones (and following the rekeys they announce, if enabled).
+    async fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            println!("[recv] Waiting to read data from stream");
+            let frame = poll_fn(|cx| self.frames.poll_next_frame(cx))
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "early eof"))?;
+            println!("[recv] Read frame of {} encrypted bytes", frame.len());
 
-        let mut len_buf = [0u8; 2];
-        {
-            println!("[recv] Locking reader to fetch message length");
-            let mut lock = self.reader.lock().await;
-            lock.read_exact(&mut len_buf).await?;
+            let plaintext = self.decrypt(&frame)?;
+            if !plaintext.is_empty() {
+                println!("[recv] Successfully decrypted {} bytes", plaintext.len());
+                return Ok(plaintext);
+            }
+        }
+    }
+
+    /// Decrypt one frame. With rekeying enabled, an empty plaintext is the
+    /// peer announcing a rekey, so the incoming key is switched right after it.
+    fn decrypt(&mut self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; frame.len()];
+        let len = self
+            .cipher
+            .decrypt(frame, &mut buf)
+            .map_err(io::Error::other)?;
+        buf.truncate(len);
+        if buf.is_empty() && self.follow_rekeys {
+            self.cipher.rekey();
+            println!("[recv] Peer rekeyed, switched incoming cipher");
+        }
+        Ok(buf)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for EncryptedReadHalf<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
         }
-        let len = u16::from_be_bytes(len_buf) as usize;
-        println!("[recv] Received message length: {len}");
 
-        let mut msg = vec![0u8; len];
+        // Empty frames carry no data; reading nothing would look like EOF.
+        while this.read_buf.is_empty() {
+            match ready!(this.frames.poll_next_frame(cx))? {
+                Some(frame) => this.read_buf = this.decrypt(&frame)?,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = buf.remaining().min(this.read_buf.len());
+        buf.put_slice(&this.read_buf[..n]);
+        this.read_buf.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// When the sending side of an `EncryptedStream` switches to a fresh key.
+/// Only the sender needs a policy: every rekey is announced in-band, and a
+/// receiver with rekeying enabled follows it whatever its own policy says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Rekey after this many Noise messages under one key.
+    pub max_messages: Option<u64>,
+    /// Rekey after this many plaintext bytes under one key.
+    pub max_bytes: Option<u64>,
+}
+
+impl RekeyPolicy {
+    /// Never rekey automatically; `rekey()` still works.
+    pub const NEVER: Self = Self {
+        max_messages: None,
+        max_bytes: None,
+    };
+
+    fn is_due(&self, messages: u64, bytes: u64) -> bool {
+        self.max_messages.is_some_and(|max| messages >= max)
+            || self.max_bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+/// Sending half of an `EncryptedStream`.
+#[derive(Debug)]
+pub struct EncryptedWriteHalf<W = OwnedWriteHalf> {
+    frames: FrameWriter<W>,
+    cipher: CipherState,
+    /// `None` unless in-band rekeying was enabled.
+    rekey_policy: Option<RekeyPolicy>,
+    /// Messages and plaintext bytes sent under the current key.
+    messages_since_rekey: u64,
+    bytes_since_rekey: u64,
+}
+
+impl<W> EncryptedWriteHalf<W> {
+    fn new(cipher: CipherState, io: W) -> Self {
+        Self {
+            frames: FrameWriter::new(io),
+            cipher,
+            rekey_policy: None,
+            messages_since_rekey: 0,
+            bytes_since_rekey: 0,
+        }
+    }
+
+    fn map_io<T>(self, f: impl FnOnce(W) -> T) -> EncryptedWriteHalf<T> {
+        EncryptedWriteHalf {
+            frames: self.frames.map_io(f),
+            cipher: self.cipher,
+            rekey_policy: self.rekey_policy,
+            messages_since_rekey: self.messages_since_rekey,
+            bytes_since_rekey: self.bytes_since_rekey,
+        }
+    }
+
+    /// Queue one data frame, followed by a rekey if the policy says so.
+    fn push_data(&mut self, chunk: &[u8]) -> io::Result<usize> {
+        let len = self.frames.push_frame(&mut self.cipher, chunk)?;
+        self.messages_since_rekey += 1;
+        self.bytes_since_rekey += chunk.len() as u64;
+        if self
+            .rekey_policy
+            .is_some_and(|p| p.is_due(self.messages_since_rekey, self.bytes_since_rekey))
         {
-            println!("[recv] Locking reader to fetch incoming data");
-            let mut lock = self.reader.lock().await;
-            lock.read_exact(&mut msg).await?;
+            self.push_rekey()?;
         }
-        println!("[recv] Read {len} bytes: {:?}", &msg[..len]);
+        Ok(len)
+    }
+
+    /// Queue the rekey announcement (an empty frame under the old key) and
+    /// switch to the new key for everything after it.
+    fn push_rekey(&mut self) -> io::Result<()> {
+        self.frames.push_frame(&mut self.cipher, &[])?;
+        self.cipher.rekey();
+        self.messages_since_rekey = 0;
+        self.bytes_since_rekey = 0;
+        println!("[send] Rekeyed outgoing cipher");
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> EncryptedWriteHalf<W> {
+    /// Encrypt and send `msg`, split across as many maximum-size Noise frames
+    /// as it needs. Sending an empty `msg` does nothing, since the receiver
+    /// skips empty frames or, with rekeying enabled, reads one as a rekey.
+    pub async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let frames = msg.len().div_ceil(MAX_PLAINTEXT_LEN);
+        println!(
+            "[send] Preparing to send {} bytes in {frames} frame(s)",
+            msg.len()
+        );
+
+        // Finish whatever a cancelled send or a `poll_write` left behind.
+        poll_fn(|cx| self.frames.poll_drain(cx)).await?;
+
+        for chunk in msg.chunks(MAX_PLAINTEXT_LEN) {
+            let len = self.push_data(chunk)?;
+            poll_fn(|cx| self.frames.poll_drain(cx)).await?;
+            println!("[send] Sent frame of {len} encrypted bytes");
+        }
+        self.frames.io.flush().await?;
+
+        println!("[send] Successfully sent {} bytes", msg.len());
+        Ok(())
+    }
+
+    /// Switch the outgoing direction to a new key now. The peer switches its
+    /// incoming key at the same point in the stream. Fails with
+    /// `ErrorKind::Unsupported` unless rekeying was enabled.
+    pub async fn rekey(&mut self) -> io::Result<()> {
+        if self.rekey_policy.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-band rekeying is not enabled",
+            ));
+        }
+        poll_fn(|cx| self.frames.poll_drain(cx)).await?;
+        self.push_rekey()?;
+        poll_fn(|cx| self.frames.poll_drain(cx)).await?;
+        self.frames.io.flush().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptedWriteHalf<W> {
+    /// Encrypts at most one Noise frame's worth of `data` per call. The frame
+    /// is buffered until the underlying writer takes it, so a later write,
+    /// flush or shutdown finishes sending it.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.frames.poll_drain(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = data.len().min(MAX_PLAINTEXT_LEN);
+        this.push_data(&data[..n])?;
+        // The frame is committed either way; Pending just leaves it buffered.
+        if let Poll::Ready(Err(e)) = this.frames.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let frames = &mut self.get_mut().frames;
+        ready!(frames.poll_drain(cx))?;
+        Pin::new(&mut frames.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let frames = &mut self.get_mut().frames;
+        ready!(frames.poll_drain(cx))?;
+        Pin::new(&mut frames.io).poll_shutdown(cx)
+    }
+}
+
+/// Read side of the framing. Keeps a partially received frame across polls,
+/// so a cancelled `recv` or a `Pending` read never loses bytes.
+#[derive(Debug)]
+struct FrameReader<R> {
+    io: R,
+    len_buf: [u8; 2],
+    len_read: usize,
+    frame: Vec<u8>,
+    frame_read: usize,
+}
+
+impl<R> FrameReader<R> {
+    fn new(io: R) -> Self {
+        Self {
+            io,
+            len_buf: [0; 2],
+            len_read: 0,
+            frame: Vec::new(),
+            frame_read: 0,
+        }
+    }
+
+    fn map_io<T>(self, f: impl FnOnce(R) -> T) -> FrameReader<T> {
+        FrameReader {
+            io: f(self.io),
+            len_buf: self.len_buf,
+            len_read: self.len_read,
+            frame: self.frame,
+            frame_read: self.frame_read,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Poll for the next complete ciphertext frame; `None` on a clean EOF
+    /// between frames.
+    fn poll_next_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Vec<u8>>>> {
+        while self.len_read < self.len_buf.len() {
+            let mut buf = ReadBuf::new(&mut self.len_buf[self.len_read..]);
+            ready!(Pin::new(&mut self.io).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 {
+                return Poll::Ready(match self.len_read {
+                    0 => Ok(None),
+                    _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "early eof")),
+                });
+            }
+            self.len_read += n;
+            if self.len_read == self.len_buf.len() {
+                self.frame = vec![0; u16::from_be_bytes(self.len_buf) as usize];
+                self.frame_read = 0;
+            }
+        }
+
+        while self.frame_read < self.frame.len() {
+            let mut buf = ReadBuf::new(&mut self.frame[self.frame_read..]);
+            ready!(Pin::new(&mut self.io).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "early eof",
+                )));
+            }
+            self.frame_read += n;
+        }
+
+        self.len_read = 0;
+        Poll::Ready(Ok(Some(std::mem::take(&mut self.frame))))
+    }
+}
 
-        let mut buf = vec![0u8; len];
-        println!("[recv] Locking noise state for decryption");
-        let mut lock = self.noise.lock().await;
-        let decrypted_len = lock
-            .read_message(&msg, &mut buf)
-            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::Other, e))?;
-        println!("[recv] Successfully decrypted {decrypted_len} bytes");
+/// Write side of the framing: encrypted frames waiting for the underlying
+/// writer to accept them.
+#[derive(Debug)]
+struct FrameWriter<W> {
+    io: W,
+    out: Vec<u8>,
+    written: usize,
+}
+
+impl<W> FrameWriter<W> {
+    fn new(io: W) -> Self {
+        Self {
+            io,
+            out: Vec::new(),
+            written: 0,
+        }
+    }
 
-        Ok(buf[..decrypted_len].to_vec())
+    fn map_io<T>(self, f: impl FnOnce(W) -> T) -> FrameWriter<T> {
+        FrameWriter {
+            io: f(self.io),
+            out: self.out,
+            written: self.written,
+        }
+    }
+
+    /// Encrypt `plaintext` (at most `MAX_PLAINTEXT_LEN` bytes) and queue it
+    /// behind its length prefix. Returns the ciphertext length.
+    fn push_frame(&mut self, cipher: &mut CipherState, plaintext: &[u8]) -> io::Result<usize> {
+        let start = self.out.len();
+        self.out.resize(start + 2 + MAX_NOISE_FRAME_LEN, 0);
+        let len = match cipher.encrypt(plaintext, &mut self.out[start + 2..]) {
+            Ok(len) => len,
+            Err(e) => {
+                self.out.truncate(start);
+                return Err(io::Error::other(e));
+            }
+        };
+        // len <= MAX_NOISE_FRAME_LEN, so it always fits the u16 prefix
+        self.out[start..start + 2].copy_from_slice(&(len as u16).to_be_bytes());
+        self.out.truncate(start + 2 + len);
+        Ok(len)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Write out every queued frame.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -151,3 +661,127 @@ pub async fn ping_server(stream: &mut EncryptedStream, addr: SocketAddr) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use snow::{Builder, HandshakeState, TransportState, error::StateProblem};
+
+    use super::*;
+    use crate::testing::transports;
+
+    fn is_exhausted(e: &io::Error) -> bool {
+        e.get_ref().and_then(|e| e.downcast_ref::<snow::Error>())
+            == Some(&snow::Error::State(StateProblem::Exhausted))
+    }
+
+    #[tokio::test]
+    async fn nonce_exhaustion_is_reported_not_a_panic() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (mut ta, mut tb) = transports();
+        ta.send.set_nonce(u64::MAX - 1);
+        tb.recv.set_nonce(u64::MAX - 1);
+        let a = EncryptedStream::from_stream(ta, a);
+        let b = EncryptedStream::from_stream(tb, b);
+
+        // The last usable nonce still works, the reserved one is refused.
+        a.send(b"last").await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), b"last");
+        let err = a.send(b"one too many").await.unwrap_err();
+        assert!(is_exhausted(&err), "{err}");
+
+        // A receiver whose counter ran out reports it too.
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (ta, mut tb) = transports();
+        tb.recv.set_nonce(u64::MAX);
+        let a = EncryptedStream::from_stream(ta, a);
+        let b = EncryptedStream::from_stream(tb, b);
+        a.send(b"hello").await.unwrap();
+        let err = b.recv().await.unwrap_err();
+        assert!(is_exhausted(&err), "{err}");
+    }
+
+    /// An NN handshake with fixed ephemeral keys, so running it twice
+    /// yields the same transport keys.
+    fn fixed_handshake() -> (HandshakeState, HandshakeState) {
+        let params = "Noise_NN_25519_ChaChaPoly_SHA256";
+        let mut initiator = Builder::new(params.parse().unwrap())
+            .fixed_ephemeral_key_for_testing_only(&[1; 32])
+            .build_initiator()
+            .unwrap();
+        let mut responder = Builder::new(params.parse().unwrap())
+            .fixed_ephemeral_key_for_testing_only(&[2; 32])
+            .build_responder()
+            .unwrap();
+        let (mut msg, mut scratch) = ([0u8; 1024], [0u8; 1024]);
+        let n = initiator.write_message(&[], &mut msg).unwrap();
+        responder.read_message(&msg[..n], &mut scratch).unwrap();
+        let n = responder.write_message(&[], &mut msg).unwrap();
+        initiator.read_message(&msg[..n], &mut scratch).unwrap();
+        (initiator, responder)
+    }
+
+    fn snow_pair() -> (TransportState, TransportState) {
+        let (initiator, responder) = fixed_handshake();
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    fn our_pair() -> (NoiseTransport, NoiseTransport) {
+        let (initiator, responder) = fixed_handshake();
+        (
+            NoiseTransport::from_handshake(initiator).unwrap(),
+            NoiseTransport::from_handshake(responder).unwrap(),
+        )
+    }
+
+    #[test]
+    fn nonces_behave_like_snows_transport_state() {
+        let (mut ours, mut our_peer) = our_pair();
+        let (mut theirs, mut their_peer) = snow_pair();
+        let (mut ct, mut expected, mut pt) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+
+        // Every message uses the next nonce, so the ciphertexts agree; a
+        // replay or a forgery fails at both and uses up no nonce.
+        for msg in [&b"one"[..], b"", b"three"] {
+            let n = ours.send.encrypt(msg, &mut ct).unwrap();
+            let m = theirs.write_message(msg, &mut expected).unwrap();
+            assert_eq!(ct[..n], expected[..m]);
+
+            let mut forged = ct;
+            forged[0] ^= 1;
+            assert_eq!(
+                our_peer.recv.decrypt(&forged[..n], &mut pt).unwrap_err(),
+                their_peer.read_message(&forged[..n], &mut pt).unwrap_err()
+            );
+            let m = our_peer.recv.decrypt(&ct[..n], &mut pt).unwrap();
+            assert_eq!(&pt[..m], msg);
+            their_peer.read_message(&ct[..n], &mut pt).unwrap();
+            assert_eq!(
+                our_peer.recv.decrypt(&ct[..n], &mut pt).unwrap_err(),
+                their_peer.read_message(&ct[..n], &mut pt).unwrap_err()
+            );
+            assert_eq!(ours.send.nonce(), theirs.sending_nonce());
+            assert_eq!(our_peer.recv.nonce(), their_peer.receiving_nonce());
+        }
+
+        // The last nonce before the reserved one works, then both give up.
+        ours.send.set_nonce(u64::MAX - 1);
+        our_peer.recv.set_nonce(u64::MAX - 1);
+        their_peer.set_receiving_nonce(u64::MAX - 1);
+        let n = ours.send.encrypt(b"last", &mut ct).unwrap();
+        our_peer.recv.decrypt(&ct[..n], &mut pt).unwrap();
+        their_peer.read_message(&ct[..n], &mut pt).unwrap();
+        let exhausted = snow::Error::State(StateProblem::Exhausted);
+        assert_eq!(ours.send.encrypt(b"more", &mut ct).unwrap_err(), exhausted);
+        assert_eq!(
+            our_peer.recv.decrypt(&ct[..n], &mut pt).unwrap_err(),
+            exhausted
+        );
+        assert_eq!(
+            their_peer.read_message(&ct[..n], &mut pt).unwrap_err(),
+            exhausted
+        );
+    }
+}
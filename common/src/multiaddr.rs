@@ -0,0 +1,371 @@
+//! Self-describing network addresses, e.g. `/ip4/127.0.0.1/tcp/8080/p2p/12D3KooW...`.
+//!
+//! The binary form is each component's `uvarint(code)` followed by its value:
+//! fixed-size for addresses and ports, `uvarint(len) || bytes` for names,
+//! paths and peer ids, nothing for flags such as `/ws`.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::{identity::PeerId, varint};
+
+const IP4: u64 = 4;
+const TCP: u64 = 6;
+const IP6: u64 = 41;
+const DNS4: u64 = 54;
+const DNS6: u64 = 55;
+const UDP: u64 = 273;
+const P2P_CIRCUIT: u64 = 290;
+const UNIX: u64 = 400;
+const P2P: u64 = 421;
+const WS: u64 = 477;
+const MEMORY: u64 = 777;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MultiaddrError {
+    #[error("multiaddr must start with '/'")]
+    MissingSlash,
+    #[error("unknown protocol {0}")]
+    UnknownProtocol(String),
+    #[error("unknown protocol code {0}")]
+    UnknownCode(u64),
+    #[error("missing value for /{0}")]
+    MissingValue(&'static str),
+    #[error("invalid value for /{protocol}: {value}")]
+    InvalidValue {
+        protocol: &'static str,
+        value: String,
+    },
+    #[error("truncated multiaddr")]
+    Truncated,
+    #[error("malformed varint: {0}")]
+    Varint(#[from] varint::DecodeError),
+}
+
+/// One component of a `Multiaddr`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Ip4(Ipv4Addr),
+    Ip6(Ipv6Addr),
+    Dns4(String),
+    Dns6(String),
+    Tcp(u16),
+    Udp(u16),
+    Ws,
+    P2p(PeerId),
+    P2pCircuit,
+    /// A filesystem path. It takes the rest of a textual address, so it
+    /// has to be the last component to survive a round trip through text.
+    Unix(String),
+    /// An in-process port, see the transport crate's `MemoryTransport`.
+    Memory(u64),
+}
+
+impl Protocol {
+    /// The name used in the textual form.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Protocol::Ip4(_) => "ip4",
+            Protocol::Ip6(_) => "ip6",
+            Protocol::Dns4(_) => "dns4",
+            Protocol::Dns6(_) => "dns6",
+            Protocol::Tcp(_) => "tcp",
+            Protocol::Udp(_) => "udp",
+            Protocol::Ws => "ws",
+            Protocol::P2p(_) => "p2p",
+            Protocol::P2pCircuit => "p2p-circuit",
+            Protocol::Unix(_) => "unix",
+            Protocol::Memory(_) => "memory",
+        }
+    }
+
+    fn code(&self) -> u64 {
+        match self {
+            Protocol::Ip4(_) => IP4,
+            Protocol::Ip6(_) => IP6,
+            Protocol::Dns4(_) => DNS4,
+            Protocol::Dns6(_) => DNS6,
+            Protocol::Tcp(_) => TCP,
+            Protocol::Udp(_) => UDP,
+            Protocol::Ws => WS,
+            Protocol::P2p(_) => P2P,
+            Protocol::P2pCircuit => P2P_CIRCUIT,
+            Protocol::Unix(_) => UNIX,
+            Protocol::Memory(_) => MEMORY,
+        }
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        varint::encode(self.code(), out);
+        let with_len = |bytes: &[u8], out: &mut Vec<u8>| {
+            varint::encode(bytes.len() as u64, out);
+            out.extend_from_slice(bytes);
+        };
+        match self {
+            Protocol::Ip4(ip) => out.extend_from_slice(&ip.octets()),
+            Protocol::Ip6(ip) => out.extend_from_slice(&ip.octets()),
+            Protocol::Memory(port) => out.extend_from_slice(&port.to_be_bytes()),
+            Protocol::Tcp(port) | Protocol::Udp(port) => out.extend_from_slice(&port.to_be_bytes()),
+            Protocol::Dns4(name) | Protocol::Dns6(name) => with_len(name.as_bytes(), out),
+            Protocol::Unix(path) => with_len(path.as_bytes(), out),
+            Protocol::P2p(peer) => with_len(&peer.to_bytes(), out),
+            Protocol::Ws | Protocol::P2pCircuit => {}
+        }
+    }
+
+    /// Decode one component from the front of `buf`, returning it and the
+    /// bytes consumed.
+    fn from_bytes(buf: &[u8]) -> Result<(Self, usize), MultiaddrError> {
+        let (code, n) = decode_varint(buf)?;
+        let rest = &buf[n..];
+        let fixed = |len: usize| rest.get(..len).ok_or(MultiaddrError::Truncated);
+        let with_len = || -> Result<(&[u8], usize), MultiaddrError> {
+            let (len, m) = decode_varint(rest)?;
+            let len = usize::try_from(len).map_err(|_| MultiaddrError::Truncated)?;
+            let end = m.checked_add(len).ok_or(MultiaddrError::Truncated)?;
+            Ok((rest.get(m..end).ok_or(MultiaddrError::Truncated)?, end))
+        };
+        let text = |protocol: &'static str, bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec()).map_err(|e| MultiaddrError::InvalidValue {
+                protocol,
+                value: e.to_string(),
+            })
+        };
+
+        let (protocol, len) = match code {
+            IP4 => {
+                let octets: [u8; 4] = fixed(4)?.try_into().unwrap();
+                (Protocol::Ip4(octets.into()), 4)
+            }
+            IP6 => {
+                let octets: [u8; 16] = fixed(16)?.try_into().unwrap();
+                (Protocol::Ip6(octets.into()), 16)
+            }
+            TCP | UDP => {
+                let port = u16::from_be_bytes(fixed(2)?.try_into().unwrap());
+                let protocol = if code == TCP {
+                    Protocol::Tcp(port)
+                } else {
+                    Protocol::Udp(port)
+                };
+                (protocol, 2)
+            }
+            DNS4 => {
+                let (bytes, len) = with_len()?;
+                (Protocol::Dns4(text("dns4", bytes)?), len)
+            }
+            DNS6 => {
+                let (bytes, len) = with_len()?;
+                (Protocol::Dns6(text("dns6", bytes)?), len)
+            }
+            UNIX => {
+                let (bytes, len) = with_len()?;
+                (Protocol::Unix(text("unix", bytes)?), len)
+            }
+            P2P => {
+                let (bytes, len) = with_len()?;
+                let peer = PeerId::from_bytes(bytes).map_err(|e| MultiaddrError::InvalidValue {
+                    protocol: "p2p",
+                    value: e.to_string(),
+                })?;
+                (Protocol::P2p(peer), len)
+            }
+            MEMORY => {
+                let port = u64::from_be_bytes(fixed(8)?.try_into().unwrap());
+                (Protocol::Memory(port), 8)
+            }
+            WS => (Protocol::Ws, 0),
+            P2P_CIRCUIT => (Protocol::P2pCircuit, 0),
+            other => return Err(MultiaddrError::UnknownCode(other)),
+        };
+        Ok((protocol, n + len))
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.tag())?;
+        match self {
+            Protocol::Ip4(ip) => write!(f, "/{ip}"),
+            Protocol::Ip6(ip) => write!(f, "/{ip}"),
+            Protocol::Dns4(name) | Protocol::Dns6(name) => write!(f, "/{name}"),
+            Protocol::Tcp(port) | Protocol::Udp(port) => write!(f, "/{port}"),
+            Protocol::P2p(peer) => write!(f, "/{peer}"),
+            Protocol::Memory(port) => write!(f, "/{port}"),
+            // an absolute path brings its own leading '/'
+            Protocol::Unix(path) if path.starts_with('/') => write!(f, "{path}"),
+            Protocol::Unix(path) => write!(f, "/{path}"),
+            Protocol::Ws | Protocol::P2pCircuit => Ok(()),
+        }
+    }
+}
+
+fn decode_varint(buf: &[u8]) -> Result<(u64, usize), MultiaddrError> {
+    match varint::decode(buf) {
+        Err(varint::DecodeError::Incomplete) => Err(MultiaddrError::Truncated),
+        other => Ok(other?),
+    }
+}
+
+/// A sequence of protocol components, outermost first.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Multiaddr {
+    components: Vec<Protocol>,
+}
+
+impl Multiaddr {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Protocol> {
+        self.components.iter()
+    }
+
+    /// Append a component, e.g. `/p2p/<id>` to an address being shared.
+    pub fn push(&mut self, protocol: Protocol) {
+        self.components.push(protocol);
+    }
+
+    /// Remove and return the last component.
+    pub fn pop(&mut self) -> Option<Protocol> {
+        self.components.pop()
+    }
+
+    /// `push` for building an address in one expression.
+    pub fn with(mut self, protocol: Protocol) -> Self {
+        self.push(protocol);
+        self
+    }
+
+    /// The peer named by a trailing `/p2p/<id>`, if any.
+    pub fn peer_id(&self) -> Option<&PeerId> {
+        match self.components.last() {
+            Some(Protocol::P2p(peer)) => Some(peer),
+            _ => None,
+        }
+    }
+
+    /// The binary encoding.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for protocol in &self.components {
+            protocol.write_bytes(&mut out);
+        }
+        out
+    }
+
+    /// Parse the binary encoding.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, MultiaddrError> {
+        let mut components = Vec::new();
+        while !bytes.is_empty() {
+            let (protocol, n) = Protocol::from_bytes(bytes)?;
+            components.push(protocol);
+            bytes = &bytes[n..];
+        }
+        Ok(Self { components })
+    }
+}
+
+impl FromStr for Multiaddr {
+    type Err = MultiaddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix('/').ok_or(MultiaddrError::MissingSlash)?;
+        let mut parts = rest.split('/').peekable();
+        let mut components = Vec::new();
+        while let Some(tag) = parts.next() {
+            if tag.is_empty() {
+                // tolerate a trailing '/', but not an empty component
+                if parts.peek().is_none() {
+                    break;
+                }
+                return Err(MultiaddrError::UnknownProtocol(String::new()));
+            }
+            let protocol = match tag {
+                "ws" => Protocol::Ws,
+                "p2p-circuit" => Protocol::P2pCircuit,
+                "unix" => {
+                    let path: Vec<&str> = parts.by_ref().collect();
+                    if path.iter().all(|p| p.is_empty()) {
+                        return Err(MultiaddrError::MissingValue("unix"));
+                    }
+                    Protocol::Unix(format!("/{}", path.join("/")))
+                }
+                _ => {
+                    let value = parts.next().filter(|v| !v.is_empty());
+                    parse_value(tag, value)?
+                }
+            };
+            components.push(protocol);
+        }
+        Ok(Self { components })
+    }
+}
+
+fn parse_value(tag: &str, value: Option<&str>) -> Result<Protocol, MultiaddrError> {
+    let (name, parse): (&'static str, fn(&str) -> Option<Protocol>) = match tag {
+        "ip4" => ("ip4", |v| v.parse().ok().map(Protocol::Ip4)),
+        "ip6" => ("ip6", |v| v.parse().ok().map(Protocol::Ip6)),
+        "dns4" => ("dns4", |v| Some(Protocol::Dns4(v.to_string()))),
+        "dns6" => ("dns6", |v| Some(Protocol::Dns6(v.to_string()))),
+        "tcp" => ("tcp", |v| v.parse().ok().map(Protocol::Tcp)),
+        "udp" => ("udp", |v| v.parse().ok().map(Protocol::Udp)),
+        "p2p" | "ipfs" => ("p2p", |v| v.parse().ok().map(Protocol::P2p)),
+        "memory" => ("memory", |v| v.parse().ok().map(Protocol::Memory)),
+        other => return Err(MultiaddrError::UnknownProtocol(other.to_string())),
+    };
+    let value = value.ok_or(MultiaddrError::MissingValue(name))?;
+    parse(value).ok_or_else(|| MultiaddrError::InvalidValue {
+        protocol: name,
+        value: value.to_string(),
+    })
+}
+
+impl fmt::Display for Multiaddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.components.iter().try_for_each(|p| write!(f, "{p}"))
+    }
+}
+
+impl fmt::Debug for Multiaddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Multiaddr({self})")
+    }
+}
+
+impl TryFrom<&[u8]> for Multiaddr {
+    type Error = MultiaddrError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl FromIterator<Protocol> for Multiaddr {
+    fn from_iter<I: IntoIterator<Item = Protocol>>(iter: I) -> Self {
+        Self {
+            components: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Multiaddr {
+    type Item = &'a Protocol;
+    type IntoIter = std::slice::Iter<'a, Protocol>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
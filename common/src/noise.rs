@@ -0,0 +1,127 @@
+//! Per-direction Noise cipher states. snow's `TransportState` keeps both
+//! nonces in one value behind `&mut self`, which would force the reader and
+//! writer of a secured channel to share a lock for every message. Its
+//! `StatelessTransportState` takes the nonce per call instead: each direction
+//! here counts its own, while snow still does the AEAD and refuses the
+//! reserved nonce. Only a rekey changes the shared state, so that is the one
+//! moment the two directions wait for each other.
+
+use std::{
+    fmt,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use snow::{
+    HandshakeState, StatelessTransportState,
+    error::{Error, StateProblem},
+};
+
+/// Size of the AEAD tag appended to every ciphertext.
+pub const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Send,
+    Recv,
+}
+
+/// One direction of a Noise transport and the nonce of its next message.
+pub struct CipherState {
+    transport: Arc<RwLock<StatelessTransportState>>,
+    direction: Direction,
+    nonce: u64,
+}
+
+impl CipherState {
+    /// Nonce of the next message.
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Jump the counter ahead, so tests can reach nonce exhaustion.
+    #[cfg(test)]
+    pub(crate) fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+    }
+
+    /// Replace this direction's key with `REKEY(k)` from the Noise spec. The
+    /// nonce keeps counting, so a direction is still limited to 2^64-1
+    /// messages.
+    pub fn rekey(&mut self) {
+        let mut transport = self
+            .transport
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match self.direction {
+            Direction::Send => transport.rekey_outgoing(),
+            Direction::Recv => transport.rekey_incoming(),
+        }
+    }
+
+    /// Encrypt `plaintext` into `out`, which needs `TAG_LEN` bytes of room
+    /// beyond the plaintext. Returns the ciphertext length. Only the sending
+    /// direction encrypts.
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        if self.direction != Direction::Send {
+            return Err(StateProblem::OneWay.into());
+        }
+        let len = self
+            .transport
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_message(self.nonce, plaintext, out)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+
+    /// Decrypt `ciphertext` into `out`. Returns the plaintext length. Only
+    /// the receiving direction decrypts; a message that fails to decrypt
+    /// does not use up a nonce.
+    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        if self.direction != Direction::Recv {
+            return Err(StateProblem::OneWay.into());
+        }
+        let len = self
+            .transport
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read_message(self.nonce, ciphertext, out)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+}
+
+impl fmt::Debug for CipherState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherState")
+            .field("direction", &self.direction)
+            .field("nonce", &self.nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Both directions of a finished handshake, as independent cipher states.
+#[derive(Debug)]
+pub struct NoiseTransport {
+    pub send: CipherState,
+    pub recv: CipherState,
+}
+
+impl NoiseTransport {
+    /// Turn a completed handshake into its two directions.
+    pub fn from_handshake(handshake: HandshakeState) -> Result<Self, Error> {
+        let transport = Arc::new(RwLock::new(handshake.into_stateless_transport_mode()?));
+        Ok(Self {
+            send: CipherState {
+                transport: Arc::clone(&transport),
+                direction: Direction::Send,
+                nonce: 0,
+            },
+            recv: CipherState {
+                transport,
+                direction: Direction::Recv,
+                nonce: 0,
+            },
+        })
+    }
+}
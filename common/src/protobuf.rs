@@ -0,0 +1,90 @@
+//! Just enough protobuf (proto2/proto3 wire format) to encode and decode the
+//! handful of small libp2p messages we exchange: keys and handshake payloads.
+
+use crate::varint;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_LEN: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated,
+    Varint(varint::DecodeError),
+    UnsupportedWireType(u64),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated protobuf message"),
+            DecodeError::Varint(e) => write!(f, "invalid varint: {e}"),
+            DecodeError::UnsupportedWireType(t) => write!(f, "unsupported wire type {t}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<varint::DecodeError> for DecodeError {
+    fn from(e: varint::DecodeError) -> Self {
+        DecodeError::Varint(e)
+    }
+}
+
+/// Append a varint field.
+pub fn put_varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    varint::encode(((field as u64) << 3) | WIRE_VARINT, out);
+    varint::encode(value, out);
+}
+
+/// Append a length-delimited (bytes / string / embedded message) field.
+pub fn put_bytes_field(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    varint::encode(((field as u64) << 3) | WIRE_LEN, out);
+    varint::encode(value.len() as u64, out);
+    out.extend_from_slice(value);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Iterate over the `(field number, value)` pairs of an encoded message.
+pub fn fields(mut buf: &[u8]) -> impl Iterator<Item = Result<(u32, FieldValue<'_>), DecodeError>> {
+    std::iter::from_fn(move || {
+        if buf.is_empty() {
+            return None;
+        }
+        let res = next_field(&mut buf);
+        if res.is_err() {
+            buf = &[];
+        }
+        Some(res)
+    })
+}
+
+fn next_field<'a>(buf: &mut &'a [u8]) -> Result<(u32, FieldValue<'a>), DecodeError> {
+    let (key, n) = varint::decode(buf)?;
+    *buf = &buf[n..];
+    let field = (key >> 3) as u32;
+
+    match key & 0x7 {
+        WIRE_VARINT => {
+            let (value, n) = varint::decode(buf)?;
+            *buf = &buf[n..];
+            Ok((field, FieldValue::Varint(value)))
+        }
+        WIRE_LEN => {
+            let (len, n) = varint::decode(buf)?;
+            let len = len as usize;
+            let rest = &buf[n..];
+            if rest.len() < len {
+                return Err(DecodeError::Truncated);
+            }
+            *buf = &rest[len..];
+            Ok((field, FieldValue::Bytes(&rest[..len])))
+        }
+        other => Err(DecodeError::UnsupportedWireType(other)),
+    }
+}
@@ -0,0 +1,49 @@
+//! Fixtures for tests of this crate and of the crates built on it, which
+//! enable them with the `test-util` feature.
+
+use snow::Builder;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{EncryptedStream, noise::NoiseTransport};
+
+const PARAMS: &str = "Noise_NN_25519_ChaChaPoly_SHA256";
+
+/// Run a bare NN handshake in memory; what is under test starts afterwards.
+/// The first transport is the initiator's.
+pub fn transports() -> (NoiseTransport, NoiseTransport) {
+    let mut initiator = Builder::new(PARAMS.parse().unwrap())
+        .build_initiator()
+        .unwrap();
+    let mut responder = Builder::new(PARAMS.parse().unwrap())
+        .build_responder()
+        .unwrap();
+    let (mut msg, mut scratch) = ([0u8; 1024], [0u8; 1024]);
+
+    let n = initiator.write_message(&[], &mut msg).unwrap();
+    responder.read_message(&msg[..n], &mut scratch).unwrap();
+    let n = responder.write_message(&[], &mut msg).unwrap();
+    initiator.read_message(&msg[..n], &mut scratch).unwrap();
+
+    (
+        NoiseTransport::from_handshake(initiator).unwrap(),
+        NoiseTransport::from_handshake(responder).unwrap(),
+    )
+}
+
+/// Both ends of a loopback TCP connection, secured with `transports`. The
+/// first one dialed.
+pub async fn stream_pair() -> (EncryptedStream, EncryptedStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dialed = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    let (a, b) = transports();
+
+    let (ar, aw) = dialed.into_split();
+    let (br, bw) = accepted.into_split();
+    (
+        EncryptedStream::new(a, ar, aw),
+        EncryptedStream::new(b, br, bw),
+    )
+}
@@ -0,0 +1,70 @@
+//! Unsigned LEB128 varints ("uvarint") as used throughout libp2p
+//! (multistream-select, mplex, multiaddr, multihash).
+
+/// Maximum number of bytes a `u64` takes when uvarint-encoded.
+pub const MAX_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended before the last byte of the varint.
+    Incomplete,
+    /// The varint does not fit into a `u64` (or is longer than 10 bytes).
+    Overflow,
+    /// The value was encoded with redundant trailing zero bytes.
+    NotMinimal,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Incomplete => write!(f, "varint is incomplete"),
+            DecodeError::Overflow => write!(f, "varint overflows u64"),
+            DecodeError::NotMinimal => write!(f, "varint is not minimally encoded"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Append the uvarint encoding of `value` to `out`.
+pub fn encode(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Number of bytes `value` occupies once encoded.
+pub fn encoded_len(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// Decode a uvarint from the front of `buf`.
+/// Returns the value and the number of bytes consumed.
+pub fn decode(buf: &[u8]) -> Result<(u64, usize), DecodeError> {
+    let mut value: u64 = 0;
+    for (i, &byte) in buf.iter().enumerate().take(MAX_LEN) {
+        let bits = (byte & 0x7f) as u64;
+        if i == MAX_LEN - 1 && byte > 0x01 {
+            return Err(DecodeError::Overflow);
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i > 0 {
+                return Err(DecodeError::NotMinimal);
+            }
+            return Ok((value, i + 1));
+        }
+    }
+    if buf.len() >= MAX_LEN {
+        Err(DecodeError::Overflow)
+    } else {
+        Err(DecodeError::Incomplete)
+    }
+}
@@ -0,0 +1,202 @@
+use common::{
+    EncryptedStream, MAX_PLAINTEXT_LEN, RekeyPolicy,
+    testing::{stream_pair, transports},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn large_message_is_chunked_and_reassembled() {
+    let (a, b) = stream_pair().await;
+    let msg = pattern(3 * MAX_PLAINTEXT_LEN + 1234);
+
+    let sent = msg.clone();
+    let sender = tokio::spawn(async move {
+        a.send(&sent).await.unwrap();
+        a
+    });
+
+    let mut received = vec![0u8; msg.len()];
+    b.read_exact(&mut received).await.unwrap();
+    assert!(received == msg);
+    sender.await.unwrap();
+}
+
+#[tokio::test]
+async fn recv_yields_one_noise_frame_at_a_time() {
+    let (a, b) = stream_pair().await;
+    let msg = pattern(MAX_PLAINTEXT_LEN + 10);
+    a.send(&msg).await.unwrap();
+    a.send(b"tail").await.unwrap();
+
+    assert_eq!(b.recv().await.unwrap().len(), MAX_PLAINTEXT_LEN);
+
+    // A partial read keeps the rest of the frame for the next caller.
+    let mut head = [0u8; 4];
+    b.read_exact(&mut head).await.unwrap();
+    assert_eq!(head, msg[MAX_PLAINTEXT_LEN..MAX_PLAINTEXT_LEN + 4]);
+    assert_eq!(b.recv().await.unwrap(), msg[MAX_PLAINTEXT_LEN + 4..]);
+    assert_eq!(b.recv().await.unwrap(), b"tail");
+}
+
+#[tokio::test]
+async fn behaves_as_a_byte_stream_over_any_io() {
+    // A pipe far smaller than a Noise frame forces partial writes and reads.
+    let (a, b) = tokio::io::duplex(1000);
+    let (ta, tb) = transports();
+    let mut a = EncryptedStream::from_stream(ta, a);
+    let mut b = EncryptedStream::from_stream(tb, b);
+    let msg = pattern(2 * MAX_PLAINTEXT_LEN + 77);
+
+    let sent = msg.clone();
+    let writer = tokio::spawn(async move {
+        let copied = tokio::io::copy(&mut &sent[..], &mut a).await.unwrap();
+        assert_eq!(copied as usize, sent.len());
+        a.shutdown().await.unwrap();
+    });
+
+    let mut received = Vec::new();
+    b.read_to_end(&mut received).await.unwrap();
+    assert!(received == msg);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn message_and_stream_apis_share_the_framing() {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let (ta, tb) = transports();
+    let mut a = EncryptedStream::from_stream(ta, a);
+    let b = EncryptedStream::from_stream(tb, b);
+
+    a.write_all(b"written ").await.unwrap();
+    a.flush().await.unwrap();
+    a.send(b"sent").await.unwrap();
+
+    let mut received = [0u8; 12];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"written sent");
+}
+
+#[tokio::test]
+async fn split_halves_work_from_separate_tasks() {
+    let (a, b) = stream_pair().await;
+    let (mut a_read, mut a_write) = a.into_split();
+    let (mut b_read, mut b_write) = b.into_split();
+
+    // Both directions are busy at once: each side writes while it reads.
+    let echo = tokio::spawn(async move {
+        for _ in 0..100 {
+            let mut msg = [0u8; 8];
+            b_read.read_exact(&mut msg).await.unwrap();
+            b_write.send(&msg).await.unwrap();
+        }
+    });
+    let sender = tokio::spawn(async move {
+        for i in 0..100u64 {
+            a_write.send(&i.to_be_bytes()).await.unwrap();
+        }
+    });
+
+    for i in 0..100u64 {
+        let mut msg = [0u8; 8];
+        a_read.read_exact(&mut msg).await.unwrap();
+        assert_eq!(u64::from_be_bytes(msg), i);
+    }
+    sender.await.unwrap();
+    echo.await.unwrap();
+}
+
+#[tokio::test]
+async fn traffic_keeps_flowing_across_automatic_rekeys() {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let (ta, tb) = transports();
+    // The two sides use different policies; each receiver simply follows.
+    let a = EncryptedStream::from_stream(ta, a).with_rekey_policy(RekeyPolicy {
+        max_messages: Some(3),
+        max_bytes: None,
+    });
+    let b = EncryptedStream::from_stream(tb, b).with_rekey_policy(RekeyPolicy {
+        max_messages: None,
+        max_bytes: Some(100_000),
+    });
+    let (mut a_read, mut a_write) = a.into_split();
+    let (mut b_read, mut b_write) = b.into_split();
+
+    let sizes = [
+        1,
+        10,
+        MAX_PLAINTEXT_LEN,
+        3 * MAX_PLAINTEXT_LEN + 5,
+        7,
+        50_000,
+    ];
+    let a_sends = tokio::spawn(async move {
+        for len in sizes.iter().cycle().take(30) {
+            a_write.send(&pattern(*len)).await.unwrap();
+        }
+    });
+    let b_sends = tokio::spawn(async move {
+        for len in sizes.iter().cycle().take(30) {
+            b_write.send(&pattern(*len)).await.unwrap();
+        }
+    });
+
+    for len in sizes.iter().cycle().take(30) {
+        let mut at_b = vec![0u8; *len];
+        b_read.read_exact(&mut at_b).await.unwrap();
+        assert!(at_b == pattern(*len));
+
+        let mut at_a = vec![0u8; *len];
+        a_read.read_exact(&mut at_a).await.unwrap();
+        assert!(at_a == pattern(*len));
+    }
+    a_sends.await.unwrap();
+    b_sends.await.unwrap();
+}
+
+#[tokio::test]
+async fn explicit_rekey_switches_keys_on_both_sides() {
+    let (a, b) = stream_pair().await;
+    let a = a.with_rekey_policy(RekeyPolicy::NEVER);
+    let b = b.with_rekey_policy(RekeyPolicy::NEVER);
+    a.send(b"before ").await.unwrap();
+    a.rekey().await.unwrap();
+    a.rekey().await.unwrap();
+    a.send(b"after").await.unwrap();
+
+    let mut received = [0u8; 12];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"before after");
+
+    // The new key really differs from the old one.
+    let (mut ta, mut tb) = transports();
+    ta.send.rekey();
+    let (mut ct, mut pt) = ([0u8; 64], [0u8; 64]);
+    let n = ta.send.encrypt(b"secret", &mut ct).unwrap();
+    assert!(tb.recv.decrypt(&ct[..n], &mut pt).is_err());
+    tb.recv.rekey();
+    let m = tb.recv.decrypt(&ct[..n], &mut pt).unwrap();
+    assert_eq!(&pt[..m], b"secret");
+}
+
+#[tokio::test]
+async fn empty_frames_are_not_rekeys_unless_enabled() {
+    let (a, mut raw) = tokio::io::duplex(64 * 1024);
+    let (ta, mut tb) = transports();
+    let a = EncryptedStream::from_stream(ta, a);
+
+    // A spec-compliant peer may send an empty frame; it must not switch keys.
+    let mut ct = [0u8; 64];
+    for plaintext in [&b""[..], b"still in sync"] {
+        let n = tb.send.encrypt(plaintext, &mut ct).unwrap();
+        raw.write_all(&(n as u16).to_be_bytes()).await.unwrap();
+        raw.write_all(&ct[..n]).await.unwrap();
+    }
+    assert_eq!(a.recv().await.unwrap(), b"still in sync");
+
+    let err = a.rekey().await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
@@ -0,0 +1,152 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use common::{
+    identity::Keypair,
+    multiaddr::{Multiaddr, MultiaddrError, Protocol},
+};
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn round_trip(text: &str) -> Multiaddr {
+    let addr: Multiaddr = text.parse().unwrap();
+    assert_eq!(addr.to_string(), text);
+    assert_eq!(Multiaddr::from_bytes(&addr.to_vec()).unwrap(), addr);
+    addr
+}
+
+#[test]
+fn encodes_spec_vectors() {
+    let addr = round_trip("/ip4/127.0.0.1/tcp/8080");
+    assert_eq!(addr.to_vec(), unhex("047f000001061f90"));
+
+    let addr = round_trip("/ip6/::1/udp/4001");
+    let loopback = format!("29{}01", "00".repeat(15));
+    assert_eq!(addr.to_vec(), unhex(&format!("{loopback}91020fa1")));
+
+    let addr = round_trip("/dns4/example.com/tcp/443/ws");
+    assert_eq!(addr.to_vec(), unhex("360b6578616d706c652e636f6d0601bbdd03"));
+}
+
+#[test]
+fn round_trips_every_protocol() {
+    let peer = Keypair::generate_ed25519().peer_id();
+    for text in [
+        "/ip4/10.0.0.1/udp/53".to_string(),
+        "/ip6/2001:db8::7/tcp/4001/ws".to_string(),
+        "/dns6/localhost/tcp/1".to_string(),
+        format!("/ip4/1.2.3.4/tcp/80/p2p/{peer}"),
+        format!("/ip4/1.2.3.4/tcp/80/p2p/{peer}/p2p-circuit/p2p/{peer}"),
+        "/unix/tmp/node.sock".to_string(),
+        "/memory/18446744073709551615/p2p-circuit".to_string(),
+    ] {
+        round_trip(&text);
+    }
+}
+
+#[test]
+fn unix_path_takes_the_rest_of_the_address() {
+    let addr: Multiaddr = "/unix/var/run/p2p/tcp/1".parse().unwrap();
+    assert_eq!(
+        addr.iter().collect::<Vec<_>>(),
+        [&Protocol::Unix("/var/run/p2p/tcp/1".to_string())]
+    );
+}
+
+#[test]
+fn iterates_pushes_and_pops_components() {
+    let peer = Keypair::generate_ed25519().peer_id();
+    let mut addr = Multiaddr::empty()
+        .with(Protocol::Ip4(Ipv4Addr::LOCALHOST))
+        .with(Protocol::Tcp(8080));
+    assert_eq!(addr.peer_id(), None);
+
+    addr.push(Protocol::P2p(peer.clone()));
+    assert_eq!(addr.len(), 3);
+    assert_eq!(addr.peer_id(), Some(&peer));
+    assert_eq!(
+        addr.iter().map(Protocol::tag).collect::<Vec<_>>(),
+        ["ip4", "tcp", "p2p"]
+    );
+
+    assert_eq!(addr.pop(), Some(Protocol::P2p(peer)));
+    assert_eq!(addr.to_string(), "/ip4/127.0.0.1/tcp/8080");
+    let rebuilt: Multiaddr = addr.iter().cloned().collect();
+    assert_eq!(rebuilt, addr);
+}
+
+#[test]
+fn accepts_legacy_ipfs_and_a_trailing_slash() {
+    let peer = Keypair::generate_ed25519().peer_id();
+    let addr: Multiaddr = format!("/ip6/::/tcp/0/ipfs/{peer}/").parse().unwrap();
+    assert_eq!(
+        addr.iter().cloned().collect::<Vec<_>>(),
+        [
+            Protocol::Ip6(Ipv6Addr::UNSPECIFIED),
+            Protocol::Tcp(0),
+            Protocol::P2p(peer)
+        ]
+    );
+}
+
+#[test]
+fn rejects_malformed_text() {
+    let cases = [
+        ("ip4/1.2.3.4", MultiaddrError::MissingSlash),
+        ("/quic/1", MultiaddrError::UnknownProtocol("quic".into())),
+        ("/ip4", MultiaddrError::MissingValue("ip4")),
+        ("/ip4/1.2.3.4/tcp", MultiaddrError::MissingValue("tcp")),
+        ("/unix", MultiaddrError::MissingValue("unix")),
+        (
+            "/ip4/1.2.3.4//tcp/1",
+            MultiaddrError::UnknownProtocol("".into()),
+        ),
+    ];
+    for (text, expected) in cases {
+        assert_eq!(text.parse::<Multiaddr>().unwrap_err(), expected, "{text}");
+    }
+    for (text, protocol) in [
+        ("/ip4/256.0.0.1", "ip4"),
+        ("/tcp/65536", "tcp"),
+        ("/p2p/not-a-peer", "p2p"),
+    ] {
+        assert!(
+            matches!(
+                text.parse::<Multiaddr>(),
+                Err(MultiaddrError::InvalidValue { protocol: p, .. }) if p == protocol
+            ),
+            "{text}"
+        );
+    }
+}
+
+#[test]
+fn rejects_malformed_bytes() {
+    let valid = "/dns4/example.com/tcp/443"
+        .parse::<Multiaddr>()
+        .unwrap()
+        .to_vec();
+    // cutting between the two components leaves a valid, shorter address
+    for cut in (1..valid.len()).filter(|&cut| cut != 13) {
+        assert_eq!(
+            Multiaddr::from_bytes(&valid[..cut]),
+            Err(MultiaddrError::Truncated),
+            "cut at {cut}"
+        );
+    }
+    assert_eq!(
+        Multiaddr::from_bytes(&[0x7f]),
+        Err(MultiaddrError::UnknownCode(0x7f))
+    );
+    assert!(matches!(
+        Multiaddr::from_bytes(&unhex("a50303000000")),
+        Err(MultiaddrError::InvalidValue {
+            protocol: "p2p",
+            ..
+        })
+    ));
+}
@@ -0,0 +1,62 @@
+use common::varint::{self, DecodeError};
+
+fn encode(value: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    varint::encode(value, &mut out);
+    out
+}
+
+#[test]
+fn encodes_known_values() {
+    let vectors: &[(u64, &[u8])] = &[
+        (0, &[0x00]),
+        (1, &[0x01]),
+        (127, &[0x7f]),
+        (128, &[0x80, 0x01]),
+        (300, &[0xac, 0x02]),
+        (16384, &[0x80, 0x80, 0x01]),
+        (
+            u64::MAX,
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        ),
+    ];
+    for &(value, bytes) in vectors {
+        assert_eq!(encode(value), bytes, "encoding {value}");
+        assert_eq!(varint::encoded_len(value), bytes.len(), "length of {value}");
+        assert_eq!(
+            varint::decode(bytes),
+            Ok((value, bytes.len())),
+            "decoding {value}"
+        );
+    }
+}
+
+#[test]
+fn decode_ignores_trailing_bytes() {
+    assert_eq!(varint::decode(&[0xac, 0x02, 0xff, 0x00]), Ok((300, 2)));
+}
+
+#[test]
+fn decode_reports_incomplete_input() {
+    assert_eq!(varint::decode(&[]), Err(DecodeError::Incomplete));
+    assert_eq!(varint::decode(&[0x80]), Err(DecodeError::Incomplete));
+    assert_eq!(varint::decode(&[0xff; 9]), Err(DecodeError::Incomplete));
+}
+
+#[test]
+fn decode_rejects_non_minimal_encodings() {
+    assert_eq!(varint::decode(&[0x80, 0x00]), Err(DecodeError::NotMinimal));
+    assert_eq!(
+        varint::decode(&[0x81, 0x80, 0x00]),
+        Err(DecodeError::NotMinimal)
+    );
+}
+
+#[test]
+fn decode_rejects_overflow() {
+    let mut too_big = vec![0xff; 9];
+    too_big.push(0x02);
+    assert_eq!(varint::decode(&too_big), Err(DecodeError::Overflow));
+
+    assert_eq!(varint::decode(&[0x80; 11]), Err(DecodeError::Overflow));
+}
@@ -0,0 +1,97 @@
+//! Buffered mplex framing. A Noise message may carry several frames, or only
+//! part of one, so the reader accumulates bytes and drains what is complete.
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use common::varint;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Frame, FrameDecodeError, FrameType, limits::MAX_FRAME_SIZE};
+
+/// `Decoder`/`Encoder` for mplex frames. Malformed input, including a frame
+/// declaring more than the maximum payload, fails with `InvalidData`
+/// wrapping the `FrameDecodeError`.
+#[derive(Debug, Clone, Copy)]
+pub struct MplexCodec {
+    max_frame_size: usize,
+}
+
+impl MplexCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+
+    /// Reject frames whose payload is over `max` bytes.
+    pub fn with_max_frame_size(max: usize) -> Self {
+        Self {
+            max_frame_size: max,
+        }
+    }
+}
+
+impl Default for MplexCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MplexCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        let Some((header, n)) = peek_varint(src)? else {
+            return Ok(None);
+        };
+        let Some((len, m)) = peek_varint(&src[n..])? else {
+            return Ok(None);
+        };
+        let t = FrameType::from_flag(header & 0x7).map_err(invalid)?;
+        // Checked before any of the payload is buffered.
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.max_frame_size)
+            .ok_or_else(|| invalid(FrameDecodeError::TooLarge(len)))?;
+
+        let start = n + m;
+        if src.len() - start < len {
+            // Make room for the rest so it arrives in one allocation.
+            src.reserve(start + len - src.len());
+            return Ok(None);
+        }
+        src.advance(start);
+        Ok(Some(Frame {
+            t,
+            stream_id: header >> 3,
+            payload: src.split_to(len).freeze(),
+        }))
+    }
+}
+
+impl Encoder<Frame> for MplexCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if frame.payload.len() > self.max_frame_size {
+            return Err(invalid(FrameDecodeError::TooLarge(
+                frame.payload.len() as u64
+            )));
+        }
+        frame.encode_into(dst);
+        Ok(())
+    }
+}
+
+/// Decode a varint at the start of `buf`; `None` if it is not all there yet.
+fn peek_varint(buf: &[u8]) -> Result<Option<(u64, usize)>, io::Error> {
+    match varint::decode(buf) {
+        Ok(decoded) => Ok(Some(decoded)),
+        Err(varint::DecodeError::Incomplete) => Ok(None),
+        Err(e) => Err(invalid(FrameDecodeError::Varint(e))),
+    }
+}
+
+fn invalid(e: FrameDecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -1,20 +1,89 @@
+//! Stream multiplexing over an `EncryptedStream` using mplex/6.7.0.
+//!
+//! Every frame is `uvarint((stream id << 3) | flag) || uvarint(len) || data`.
+//! Both peers number the streams they open themselves, so a stream is named
+//! by its number together with the side that opened it; the flag tells the
+//! receiver which side that was.
+
 use bytes::{Bytes, BytesMut};
-use common::EncryptedStream;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, mpsc};
+use common::{
+    BoxedReader, BoxedWriter, EncryptedReadHalf, EncryptedStream, EncryptedWriteHalf, varint,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::{Mutex, mpsc},
+};
+use tokio_util::codec::Decoder;
+
+mod codec;
+mod limits;
+mod state;
+mod substream;
+pub mod yamux;
+
+pub use codec::MplexCodec;
+use limits::ViolationCounter;
+pub use limits::{Limits, MAX_FRAME_SIZE, Violation, Violations};
+use state::StateCell;
+pub use state::StreamState;
+pub use substream::Substream;
+pub use yamux::{YAMUX_PROTOCOL, Yamux};
 
+/// Protocol id negotiated with multistream-select.
+pub const MPLEX_PROTOCOL: &str = "/mplex/6.7.0";
+
+/// mplex flags. `*Initiator` frames are sent by the side that opened the
+/// stream, `*Receiver` frames by the other side.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameType {
-    Open = 1,
-    Data = 2,
-    Close = 3,
-    Reset = 4,
+    NewStream = 0,
+    MessageReceiver = 1,
+    MessageInitiator = 2,
+    CloseReceiver = 3,
+    CloseInitiator = 4,
+    ResetReceiver = 5,
+    ResetInitiator = 6,
+}
+
+impl FrameType {
+    fn from_flag(flag: u64) -> Result<Self, FrameDecodeError> {
+        Ok(match flag {
+            0 => FrameType::NewStream,
+            1 => FrameType::MessageReceiver,
+            2 => FrameType::MessageInitiator,
+            3 => FrameType::CloseReceiver,
+            4 => FrameType::CloseInitiator,
+            5 => FrameType::ResetReceiver,
+            6 => FrameType::ResetInitiator,
+            other => return Err(FrameDecodeError::UnknownType(other as u8)),
+        })
+    }
+
+    /// Whether the sender of this frame is the side that opened the stream.
+    pub fn from_initiator(self) -> bool {
+        matches!(
+            self,
+            FrameType::NewStream
+                | FrameType::MessageInitiator
+                | FrameType::CloseInitiator
+                | FrameType::ResetInitiator
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub t: FrameType,
-    pub stream_id: u32,
+    pub stream_id: u64,
     pub payload: Bytes,
 }
 
@@ -25,48 +94,48 @@ pub enum FrameDecodeError {
     #[error("unknown frame type {0}")]
     UnknownType(u8),
     #[error("declared payload too large: {0}")]
-    TooLarge(u32),
+    TooLarge(u64),
     #[error("payload length mismatch: declared {declared}, actual {actual}")]
     LengthMismatch { declared: usize, actual: usize },
+    #[error("invalid varint: {0}")]
+    Varint(varint::DecodeError),
 }
 
 impl Frame {
     pub fn encode(&self) -> Bytes {
         let mut buf = BytesMut::new();
+        self.encode_into(&mut buf);
+        buf.freeze()
+    }
 
-        buf.extend_from_slice(&self.stream_id.to_le_bytes());
-        buf.extend_from_slice(&(self.t as u8).to_le_bytes());
-        buf.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
-        buf.extend_from_slice(&self.payload);
+    /// Append the encoded frame to `dst`.
+    pub(crate) fn encode_into(&self, dst: &mut BytesMut) {
+        let mut header = Vec::with_capacity(2 * varint::MAX_LEN);
+        varint::encode((self.stream_id << 3) | self.t as u64, &mut header);
+        varint::encode(self.payload.len() as u64, &mut header);
 
-        buf.freeze()
+        dst.reserve(header.len() + self.payload.len());
+        dst.extend_from_slice(&header);
+        dst.extend_from_slice(&self.payload);
     }
 
     pub fn decode(buf: &[u8]) -> Result<(Frame, usize), FrameDecodeError> {
-        if buf.len() < 4 + 1 + 4 {
-            return Err(FrameDecodeError::TooShort);
-        }
+        let (header, n) = decode_varint(buf)?;
+        let (len, m) = decode_varint(&buf[n..])?;
+        let start = n + m;
 
-        let stream_id = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-        let t_raw = buf[4];
-        let len = u32::from_le_bytes(buf[5..9].try_into().unwrap()) as usize;
+        let t = FrameType::from_flag(header & 0x7)?;
+        let stream_id = header >> 3;
 
-        if buf.len() < 9 + len {
+        let len = usize::try_from(len).map_err(|_| FrameDecodeError::TooLarge(len))?;
+        if buf.len() - start < len {
             return Err(FrameDecodeError::LengthMismatch {
                 declared: len,
-                actual: buf.len() - 9,
+                actual: buf.len() - start,
             });
         }
 
-        let t = match t_raw {
-            1 => FrameType::Open,
-            2 => FrameType::Data,
-            3 => FrameType::Close,
-            4 => FrameType::Reset,
-            other => return Err(FrameDecodeError::UnknownType(other)),
-        };
-
-        let payload = Bytes::copy_from_slice(&buf[9..9 + len]);
+        let payload = Bytes::copy_from_slice(&buf[start..start + len]);
 
         Ok((
             Frame {
@@ -74,27 +143,208 @@ impl Frame {
                 stream_id,
                 payload,
             },
-            9 + len,
+            start + len,
         ))
     }
 }
 
+fn decode_varint(buf: &[u8]) -> Result<(u64, usize), FrameDecodeError> {
+    varint::decode(buf).map_err(|e| match e {
+        varint::DecodeError::Incomplete => FrameDecodeError::TooShort,
+        other => FrameDecodeError::Varint(other),
+    })
+}
+
+/// A stream on one connection. mplex stream numbers are only unique per
+/// side, so the id records whether we opened the stream or the remote did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId {
+    pub num: u64,
+    /// We sent the `NewStream` frame.
+    pub local: bool,
+}
+
+impl StreamId {
+    /// The stream a received frame belongs to.
+    fn of_frame(frame: &Frame) -> Self {
+        StreamId {
+            num: frame.stream_id,
+            local: !frame.t.from_initiator(),
+        }
+    }
+
+    /// A frame for this stream, using the flag variant for our side of it.
+    fn frame(self, initiator: FrameType, receiver: FrameType, payload: Bytes) -> Frame {
+        Frame {
+            t: if self.local { initiator } else { receiver },
+            stream_id: self.num,
+            payload,
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = if self.local { "out" } else { "in" };
+        write!(f, "{}/{side}", self.num)
+    }
+}
+
+/// What to do with data from a peer that sends more than a stream's
+/// receive window allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Keep it anyway; memory use is then up to the peer.
+    Buffer,
+    /// Discard the frames that do not fit.
+    Drop,
+    /// Reset the offending stream.
+    Reset,
+}
+
+/// Per-stream receive limits. Yamux advertises the window to the peer and
+/// returns credit as the application reads; a peer that sends past it breaks
+/// the protocol and the session is closed, so `overflow` does not apply.
+/// Mplex has no window frames, so there the window only bounds what we
+/// buffer for a stream and `overflow` decides what happens beyond it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowControl {
+    /// Bytes buffered per stream before the reader counts as too slow.
+    pub receive_window: u32,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self {
+            receive_window: 256 * 1024,
+            overflow: OverflowPolicy::Reset,
+        }
+    }
+}
+
+/// Hand a stream's data to the application through a one-slot channel and
+/// report each chunk to `consumed` once the application took the one before
+/// it. The connection's reader only ever pushes into `data_rx`, so a slow
+/// application stalls its own stream and nothing else. The relay stops when
+/// `consumed` returns `false` or either side goes away.
+pub(crate) fn spawn_relay<F, Fut>(
+    mut data_rx: mpsc::UnboundedReceiver<Bytes>,
+    mut consumed: F,
+) -> mpsc::Receiver<Bytes>
+where
+    F: FnMut(usize) -> Fut + Send + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    let (app_tx, app_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while let Some(chunk) = data_rx.recv().await {
+            let len = chunk.len();
+            if app_tx.send(chunk).await.is_err() || !consumed(len).await {
+                break;
+            }
+        }
+    });
+    app_rx
+}
+
+/// The operations the node needs from a multiplexer, so the one chosen by
+/// negotiation can be driven by the same code.
+pub trait StreamMuxer: Sized + Send + Sync + 'static {
+    /// Open an outbound stream. Its protocol is agreed on over the stream
+    /// itself, see `Substream::select_outbound`.
+    fn open_stream(
+        self: &Arc<Self>,
+    ) -> impl Future<Output = Result<Substream<Self>, std::io::Error>> + Send;
+
+    /// Next inbound stream, not yet negotiated.
+    fn accept_stream(self: &Arc<Self>) -> impl Future<Output = Option<Substream<Self>>> + Send;
+
+    /// Send on a stream; what `Substream` writes go through.
+    fn send_data(
+        &self,
+        stream_id: StreamId,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+
+    /// Close our side of a stream; what `Substream` shutdown and drop go
+    /// through. The peer may keep sending until it closes too.
+    fn close_stream(
+        &self,
+        stream_id: StreamId,
+    ) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+
+    /// Abort a stream in both directions.
+    fn reset_stream(
+        &self,
+        stream_id: StreamId,
+    ) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+}
+
+/// A new inbound stream on its way from the reader loop to `accept_stream`.
+type Incoming = (StreamId, mpsc::Receiver<Bytes>, StateCell);
+
+/// Receiving side of one mplex stream, as seen by the reader loop.
+struct StreamEntry {
+    /// `None` once the peer closed its side.
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    /// Bytes queued for the application but not taken yet.
+    buffered: Arc<AtomicUsize>,
+    state: StateCell,
+}
+
 pub struct Muxer {
-    inner: Arc<EncryptedStream>,
-    next_stream_id: Mutex<u32>, // allocate ids (odd/even handled by caller)
-    streams: Mutex<HashMap<u32, mpsc::Sender<Bytes>>>, // stream_id -> sender to per-stream handler
-    incoming_tx: mpsc::Sender<(u32, String, mpsc::Receiver<Bytes>)>, // reader -> app (for new incoming streams)
-    incoming_rx: Mutex<mpsc::Receiver<(u32, String, mpsc::Receiver<Bytes>)>>,
+    writer: Mutex<EncryptedWriteHalf<BoxedWriter>>,
+    reader: Mutex<Option<EncryptedReadHalf<BoxedReader>>>, // taken by the reader task
+    flow: FlowControl,
+    limits: Limits,
+    violations: ViolationCounter,
+    next_stream_num: Mutex<u64>, // numbers for the streams we open
+    streams: Mutex<HashMap<StreamId, StreamEntry>>, // stream -> per-stream queue
+    incoming_tx: mpsc::Sender<Incoming>, // reader -> app (for new incoming streams)
+    incoming_rx: Mutex<mpsc::Receiver<Incoming>>,
 }
 
 impl Muxer {
-    /// Create the muxer. `initiator=true` => start ids at 1 (odd), else 2.
-    pub fn new(inner: Arc<EncryptedStream>, initiator: bool) -> Arc<Self> {
-        let start = if initiator { 1 } else { 2 };
+    /// Create the muxer. The stream is split so the reader task owns the
+    /// receiving half and never contends with writers. Any transport will
+    /// do; its types are erased.
+    pub fn new<R, W>(inner: EncryptedStream<R, W>) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::with_flow_control(inner, FlowControl::default())
+    }
+
+    /// Like `new`, with custom per-stream receive limits.
+    pub fn with_flow_control<R, W>(inner: EncryptedStream<R, W>, flow: FlowControl) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::with_limits(inner, flow, Limits::default())
+    }
+
+    /// Like `with_flow_control`, with custom stream, frame and buffer limits.
+    pub fn with_limits<R, W>(
+        inner: EncryptedStream<R, W>,
+        flow: FlowControl,
+        limits: Limits,
+    ) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
         let (tx, rx) = mpsc::channel(32);
+        let (reader, writer) = inner.boxed().into_split();
         Arc::new(Self {
-            inner,
-            next_stream_id: Mutex::new(start),
+            writer: Mutex::new(writer),
+            reader: Mutex::new(Some(reader)),
+            flow,
+            limits,
+            violations: ViolationCounter::default(),
+            next_stream_num: Mutex::new(0),
             streams: Mutex::new(HashMap::new()),
             incoming_tx: tx,
             incoming_rx: Mutex::new(rx),
@@ -105,134 +355,346 @@ impl Muxer {
     pub fn start_reader(self: &Arc<Self>) {
         let s = Arc::clone(self);
         tokio::spawn(async move {
-            s.reader_loop().await;
+            let Some(reader) = s.reader.lock().await.take() else {
+                println!("[muxer] reader already started");
+                return;
+            };
+            s.reader_loop(reader).await;
         });
     }
 
-    /// Reader loop: pulls frames from EncryptedStream, decodes, routes them.
-    async fn reader_loop(self: Arc<Self>) {
-        loop {
-            let raw = match self.inner.recv().await {
-                Ok(b) => b,
+    /// Reader loop: decodes every frame in the bytes received so far and
+    /// routes them. Frames need not line up with Noise messages; a partial
+    /// frame waits in the buffer for the rest.
+    async fn reader_loop(self: Arc<Self>, mut reader: EncryptedReadHalf<BoxedReader>) {
+        let mut codec = MplexCodec::with_max_frame_size(self.limits.max_frame_size);
+        let mut buf = BytesMut::new();
+        'conn: loop {
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(frame)) => {
+                        if let Err(violation) = self.on_frame(frame).await {
+                            self.violations.record(violation);
+                            break 'conn;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        // framing is lost, nothing after this can be trusted
+                        println!("[muxer] frame decode error: {e}");
+                        let too_large = e
+                            .get_ref()
+                            .and_then(|inner| inner.downcast_ref::<FrameDecodeError>())
+                            .is_some_and(|e| matches!(e, FrameDecodeError::TooLarge(_)));
+                        if too_large {
+                            self.violations.record(Violation::FrameTooLarge);
+                        }
+                        break 'conn;
+                    }
+                }
+            }
+            match reader.recv().await {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
                 Err(e) => {
                     println!("[muxer] underlying recv error: {:?}", e);
                     break;
                 }
-            };
+            }
+        }
 
-            match Frame::decode(&raw) {
-                Ok((frame, _consumed)) => {
-                    match frame.t {
-                        FrameType::Open => {
-                            // payload is protocol name
-                            let proto = String::from_utf8_lossy(&frame.payload).to_string();
-                            // create channel the handler will read from
-                            let (tx, rx) = mpsc::channel::<Bytes>(32);
-                            {
-                                let mut map = self.streams.lock().await;
-                                map.insert(frame.stream_id, tx);
-                            }
-                            // notify application of incoming stream
-                            let _ = self.incoming_tx.send((frame.stream_id, proto, rx)).await;
-                        }
-                        FrameType::Data => {
-                            let maybe = {
-                                let map = self.streams.lock().await;
-                                map.get(&frame.stream_id).cloned()
-                            };
-                            if let Some(tx) = maybe {
-                                // best-effort send
-                                let _ = tx.send(frame.payload).await;
-                            } else {
-                                println!("[muxer] data for unknown stream {}", frame.stream_id);
-                            }
-                        }
-                        FrameType::Close | FrameType::Reset => {
-                            // remove stream and close channel
-                            let maybe = {
-                                let mut map = self.streams.lock().await;
-                                map.remove(&frame.stream_id)
-                            };
-                            if maybe.is_some() {
-                                println!("[muxer] stream {} closed/removed", frame.stream_id);
-                            }
-                        }
+        // tell the peer we are gone, in case we are the ones giving up
+        if let Err(e) = self.writer.lock().await.shutdown().await {
+            println!("[muxer] shutdown failed: {e}");
+        }
+
+        // the connection is gone, so no stream can finish cleanly
+        for (_, entry) in self.streams.lock().await.drain() {
+            entry.state.reset();
+        }
+        println!("[muxer] reader exiting");
+    }
+
+    /// Route one decoded frame to its stream. Fails if the peer broke a
+    /// limit that ends the connection.
+    async fn on_frame(&self, frame: Frame) -> Result<(), Violation> {
+        let id = StreamId::of_frame(&frame);
+        match frame.t {
+            FrameType::NewStream => {
+                // the id can only name one stream, so neither is trusted;
+                // the map is unlocked again before the reset goes out
+                let existing = self.streams.lock().await.remove(&id);
+                if let Some(existing) = existing {
+                    self.violations.record(Violation::DuplicateStream);
+                    existing.state.reset();
+                    if let Err(e) = self.reset(id).await {
+                        println!("[muxer] reset of {id} failed: {e}");
                     }
+                    return Ok(());
                 }
-                Err(e) => {
-                    println!("[muxer] frame decode error: {:?}", e);
-                    // continue (you can decide to break/terminate connection)
+                let inbound = self
+                    .streams
+                    .lock()
+                    .await
+                    .keys()
+                    .filter(|id| !id.local)
+                    .count();
+                if inbound >= self.limits.max_inbound_streams {
+                    self.violations.record(Violation::InboundStreamLimit);
+                    if let Err(e) = self.reset(id).await {
+                        println!("[muxer] reset of {id} failed: {e}");
+                    }
+                    return Ok(());
+                }
+                // the payload is a name for debugging only; the protocol is
+                // negotiated on the stream itself
+                let (rx, state) = self.register(id).await;
+                // never wait on the application here: a full
+                // accept backlog refuses the stream instead
+                if self.incoming_tx.try_send((id, rx, state)).is_err() {
+                    println!("[muxer] accept backlog full, refusing stream {id}");
+                    self.streams.lock().await.remove(&id);
+                    if let Err(e) = self.reset(id).await {
+                        println!("[muxer] reset of {id} failed: {e}");
+                    }
+                }
+            }
+            FrameType::MessageInitiator | FrameType::MessageReceiver => {
+                return self.on_message(id, frame.payload).await;
+            }
+            FrameType::CloseInitiator | FrameType::CloseReceiver => {
+                // the peer is done sending; we may still write
+                let mut map = self.streams.lock().await;
+                if let Some(entry) = map.get_mut(&id) {
+                    entry.data_tx = None;
+                    let state = entry.state.close_remote();
+                    if state.is_finished() {
+                        map.remove(&id);
+                    }
+                    println!("[muxer] stream {id} closed by peer, now {state:?}");
+                }
+            }
+            FrameType::ResetInitiator | FrameType::ResetReceiver => {
+                if let Some(entry) = self.streams.lock().await.remove(&id) {
+                    entry.state.reset();
+                    println!("[muxer] stream {id} reset by peer");
                 }
             }
         }
+        Ok(())
+    }
 
-        println!("[muxer] reader exiting");
+    /// Create the queue for a new stream. The reader loop only pushes into
+    /// it; a relay moves the data on to the application.
+    async fn register(&self, id: StreamId) -> (mpsc::Receiver<Bytes>, StateCell) {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let state = StateCell::new();
+        self.streams.lock().await.insert(
+            id,
+            StreamEntry {
+                data_tx: Some(data_tx),
+                buffered: Arc::clone(&buffered),
+                state: state.clone(),
+            },
+        );
+        let rx = spawn_relay(data_rx, move |len| {
+            buffered.fetch_sub(len, Ordering::SeqCst);
+            std::future::ready(true)
+        });
+        (rx, state)
     }
 
-    /// Open an outgoing stream with a protocol name.
-    /// Returns (stream_id, receiver) where `receiver` yields Bytes for Data frames from peer.
-    pub async fn open_stream(
-        self: &Arc<Self>,
-        protocol: &str,
-    ) -> Result<(u32, mpsc::Receiver<Bytes>), std::io::Error> {
+    /// Queue a message for its stream, applying the overflow policy when
+    /// the stream's reader has fallen a full window behind, and the hard
+    /// buffer limits past that.
+    async fn on_message(&self, id: StreamId, payload: Bytes) -> Result<(), Violation> {
+        let mut map = self.streams.lock().await;
+        let total: usize = map
+            .values()
+            .map(|entry| entry.buffered.load(Ordering::SeqCst))
+            .sum();
+        if total + payload.len() > self.limits.max_connection_buffer {
+            return Err(Violation::ConnectionBufferLimit);
+        }
+        let Some(entry) = map.get(&id) else {
+            println!("[muxer] data for unknown stream {id}");
+            return Ok(());
+        };
+        let Some(data_tx) = &entry.data_tx else {
+            println!("[muxer] data on {id} after the peer closed it");
+            return Ok(());
+        };
+
+        let buffered = entry.buffered.load(Ordering::SeqCst) + payload.len();
+        let mut reset = buffered > self.limits.max_stream_buffer;
+        if reset {
+            self.violations.record(Violation::StreamBufferLimit);
+        } else if buffered > self.flow.receive_window as usize {
+            println!("[muxer] stream {id} exceeded its receive window");
+            match self.flow.overflow {
+                OverflowPolicy::Buffer => {}
+                OverflowPolicy::Drop => return Ok(()),
+                OverflowPolicy::Reset => reset = true,
+            }
+        }
+        if reset {
+            entry.state.reset();
+            map.remove(&id);
+            drop(map);
+            if let Err(e) = self.reset(id).await {
+                println!("[muxer] reset of {id} failed: {e}");
+            }
+            return Ok(());
+        }
+        entry.buffered.fetch_add(payload.len(), Ordering::SeqCst);
+        let _ = data_tx.send(payload);
+        Ok(())
+    }
+
+    async fn reset(&self, id: StreamId) -> Result<(), std::io::Error> {
+        let frame = id.frame(
+            FrameType::ResetInitiator,
+            FrameType::ResetReceiver,
+            Bytes::new(),
+        );
+        self.send_frame(&frame).await
+    }
+
+    /// Open an outgoing stream with an empty name, as other implementations
+    /// do. Fails with `QuotaExceeded` while `max_outbound_streams` are open.
+    pub async fn open_stream(self: &Arc<Self>) -> Result<Substream<Self>, std::io::Error> {
+        let outbound = self
+            .streams
+            .lock()
+            .await
+            .keys()
+            .filter(|id| id.local)
+            .count();
+        if outbound >= self.limits.max_outbound_streams {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::QuotaExceeded,
+                "outbound stream limit reached",
+            ));
+        }
         // allocate id
         let id = {
-            let mut lock = self.next_stream_id.lock().await;
-            let id = *lock;
-            *lock = id.wrapping_add(2);
-            id
+            let mut lock = self.next_stream_num.lock().await;
+            let num = *lock;
+            *lock += 1;
+            StreamId { num, local: true }
         };
 
-        // create per-stream rx/tx and register tx in map so incoming DATA gets routed
-        let (tx, rx) = mpsc::channel::<Bytes>(32);
-        {
-            let mut map = self.streams.lock().await;
-            map.insert(id, tx);
-        }
+        // register the stream so incoming messages get routed
+        let (rx, state) = self.register(id).await;
 
-        // send OPEN frame with protocol name as payload
         let frame = Frame {
-            t: FrameType::Open,
-            stream_id: id,
-            payload: Bytes::from(protocol.to_string()),
+            t: FrameType::NewStream,
+            stream_id: id.num,
+            payload: Bytes::new(),
         };
-        let enc = frame.encode();
-        self.inner.send(&enc).await?;
-        Ok((id, rx))
+        self.send_frame(&frame).await?;
+        Ok(Substream::new(Arc::clone(self), id, rx, state))
     }
 
-    /// Accept next incoming stream (server side). Returns (stream_id, protocol, receiver)
-    /// awaits until a remote opens a stream.
-    pub async fn accept_stream(&self) -> Option<(u32, String, mpsc::Receiver<Bytes>)> {
-        let mut rx = self.incoming_rx.lock().await;
-        rx.recv().await
+    /// Accept next incoming stream (server side); awaits until a remote
+    /// opens one.
+    pub async fn accept_stream(self: &Arc<Self>) -> Option<Substream<Self>> {
+        let (id, rx, state) = self.incoming_rx.lock().await.recv().await?;
+        Some(Substream::new(Arc::clone(self), id, rx, state))
     }
 
-    /// Send application data on stream_id
-    pub async fn send_data(&self, stream_id: u32, data: &[u8]) -> Result<(), std::io::Error> {
-        let frame = Frame {
-            t: FrameType::Data,
-            stream_id,
-            payload: Bytes::copy_from_slice(data),
-        };
-        let enc = frame.encode();
-        self.inner.send(&enc).await?;
+    /// Send application data on stream_id, split into frames no larger
+    /// than `max_frame_size`.
+    pub async fn send_data(&self, stream_id: StreamId, data: &[u8]) -> Result<(), std::io::Error> {
+        self.check_writable(stream_id).await?;
+        for chunk in data.chunks(self.limits.max_frame_size) {
+            let frame = stream_id.frame(
+                FrameType::MessageInitiator,
+                FrameType::MessageReceiver,
+                Bytes::copy_from_slice(chunk),
+            );
+            self.send_frame(&frame).await?;
+        }
         Ok(())
     }
 
-    /// Close stream (notify remote and remove local state)
-    pub async fn close_stream(&self, stream_id: u32) -> Result<(), std::io::Error> {
+    /// Close our side of the stream. The entry stays until the peer closes
+    /// its side as well, so its data still reaches us.
+    pub async fn close_stream(&self, stream_id: StreamId) -> Result<(), std::io::Error> {
         {
             let mut map = self.streams.lock().await;
-            map.remove(&stream_id);
+            let Some(entry) = map.get(&stream_id) else {
+                return Ok(()); // already finished
+            };
+            if !entry.state.get().can_write() {
+                return Ok(());
+            }
+            if entry.state.close_local().is_finished() {
+                map.remove(&stream_id);
+            }
         }
-        let frame = Frame {
-            t: FrameType::Close,
-            stream_id,
-            payload: Bytes::new(),
+        let frame = stream_id.frame(
+            FrameType::CloseInitiator,
+            FrameType::CloseReceiver,
+            Bytes::new(),
+        );
+        self.send_frame(&frame).await
+    }
+
+    /// Abort the stream in both directions.
+    pub async fn reset_stream(&self, stream_id: StreamId) -> Result<(), std::io::Error> {
+        let Some(entry) = self.streams.lock().await.remove(&stream_id) else {
+            return Ok(());
         };
+        entry.state.reset();
+        self.reset(stream_id).await
+    }
+
+    /// Current state of a stream, `None` once it is finished and forgotten.
+    pub async fn stream_state(&self, stream_id: StreamId) -> Option<StreamState> {
+        let map = self.streams.lock().await;
+        map.get(&stream_id).map(|entry| entry.state.get())
+    }
+
+    /// How often the peer has run into each limit so far.
+    pub fn violations(&self) -> Violations {
+        self.violations.snapshot()
+    }
+
+    async fn check_writable(&self, stream_id: StreamId) -> Result<(), std::io::Error> {
+        match self.streams.lock().await.get(&stream_id) {
+            Some(entry) => entry.state.check_writable(),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                format!("unknown stream {stream_id}"),
+            )),
+        }
+    }
+
+    async fn send_frame(&self, frame: &Frame) -> Result<(), std::io::Error> {
         let enc = frame.encode();
-        self.inner.send(&enc).await?;
-        Ok(())
+        self.writer.lock().await.send(&enc).await
+    }
+}
+
+impl StreamMuxer for Muxer {
+    async fn open_stream(self: &Arc<Self>) -> Result<Substream<Self>, std::io::Error> {
+        Muxer::open_stream(self).await
+    }
+
+    async fn accept_stream(self: &Arc<Self>) -> Option<Substream<Self>> {
+        Muxer::accept_stream(self).await
+    }
+
+    async fn send_data(&self, stream_id: StreamId, data: &[u8]) -> Result<(), std::io::Error> {
+        Muxer::send_data(self, stream_id, data).await
+    }
+
+    async fn close_stream(&self, stream_id: StreamId) -> Result<(), std::io::Error> {
+        Muxer::close_stream(self, stream_id).await
+    }
+
+    async fn reset_stream(&self, stream_id: StreamId) -> Result<(), std::io::Error> {
+        Muxer::reset_stream(self, stream_id).await
     }
 }
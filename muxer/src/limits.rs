@@ -0,0 +1,86 @@
+//! Bounds on what a peer can make us hold, and counts of the times a peer
+//! ran into them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Largest frame payload we accept or send: the limit go-mplex and
+/// rust-libp2p use.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Per-connection resource limits, for both the mplex `Muxer` and `Yamux`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Streams the peer may have open at once; more are reset.
+    pub max_inbound_streams: usize,
+    /// Streams we may have open at once; `open_stream` fails beyond it.
+    pub max_outbound_streams: usize,
+    /// Largest frame payload; a bigger frame ends the connection.
+    pub max_frame_size: usize,
+    /// Bytes buffered for one stream whatever the overflow policy; more
+    /// resets the stream. With mplex only reachable with
+    /// `OverflowPolicy::Buffer`; yamux never grows a window past it.
+    pub max_stream_buffer: usize,
+    /// Bytes buffered across all streams; more ends the connection.
+    pub max_connection_buffer: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_inbound_streams: 128,
+            max_outbound_streams: 128,
+            max_frame_size: MAX_FRAME_SIZE,
+            max_stream_buffer: 4 * 1024 * 1024,
+            max_connection_buffer: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A limit the peer ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Opened a stream past `max_inbound_streams`; the stream was reset.
+    InboundStreamLimit,
+    /// Sent a frame over `max_frame_size`; the connection was closed.
+    FrameTooLarge,
+    /// Filled a stream past `max_stream_buffer`; the stream was reset.
+    StreamBufferLimit,
+    /// Filled the connection past `max_connection_buffer`; the connection
+    /// was closed.
+    ConnectionBufferLimit,
+    /// Opened a stream under the id of one still open. Mplex resets both,
+    /// yamux closes the session.
+    DuplicateStream,
+}
+
+/// How often each limit was hit on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Violations {
+    pub inbound_stream_limit: u64,
+    pub frame_too_large: u64,
+    pub stream_buffer_limit: u64,
+    pub connection_buffer_limit: u64,
+    pub duplicate_stream: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ViolationCounter([AtomicU64; 5]);
+
+impl ViolationCounter {
+    /// Count one violation and log it.
+    pub(crate) fn record(&self, violation: Violation) {
+        let n = self.0[violation as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        println!("[muxer] peer violation {violation:?} (#{n} on this connection)");
+    }
+
+    pub(crate) fn snapshot(&self) -> Violations {
+        let get = |v: Violation| self.0[v as usize].load(Ordering::Relaxed);
+        Violations {
+            inbound_stream_limit: get(Violation::InboundStreamLimit),
+            frame_too_large: get(Violation::FrameTooLarge),
+            stream_buffer_limit: get(Violation::StreamBufferLimit),
+            connection_buffer_limit: get(Violation::ConnectionBufferLimit),
+            duplicate_stream: get(Violation::DuplicateStream),
+        }
+    }
+}
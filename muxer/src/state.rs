@@ -0,0 +1,105 @@
+//! Per-stream lifecycle, shared between a muxer and the stream's `Substream`.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// Where a stream is in its lifecycle. Each side closes its own direction
+/// independently; a reset ends both at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    Open,
+    /// We closed our side; the peer may still send.
+    LocalClosed,
+    /// The peer closed its side; we may still send.
+    RemoteClosed,
+    /// Both sides closed cleanly.
+    Closed,
+    /// Either side aborted the stream.
+    Reset,
+}
+
+impl StreamState {
+    fn close_local(self) -> Self {
+        match self {
+            StreamState::Open => StreamState::LocalClosed,
+            StreamState::RemoteClosed => StreamState::Closed,
+            other => other,
+        }
+    }
+
+    fn close_remote(self) -> Self {
+        match self {
+            StreamState::Open => StreamState::RemoteClosed,
+            StreamState::LocalClosed => StreamState::Closed,
+            other => other,
+        }
+    }
+
+    /// Whether the peer may still send us data.
+    pub fn can_read(self) -> bool {
+        matches!(self, StreamState::Open | StreamState::LocalClosed)
+    }
+
+    /// Whether we may still send data.
+    pub fn can_write(self) -> bool {
+        matches!(self, StreamState::Open | StreamState::RemoteClosed)
+    }
+
+    /// Neither side will send anything more.
+    pub fn is_finished(self) -> bool {
+        matches!(self, StreamState::Closed | StreamState::Reset)
+    }
+}
+
+/// The error reads and writes on a reset stream fail with.
+pub(crate) fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "stream reset")
+}
+
+/// Shared, cheaply cloned handle to a stream's state.
+#[derive(Debug, Clone)]
+pub(crate) struct StateCell(Arc<Mutex<StreamState>>);
+
+impl StateCell {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(StreamState::Open)))
+    }
+
+    pub(crate) fn get(&self) -> StreamState {
+        *self.0.lock().unwrap()
+    }
+
+    /// Record that we closed our side; returns the new state.
+    pub(crate) fn close_local(&self) -> StreamState {
+        self.update(StreamState::close_local)
+    }
+
+    /// Record that the peer closed its side; returns the new state.
+    pub(crate) fn close_remote(&self) -> StreamState {
+        self.update(StreamState::close_remote)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.update(|_| StreamState::Reset);
+    }
+
+    /// Fail with the matching error unless we may still write.
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        match self.get() {
+            StreamState::Reset => Err(reset_error()),
+            state if state.can_write() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream closed for writing",
+            )),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(StreamState) -> StreamState) -> StreamState {
+        let mut state = self.0.lock().unwrap();
+        *state = f(*state);
+        *state
+    }
+}
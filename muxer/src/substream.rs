@@ -0,0 +1,246 @@
+//! A single multiplexed stream as an owned byte stream.
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use negotiation::{
+    Negotiated, NegotiationError, Selected, Version, dialer_select, listener_select,
+};
+
+use crate::{
+    StreamId, StreamMuxer, StreamState,
+    state::{StateCell, reset_error},
+};
+
+/// Most bytes a single `poll_write` takes, so one call maps to a bounded
+/// number of frames.
+const MAX_WRITE: usize = 64 * 1024;
+
+/// Writes run as tasks so they make progress, and release the connection's
+/// writer, even when the caller stops polling.
+type PendingWrite = JoinHandle<io::Result<()>>;
+
+/// One stream of a muxed connection. Inbound and outbound streams are the
+/// same type; reading yields what the peer sent, writing sends on the stream.
+/// Shutting it down half-closes the stream: the peer's data keeps arriving
+/// until it closes too. Dropping it closes the stream.
+pub struct Substream<M: StreamMuxer> {
+    id: StreamId,
+    mux: Arc<M>,
+    incoming: mpsc::Receiver<Bytes>,
+    read_buf: Bytes,
+    /// A write already reported as done to the caller but still being sent.
+    pending_write: Option<PendingWrite>,
+    closed: bool,
+    state: StateCell,
+}
+
+impl<M: StreamMuxer> Substream<M> {
+    pub(crate) fn new(
+        mux: Arc<M>,
+        id: StreamId,
+        incoming: mpsc::Receiver<Bytes>,
+        state: StateCell,
+    ) -> Self {
+        Self {
+            id,
+            mux,
+            incoming,
+            read_buf: Bytes::new(),
+            pending_write: None,
+            closed: false,
+            state,
+        }
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Where the stream is in its lifecycle, as the muxer last saw it.
+    pub fn state(&self) -> StreamState {
+        self.state.get()
+    }
+
+    /// Abort the stream in both directions. Pending reads and writes, here
+    /// and at the peer, fail with `ConnectionReset`.
+    pub async fn reset(&mut self) -> io::Result<()> {
+        self.closed = true;
+        if self.state.get().is_finished() {
+            return Ok(());
+        }
+        let result = self.mux.reset_stream(self.id).await;
+        if let Some(write) = self.pending_write.take() {
+            let _ = write.await;
+        }
+        result
+    }
+
+    /// Agree on one of `protocols` as the side that opened the stream. With
+    /// `Version::V1Lazy` and a single protocol the stream is usable at once
+    /// and a refusal surfaces on the first read; reset the stream then. If
+    /// negotiation fails here the stream is reset.
+    pub async fn select_outbound(
+        mut self,
+        protocols: &[&str],
+        version: Version,
+    ) -> Result<Negotiated<Self>, NegotiationError> {
+        let selected = dialer_select(&mut self, protocols, version).await;
+        self.negotiated(selected).await
+    }
+
+    /// Agree on one of `protocols` as the side that accepted the stream.
+    /// If negotiation fails the stream is reset.
+    pub async fn select_inbound(
+        mut self,
+        protocols: &[&str],
+    ) -> Result<Negotiated<Self>, NegotiationError> {
+        let selected = listener_select(&mut self, protocols).await;
+        self.negotiated(selected).await
+    }
+
+    async fn negotiated(
+        mut self,
+        selected: Result<Selected, NegotiationError>,
+    ) -> Result<Negotiated<Self>, NegotiationError> {
+        match selected {
+            Ok(selected) => {
+                let verb = if selected.confirmed {
+                    "speaks"
+                } else {
+                    "proposed"
+                };
+                println!("[substream] {} {verb} {}", self.id, selected.protocol);
+                Ok(Negotiated::new(self, selected))
+            }
+            Err(e) => {
+                println!("[substream] negotiation on {} failed: {e}", self.id);
+                if let Err(e) = self.reset().await {
+                    println!("[substream] resetting {} failed: {e}", self.id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Drive the write in flight, if any, to completion.
+    fn poll_pending_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(write) = self.pending_write.as_mut() {
+            let result = ready!(Pin::new(write).poll(cx));
+            self.pending_write = None;
+            let result = result.map_err(io::Error::other).and_then(|r| r);
+            if result.is_err() && self.state.get() == StreamState::Reset {
+                return Poll::Ready(Err(reset_error()));
+            }
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M: StreamMuxer> AsyncRead for Substream<M> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.state.get() == StreamState::Reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        while this.read_buf.is_empty() {
+            match ready!(this.incoming.poll_recv(cx)) {
+                Some(chunk) => this.read_buf = chunk,
+                // The muxer drops the sender on a reset as well as a close.
+                None if this.state.get() == StreamState::Reset => {
+                    return Poll::Ready(Err(reset_error()));
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = this.read_buf.len().min(buf.remaining());
+        buf.put_slice(&this.read_buf[..n]);
+        this.read_buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M: StreamMuxer> AsyncWrite for Substream<M> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending_write(cx))?;
+        if this.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        this.state.check_writable()?;
+
+        let n = buf.len().min(MAX_WRITE);
+        let (mux, id, data) = (Arc::clone(&this.mux), this.id, buf[..n].to_vec());
+        this.pending_write = Some(tokio::spawn(async move { mux.send_data(id, &data).await }));
+        // Reported as written now; errors surface on the next write or flush.
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending_write(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending_write(cx))?;
+        if !this.closed {
+            let (mux, id) = (Arc::clone(&this.mux), this.id);
+            this.pending_write = Some(tokio::spawn(async move { mux.close_stream(id).await }));
+            this.closed = true;
+        }
+        this.poll_pending_write(cx)
+    }
+}
+
+impl<M: StreamMuxer> Drop for Substream<M> {
+    fn drop(&mut self) {
+        let finished = self.state.get().is_finished();
+        if (self.closed || finished) && self.pending_write.is_none() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (mux, id, closed) = (Arc::clone(&self.mux), self.id, self.closed || finished);
+        let pending = self.pending_write.take();
+        runtime.spawn(async move {
+            if let Some(write) = pending {
+                let _ = write.await;
+            }
+            if !closed && let Err(e) = mux.close_stream(id).await {
+                println!("[substream] closing {id} on drop failed: {e}");
+            }
+        });
+    }
+}
+
+impl<M: StreamMuxer> fmt::Debug for Substream<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Substream")
+            .field("id", &self.id)
+            .field("state", &self.state.get())
+            .finish_non_exhaustive()
+    }
+}
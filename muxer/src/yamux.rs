@@ -0,0 +1,944 @@
+//! Yamux over an `EncryptedStream`, following the hashicorp/yamux spec.
+//!
+//! Every frame starts with a 12-byte header: version (u8), type (u8), flags
+//! (u16 BE), stream id (u32 BE) and length (u32 BE). Only `Data` frames carry
+//! a body; for the other types `length` is the window delta, the ping value
+//! or the GoAway code. Dialers use odd stream ids, listeners even ones.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use common::{BoxedReader, BoxedWriter, EncryptedReadHalf, EncryptedStream, EncryptedWriteHalf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::{Mutex, Notify, mpsc, oneshot},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    FlowControl, Incoming, Limits, StreamId, StreamMuxer, StreamState, Substream, Violation,
+    Violations, limits::ViolationCounter, spawn_relay, state::StateCell,
+};
+
+/// Protocol id negotiated with multistream-select.
+pub const YAMUX_PROTOCOL: &str = "/yamux/1.0.0";
+pub const HEADER_LEN: usize = 12;
+const VERSION: u8 = 0;
+/// Window every stream starts with before any update, per the spec.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Upper bound for window growth.
+pub const MAX_WINDOW: u32 = 16 * 1024 * 1024;
+/// Largest `Data` frame we send, so one busy stream cannot hog the connection.
+const MAX_DATA_FRAME: u32 = 16 * 1024;
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a ping may go unanswered before the session is given up.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Control frames the reader may have queued before it waits for the
+/// writer. Only a peer that keeps asking for replies without reading them
+/// fills it.
+const CONTROL_QUEUE: usize = 64;
+
+/// GoAway codes.
+pub const GO_AWAY_NORMAL: u32 = 0;
+pub const GO_AWAY_PROTOCOL_ERROR: u32 = 1;
+pub const GO_AWAY_INTERNAL_ERROR: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Data = 0,
+    WindowUpdate = 1,
+    Ping = 2,
+    GoAway = 3,
+}
+
+/// Header flags; several may be set at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    /// Opens a stream, or asks for a ping reply.
+    pub const SYN: Flags = Flags(0x1);
+    /// Acknowledges a stream, or answers a ping.
+    pub const ACK: Flags = Flags(0x2);
+    /// Half-closes the sender's side of a stream.
+    pub const FIN: Flags = Flags(0x4);
+    /// Resets a stream.
+    pub const RST: Flags = Flags(0x8);
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub t: FrameType,
+    pub flags: Flags,
+    pub stream_id: u32,
+    pub length: u32,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum YamuxError {
+    #[error("unsupported yamux version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown frame type {0}")]
+    UnknownType(u8),
+    #[error("stream {0} is closed or unknown")]
+    StreamClosed(StreamId),
+    #[error("stream {0} was reset")]
+    StreamReset(StreamId),
+    #[error("session is going away")]
+    GoingAway,
+    #[error("no stream ids left")]
+    StreamIdsExhausted,
+    #[error("outbound stream limit reached")]
+    OutboundStreamLimit,
+    #[error("ping timed out")]
+    PingTimeout,
+    #[error("stream {stream} sent a {len}-byte data frame, only {allowed} bytes allowed")]
+    FrameTooLarge {
+        stream: StreamId,
+        len: u32,
+        allowed: u32,
+    },
+    #[error("yamux i/o error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<YamuxError> for io::Error {
+    fn from(e: YamuxError) -> Self {
+        match e {
+            YamuxError::Io(e) => e,
+            YamuxError::StreamReset(_) => crate::state::reset_error(),
+            YamuxError::OutboundStreamLimit => io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                YamuxError::OutboundStreamLimit,
+            ),
+            other => io::Error::other(other),
+        }
+    }
+}
+
+impl Header {
+    fn new(t: FrameType, flags: Flags, stream_id: u32, length: u32) -> Self {
+        Self {
+            t,
+            flags,
+            stream_id,
+            length,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0] = VERSION;
+        buf[1] = self.t as u8;
+        buf[2..4].copy_from_slice(&self.flags.0.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.stream_id.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.length.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; HEADER_LEN]) -> Result<Self, YamuxError> {
+        if buf[0] != VERSION {
+            return Err(YamuxError::UnsupportedVersion(buf[0]));
+        }
+        let t = match buf[1] {
+            0 => FrameType::Data,
+            1 => FrameType::WindowUpdate,
+            2 => FrameType::Ping,
+            3 => FrameType::GoAway,
+            other => return Err(YamuxError::UnknownType(other)),
+        };
+        Ok(Self {
+            t,
+            flags: Flags(u16::from_be_bytes(buf[2..4].try_into().unwrap())),
+            stream_id: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            length: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+/// What the reader hands to the control writer.
+enum Control {
+    Frame(Header),
+    /// Shut the write side down once everything queued before is out.
+    Shutdown,
+}
+
+/// Per-stream bookkeeping, owned by the session.
+struct Stream {
+    /// Feeds the relay task; `None` once the peer sent FIN.
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    /// Bytes we may still send.
+    send_window: u32,
+    send_window_changed: Arc<Notify>,
+    /// Bytes the peer may still send us.
+    recv_window: u32,
+    /// Receive window we currently grant; grows while the reader keeps up.
+    window_size: u32,
+    /// Bytes the application consumed but we have not credited back yet.
+    unacked: u32,
+    /// Bytes queued for the application but not taken yet.
+    buffered: usize,
+    last_update: Instant,
+    state: StateCell,
+}
+
+impl Stream {
+    fn new(data_tx: mpsc::UnboundedSender<Bytes>, send_window: u32, window: u32) -> Self {
+        Self {
+            data_tx: Some(data_tx),
+            send_window,
+            send_window_changed: Arc::new(Notify::new()),
+            recv_window: window,
+            window_size: window,
+            unacked: 0,
+            buffered: 0,
+            last_update: Instant::now(),
+            state: StateCell::new(),
+        }
+    }
+}
+
+pub struct Yamux {
+    writer: Mutex<EncryptedWriteHalf<BoxedWriter>>,
+    reader: Mutex<Option<EncryptedReadHalf<BoxedReader>>>, // taken by the reader task
+    /// Frames the reader owes the peer. A task of their own writes them, so
+    /// the reader never waits on the connection's write side.
+    control_tx: mpsc::Sender<Control>,
+    control_rx: Mutex<Option<mpsc::Receiver<Control>>>, // taken by the control task
+    initiator: bool,
+    flow: FlowControl,
+    limits: Limits,
+    violations: ViolationCounter,
+    next_stream_id: Mutex<u32>,
+    streams: Mutex<HashMap<u32, Stream>>,
+    incoming_tx: mpsc::Sender<Incoming>,
+    incoming_rx: Mutex<mpsc::Receiver<Incoming>>,
+    pings: Mutex<HashMap<u32, oneshot::Sender<()>>>,
+    next_ping: AtomicU32,
+    /// Last measured round-trip time, used to decide on window growth.
+    rtt: Mutex<Option<Duration>>,
+    going_away: AtomicBool,
+    closed: AtomicBool,
+    /// Cancelled when the session ends; stops the reader and pending accepts.
+    shutdown: CancellationToken,
+}
+
+impl Yamux {
+    /// Create the session. The dialer (`initiator`) opens odd stream ids,
+    /// the listener even ones.
+    pub fn new<R, W>(inner: EncryptedStream<R, W>, initiator: bool) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::with_flow_control(inner, initiator, FlowControl::default())
+    }
+
+    /// Like `new`, with custom receive windows. The spec's 256 KiB is the
+    /// smallest window a stream can have, so smaller values are raised to it.
+    pub fn with_flow_control<R, W>(
+        inner: EncryptedStream<R, W>,
+        initiator: bool,
+        flow: FlowControl,
+    ) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::with_limits(inner, initiator, flow, Limits::default())
+    }
+
+    /// Like `with_flow_control`, with custom stream, frame and buffer limits.
+    /// Windows never grow past `max_stream_buffer`, so only a peer that
+    /// ignores them, or one facing a window set above it, can run into it.
+    pub fn with_limits<R, W>(
+        inner: EncryptedStream<R, W>,
+        initiator: bool,
+        flow: FlowControl,
+        limits: Limits,
+    ) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let flow = FlowControl {
+            receive_window: flow.receive_window.clamp(INITIAL_WINDOW, MAX_WINDOW),
+            ..flow
+        };
+        let (tx, rx) = mpsc::channel(32);
+        let (control_tx, control_rx) = mpsc::channel(CONTROL_QUEUE);
+        let (reader, writer) = inner.boxed().into_split();
+        Arc::new(Self {
+            writer: Mutex::new(writer),
+            reader: Mutex::new(Some(reader)),
+            control_tx,
+            control_rx: Mutex::new(Some(control_rx)),
+            initiator,
+            flow,
+            limits,
+            violations: ViolationCounter::default(),
+            next_stream_id: Mutex::new(if initiator { 1 } else { 2 }),
+            streams: Mutex::new(HashMap::new()),
+            incoming_tx: tx,
+            incoming_rx: Mutex::new(rx),
+            pings: Mutex::new(HashMap::new()),
+            next_ping: AtomicU32::new(0),
+            rtt: Mutex::new(None),
+            going_away: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Spawn the background reader, the control writer and the keepalive
+    /// pinger. Call this once.
+    pub fn start(self: &Arc<Self>) {
+        let s = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(reader) = s.reader.lock().await.take() else {
+                println!("[yamux] reader already started");
+                return;
+            };
+            s.reader_loop(reader).await;
+        });
+
+        let s = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(control) = s.control_rx.lock().await.take() else {
+                return;
+            };
+            s.control_loop(control).await;
+        });
+
+        let s = Arc::clone(self);
+        tokio::spawn(async move {
+            s.keepalive_loop().await;
+        });
+    }
+
+    fn stream_id(&self, id: u32) -> StreamId {
+        StreamId {
+            num: id as u64,
+            local: (id % 2 == 1) == self.initiator,
+        }
+    }
+
+    async fn send_header(&self, header: Header) -> Result<(), YamuxError> {
+        self.writer.lock().await.send(&header.encode()).await?;
+        Ok(())
+    }
+
+    /// Queue a control frame for the control task. Waits only while the
+    /// queue is full.
+    async fn send_control(&self, header: Header) -> Result<(), YamuxError> {
+        self.control_tx
+            .send(Control::Frame(header))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(())
+    }
+
+    /// Write queued control frames in order, then shut the write side down
+    /// when asked to.
+    async fn control_loop(self: Arc<Self>, mut control: mpsc::Receiver<Control>) {
+        while let Some(next) = control.recv().await {
+            match next {
+                Control::Frame(header) => {
+                    if let Err(e) = self.send_header(header).await {
+                        println!("[yamux] control frame failed: {e}");
+                        break;
+                    }
+                }
+                Control::Shutdown => break,
+            }
+        }
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+
+    /// Announce GoAway from the reader, through the control queue.
+    async fn queue_go_away(&self, code: u32) {
+        self.going_away.store(true, Ordering::SeqCst);
+        let _ = self
+            .send_control(Header::new(FrameType::GoAway, Flags::NONE, 0, code))
+            .await;
+    }
+
+    async fn send_data_frame(&self, header: Header, body: &[u8]) -> Result<(), YamuxError> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.extend_from_slice(&header.encode());
+        frame.extend_from_slice(body);
+        self.writer.lock().await.send(&frame).await?;
+        Ok(())
+    }
+
+    /// Register a stream. Credit goes back to the peer only once the
+    /// application has taken the data, so a slow reader throttles just its
+    /// own stream.
+    fn register(
+        self: &Arc<Self>,
+        streams: &mut HashMap<u32, Stream>,
+        id: u32,
+        send_window: u32,
+    ) -> (mpsc::Receiver<Bytes>, StateCell) {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let stream = Stream::new(data_tx, send_window, self.flow.receive_window);
+        let state = stream.state.clone();
+        streams.insert(id, stream);
+
+        let session = Arc::clone(self);
+        let rx = spawn_relay(data_rx, move |len| {
+            let session = Arc::clone(&session);
+            async move {
+                match session.return_credit(id, len as u32).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        println!("[yamux] window update for stream {id} failed: {e}");
+                        false
+                    }
+                }
+            }
+        });
+        (rx, state)
+    }
+
+    /// Window delta announced with SYN and ACK when we grant more than the
+    /// spec's initial window.
+    fn extra_window(&self) -> u32 {
+        self.flow.receive_window - INITIAL_WINDOW
+    }
+
+    /// Credit `len` consumed bytes; send a window update once half the
+    /// window is used up, growing the window when the reader drains it
+    /// within about two round trips.
+    async fn return_credit(&self, id: u32, len: u32) -> Result<(), YamuxError> {
+        let rtt = *self.rtt.lock().await;
+        let delta = {
+            let mut streams = self.streams.lock().await;
+            let Some(stream) = streams.get_mut(&id) else {
+                return Ok(());
+            };
+            stream.unacked += len;
+            stream.buffered = stream.buffered.saturating_sub(len as usize);
+            if stream.unacked < stream.window_size / 2 {
+                return Ok(());
+            }
+
+            let mut delta = stream.unacked;
+            let max_window = MAX_WINDOW.min(self.limits.max_stream_buffer as u32);
+            if let Some(rtt) = rtt
+                && stream.last_update.elapsed() < 2 * rtt
+                && stream.window_size < max_window
+            {
+                let grown = (stream.window_size * 2).min(max_window);
+                delta += grown - stream.window_size;
+                stream.window_size = grown;
+            }
+            stream.unacked = 0;
+            stream.recv_window += delta;
+            stream.last_update = Instant::now();
+            delta
+        };
+        self.send_header(Header::new(FrameType::WindowUpdate, Flags::NONE, id, delta))
+            .await
+    }
+
+    async fn reader_loop(self: Arc<Self>, mut reader: EncryptedReadHalf<BoxedReader>) {
+        tokio::select! {
+            result = self.read_frames(&mut reader) => {
+                if let Err(e) = result {
+                    println!("[yamux] session ended: {e}");
+                }
+            }
+            // Torn down from elsewhere, e.g. by the keepalive.
+            _ = self.shutdown.cancelled() => {}
+        }
+        self.close().await;
+        println!("[yamux] reader exiting");
+    }
+
+    /// End the session: stop the reader, reset every stream so pending
+    /// reads and writes fail, drop outstanding pings, let `accept_stream`
+    /// return `None`, and shut the write side down once what is already
+    /// queued has gone out.
+    async fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.shutdown.cancel();
+        // The connection is gone, so no stream can finish cleanly.
+        for (_, stream) in self.streams.lock().await.drain() {
+            stream.state.reset();
+            stream.send_window_changed.notify_waiters();
+        }
+        self.pings.lock().await.clear();
+        // Whatever the reader queued, such as a GoAway, goes out first. If
+        // the control task is gone, shut down here.
+        if self.control_tx.send(Control::Shutdown).await.is_err() {
+            let _ = self.writer.lock().await.shutdown().await;
+        }
+    }
+
+    async fn read_frames(
+        self: &Arc<Self>,
+        reader: &mut EncryptedReadHalf<BoxedReader>,
+    ) -> Result<(), YamuxError> {
+        loop {
+            let mut raw = [0u8; HEADER_LEN];
+            reader.read_exact(&mut raw).await?;
+            let header = match Header::decode(&raw) {
+                Ok(header) => header,
+                Err(e) => {
+                    self.queue_go_away(GO_AWAY_PROTOCOL_ERROR).await;
+                    return Err(e);
+                }
+            };
+
+            match header.t {
+                FrameType::Data | FrameType::WindowUpdate => {
+                    let body = if header.t == FrameType::Data {
+                        // Check before allocating: the length is the peer's word.
+                        let allowed = self.data_allowance(&header).await;
+                        if header.length > allowed {
+                            if header.length as usize > self.limits.max_frame_size {
+                                self.violations.record(Violation::FrameTooLarge);
+                            }
+                            self.queue_go_away(GO_AWAY_PROTOCOL_ERROR).await;
+                            return Err(YamuxError::FrameTooLarge {
+                                stream: self.stream_id(header.stream_id),
+                                len: header.length,
+                                allowed,
+                            });
+                        }
+                        let mut body = vec![0u8; header.length as usize];
+                        reader.read_exact(&mut body).await?;
+                        Bytes::from(body)
+                    } else {
+                        Bytes::new()
+                    };
+                    if !self.on_stream_frame(header, body).await? {
+                        self.queue_go_away(GO_AWAY_PROTOCOL_ERROR).await;
+                        return Ok(());
+                    }
+                }
+                FrameType::Ping => {
+                    if header.flags.contains(Flags::SYN) {
+                        self.send_control(Header::new(
+                            FrameType::Ping,
+                            Flags::ACK,
+                            0,
+                            header.length,
+                        ))
+                        .await?;
+                    } else if header.flags.contains(Flags::ACK)
+                        && let Some(waiter) = self.pings.lock().await.remove(&header.length)
+                    {
+                        let _ = waiter.send(());
+                    }
+                }
+                FrameType::GoAway => {
+                    println!("[yamux] peer is going away (code {})", header.length);
+                    self.going_away.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Largest body a Data frame may carry: what is left of its stream's
+    /// receive window (the full window for a stream the frame opens), and
+    /// never more than `max_frame_size`. Frames for unknown streams are
+    /// read and dropped, so only the frame size bounds them.
+    async fn data_allowance(&self, header: &Header) -> u32 {
+        let window = match self.streams.lock().await.get(&header.stream_id) {
+            Some(stream) => stream.recv_window,
+            None if header.flags.contains(Flags::SYN) => self.flow.receive_window,
+            None => u32::MAX,
+        };
+        window.min(self.limits.max_frame_size.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// Handle a Data or WindowUpdate frame. Returns `false` on a protocol
+    /// violation that ends the session.
+    async fn on_stream_frame(
+        self: &Arc<Self>,
+        header: Header,
+        body: Bytes,
+    ) -> Result<bool, YamuxError> {
+        let id = header.stream_id;
+        let sid = self.stream_id(id);
+        let mut streams = self.streams.lock().await;
+
+        if header.flags.contains(Flags::SYN) {
+            if streams.contains_key(&id) {
+                self.violations.record(Violation::DuplicateStream);
+            }
+            if sid.local || streams.contains_key(&id) {
+                println!("[yamux] invalid SYN for stream {sid}");
+                return Ok(false);
+            }
+            let inbound = streams
+                .keys()
+                .filter(|&&id| !self.stream_id(id).local)
+                .count();
+            if inbound >= self.limits.max_inbound_streams {
+                self.violations.record(Violation::InboundStreamLimit);
+            }
+            if inbound >= self.limits.max_inbound_streams || self.going_away.load(Ordering::SeqCst)
+            {
+                drop(streams);
+                self.send_control(Header::new(FrameType::WindowUpdate, Flags::RST, id, 0))
+                    .await?;
+                return Ok(true);
+            }
+            let send_window = match header.t {
+                FrameType::WindowUpdate => INITIAL_WINDOW.saturating_add(header.length),
+                _ => INITIAL_WINDOW,
+            };
+            let (rx, state) = self.register(&mut streams, id, send_window);
+            println!("[yamux] inbound stream {sid}");
+            if self.incoming_tx.try_send((sid, rx, state)).is_err() {
+                println!("[yamux] accept backlog full, refusing stream {sid}");
+                streams.remove(&id);
+                drop(streams);
+                self.send_control(Header::new(FrameType::WindowUpdate, Flags::RST, id, 0))
+                    .await?;
+                return Ok(true);
+            }
+            drop(streams);
+            self.send_control(Header::new(
+                FrameType::WindowUpdate,
+                Flags::ACK,
+                id,
+                self.extra_window(),
+            ))
+            .await?;
+            streams = self.streams.lock().await;
+        }
+
+        if header.t == FrameType::Data && streams.contains_key(&id) {
+            let buffered: usize = streams.values().map(|s| s.buffered).sum();
+            if buffered + body.len() > self.limits.max_connection_buffer {
+                self.violations.record(Violation::ConnectionBufferLimit);
+                return Ok(false);
+            }
+        }
+
+        let Some(stream) = streams.get_mut(&id) else {
+            println!("[yamux] frame for unknown stream {sid}");
+            return Ok(true);
+        };
+
+        match header.t {
+            FrameType::WindowUpdate => {
+                stream.send_window = stream.send_window.saturating_add(header.length);
+                stream.send_window_changed.notify_waiters();
+            }
+            _ => {
+                // `data_allowance` already held the frame to the window.
+                let len = body.len() as u32;
+                stream.recv_window = stream.recv_window.saturating_sub(len);
+                if stream.data_tx.is_some() {
+                    stream.buffered += body.len();
+                }
+                if stream.buffered > self.limits.max_stream_buffer {
+                    self.violations.record(Violation::StreamBufferLimit);
+                    stream.state.reset();
+                    stream.send_window_changed.notify_waiters();
+                    streams.remove(&id);
+                    drop(streams);
+                    self.send_control(Header::new(FrameType::WindowUpdate, Flags::RST, id, 0))
+                        .await?;
+                    return Ok(true);
+                }
+                if !body.is_empty()
+                    && let Some(tx) = &stream.data_tx
+                {
+                    let _ = tx.send(body);
+                }
+            }
+        }
+
+        if header.flags.contains(Flags::RST) {
+            stream.state.reset();
+            stream.send_window_changed.notify_waiters();
+            streams.remove(&id);
+            println!("[yamux] stream {sid} reset by peer");
+        } else if header.flags.contains(Flags::FIN) {
+            // The peer is done sending; we may still write.
+            stream.data_tx = None;
+            let state = stream.state.close_remote();
+            if state.is_finished() {
+                streams.remove(&id);
+            }
+            println!("[yamux] stream {sid} closed by peer, now {state:?}");
+        }
+        Ok(true)
+    }
+
+    /// Ping the peer and wait for the answer; records the round-trip time.
+    /// The timeout also covers sending the ping, which a peer that stopped
+    /// reading can hold up.
+    pub async fn ping(&self) -> Result<Duration, YamuxError> {
+        let value = self.next_ping.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pings.lock().await.insert(value, tx);
+
+        let start = Instant::now();
+        let answered = tokio::time::timeout(PING_TIMEOUT, async {
+            self.send_header(Header::new(FrameType::Ping, Flags::SYN, 0, value))
+                .await?;
+            rx.await.map_err(|_| YamuxError::PingTimeout)
+        })
+        .await;
+        match answered {
+            Ok(Ok(())) => {
+                let rtt = start.elapsed();
+                *self.rtt.lock().await = Some(rtt);
+                Ok(rtt)
+            }
+            Ok(Err(e)) => {
+                self.pings.lock().await.remove(&value);
+                Err(e)
+            }
+            Err(_) => {
+                self.pings.lock().await.remove(&value);
+                Err(YamuxError::PingTimeout)
+            }
+        }
+    }
+
+    /// Ping right away to learn the RTT, then every `KEEPALIVE_INTERVAL`.
+    /// An unanswered ping ends the session.
+    async fn keepalive_loop(self: Arc<Self>) {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                break;
+            }
+            match self.ping().await {
+                Ok(rtt) => println!("[yamux] keepalive rtt {rtt:?}"),
+                Err(e) => {
+                    if !self.closed.load(Ordering::SeqCst) {
+                        println!("[yamux] keepalive failed: {e}");
+                        // Best effort: a peer that stopped answering may
+                        // not be reading either, so do not wait for room.
+                        self.going_away.store(true, Ordering::SeqCst);
+                        let _ = self.control_tx.try_send(Control::Frame(Header::new(
+                            FrameType::GoAway,
+                            Flags::NONE,
+                            0,
+                            GO_AWAY_INTERNAL_ERROR,
+                        )));
+                        self.close().await;
+                    }
+                    break;
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(KEEPALIVE_INTERVAL) => {}
+                _ = self.shutdown.cancelled() => break,
+            }
+        }
+    }
+
+    /// Tell the peer we will not accept new streams.
+    pub async fn go_away(&self, code: u32) -> Result<(), YamuxError> {
+        self.going_away.store(true, Ordering::SeqCst);
+        self.send_header(Header::new(FrameType::GoAway, Flags::NONE, 0, code))
+            .await
+    }
+
+    /// Open an outbound stream. Yamux streams carry no name. Fails with
+    /// `OutboundStreamLimit` while `max_outbound_streams` are open.
+    pub async fn open_stream(self: &Arc<Self>) -> Result<Substream<Self>, YamuxError> {
+        if self.going_away.load(Ordering::SeqCst) || self.closed.load(Ordering::SeqCst) {
+            return Err(YamuxError::GoingAway);
+        }
+        let outbound = self
+            .streams
+            .lock()
+            .await
+            .keys()
+            .filter(|&&id| self.stream_id(id).local)
+            .count();
+        if outbound >= self.limits.max_outbound_streams {
+            return Err(YamuxError::OutboundStreamLimit);
+        }
+        let id = {
+            let mut next = self.next_stream_id.lock().await;
+            let id = *next;
+            *next = id.checked_add(2).ok_or(YamuxError::StreamIdsExhausted)?;
+            id
+        };
+
+        let (rx, state) = {
+            let mut streams = self.streams.lock().await;
+            self.register(&mut streams, id, INITIAL_WINDOW)
+        };
+        self.send_header(Header::new(
+            FrameType::WindowUpdate,
+            Flags::SYN,
+            id,
+            self.extra_window(),
+        ))
+        .await?;
+        Ok(Substream::new(
+            Arc::clone(self),
+            self.stream_id(id),
+            rx,
+            state,
+        ))
+    }
+
+    /// Accept the next inbound stream; `None` once the session is gone.
+    pub async fn accept_stream(self: &Arc<Self>) -> Option<Substream<Self>> {
+        let mut incoming = self.incoming_rx.lock().await;
+        let (id, rx, state) = tokio::select! {
+            biased;
+            _ = self.shutdown.cancelled() => return None,
+            next = incoming.recv() => next?,
+        };
+        Some(Substream::new(Arc::clone(self), id, rx, state))
+    }
+
+    /// Send `data` on a stream, waiting for window credit as needed.
+    pub async fn send_data(&self, stream_id: StreamId, data: &[u8]) -> Result<(), YamuxError> {
+        let id = stream_id.num as u32;
+        let (notify, state) = match self.streams.lock().await.get(&id) {
+            Some(stream) => (
+                Arc::clone(&stream.send_window_changed),
+                stream.state.clone(),
+            ),
+            None => return Err(YamuxError::StreamClosed(stream_id)),
+        };
+        // The state outlives the map entry, so a reset while we wait for
+        // credit is told apart from a close.
+        let writable = || match state.get() {
+            StreamState::Reset => Err(YamuxError::StreamReset(stream_id)),
+            s if s.can_write() => Ok(()),
+            _ => Err(YamuxError::StreamClosed(stream_id)),
+        };
+
+        let mut rest = data;
+        while !rest.is_empty() {
+            let n = loop {
+                // Register for wakeups before looking at the window, so an
+                // update landing in between is not missed.
+                let notified = notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                {
+                    writable()?;
+                    let mut streams = self.streams.lock().await;
+                    let stream = streams
+                        .get_mut(&id)
+                        .ok_or(YamuxError::StreamClosed(stream_id))?;
+                    if stream.send_window > 0 {
+                        let n = stream
+                            .send_window
+                            .min(rest.len() as u32)
+                            .min(MAX_DATA_FRAME)
+                            .min(self.limits.max_frame_size.try_into().unwrap_or(u32::MAX));
+                        stream.send_window -= n;
+                        break n as usize;
+                    }
+                }
+                notified.await;
+            };
+
+            self.send_data_frame(
+                Header::new(FrameType::Data, Flags::NONE, id, n as u32),
+                &rest[..n],
+            )
+            .await?;
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+
+    /// Half-close our side with FIN. The stream stays registered until the
+    /// peer sends FIN too, so its data and window updates still arrive.
+    pub async fn close_stream(&self, stream_id: StreamId) -> Result<(), YamuxError> {
+        let id = stream_id.num as u32;
+        {
+            let mut streams = self.streams.lock().await;
+            let Some(stream) = streams.get(&id) else {
+                return Ok(()); // already finished
+            };
+            if !stream.state.get().can_write() {
+                return Ok(());
+            }
+            if stream.state.close_local().is_finished() {
+                streams.remove(&id);
+            }
+        }
+        self.send_header(Header::new(FrameType::WindowUpdate, Flags::FIN, id, 0))
+            .await
+    }
+
+    /// Abort a stream in both directions with RST.
+    pub async fn reset_stream(&self, stream_id: StreamId) -> Result<(), YamuxError> {
+        let id = stream_id.num as u32;
+        let Some(stream) = self.streams.lock().await.remove(&id) else {
+            return Ok(());
+        };
+        stream.state.reset();
+        stream.send_window_changed.notify_waiters();
+        self.send_header(Header::new(FrameType::WindowUpdate, Flags::RST, id, 0))
+            .await
+    }
+
+    /// Current state of a stream, `None` once it is finished and forgotten.
+    pub async fn stream_state(&self, stream_id: StreamId) -> Option<StreamState> {
+        let streams = self.streams.lock().await;
+        streams
+            .get(&(stream_id.num as u32))
+            .map(|stream| stream.state.get())
+    }
+
+    /// How often the peer has run into each limit so far.
+    pub fn violations(&self) -> Violations {
+        self.violations.snapshot()
+    }
+}
+
+impl StreamMuxer for Yamux {
+    async fn open_stream(self: &Arc<Self>) -> Result<Substream<Self>, io::Error> {
+        Ok(Yamux::open_stream(self).await?)
+    }
+
+    async fn accept_stream(self: &Arc<Self>) -> Option<Substream<Self>> {
+        Yamux::accept_stream(self).await
+    }
+
+    async fn send_data(&self, stream_id: StreamId, data: &[u8]) -> Result<(), io::Error> {
+        Ok(Yamux::send_data(self, stream_id, data).await?)
+    }
+
+    async fn close_stream(&self, stream_id: StreamId) -> Result<(), io::Error> {
+        Ok(Yamux::close_stream(self, stream_id).await?)
+    }
+
+    async fn reset_stream(&self, stream_id: StreamId) -> Result<(), io::Error> {
+        Ok(Yamux::reset_stream(self, stream_id).await?)
+    }
+}
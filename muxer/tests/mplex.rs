@@ -0,0 +1,555 @@
+use bytes::{Bytes, BytesMut};
+use common::{EncryptedStream, testing::stream_pair};
+use std::{io::ErrorKind, sync::Arc, time::Duration};
+
+use muxer::{
+    FlowControl, Frame, FrameDecodeError, FrameType, Limits, MplexCodec, Muxer, OverflowPolicy,
+    StreamId, StreamState, Substream, Violations,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Frames as go-mplex and rust-libp2p put them on the wire: both open streams
+/// with an empty name and number them from 0 on each side.
+const VECTORS: &[(FrameType, u64, &[u8], &[u8])] = &[
+    (FrameType::NewStream, 0, b"", &[0x00, 0x00]),
+    (FrameType::NewStream, 3, b"3", &[0x18, 0x01, b'3']),
+    (
+        FrameType::MessageInitiator,
+        0,
+        b"hi",
+        &[0x02, 0x02, b'h', b'i'],
+    ),
+    (
+        FrameType::MessageReceiver,
+        0,
+        b"hi",
+        &[0x01, 0x02, b'h', b'i'],
+    ),
+    (FrameType::CloseInitiator, 0, b"", &[0x04, 0x00]),
+    (FrameType::CloseReceiver, 0, b"", &[0x03, 0x00]),
+    (FrameType::ResetInitiator, 0, b"", &[0x06, 0x00]),
+    (FrameType::ResetReceiver, 0, b"", &[0x05, 0x00]),
+    // 17 << 3 | 2 = 138 needs a two-byte header.
+    (
+        FrameType::MessageInitiator,
+        17,
+        b"abc",
+        &[0x8a, 0x01, 0x03, b'a', b'b', b'c'],
+    ),
+];
+
+#[test]
+fn frames_match_reference_bytes() {
+    for &(t, stream_id, payload, wire) in VECTORS {
+        let frame = Frame {
+            t,
+            stream_id,
+            payload: Bytes::from_static(payload),
+        };
+        assert_eq!(&frame.encode()[..], wire, "{t:?} {stream_id}");
+
+        let (decoded, consumed) = Frame::decode(wire).unwrap();
+        assert_eq!(consumed, wire.len());
+        assert_eq!(decoded.t, t);
+        assert_eq!(decoded.stream_id, stream_id);
+        assert_eq!(&decoded.payload[..], payload);
+    }
+}
+
+#[test]
+fn payload_longer_than_127_bytes_uses_a_varint_length() {
+    let frame = Frame {
+        t: FrameType::MessageReceiver,
+        stream_id: 1,
+        payload: Bytes::from(vec![7u8; 300]),
+    };
+    let wire = frame.encode();
+    assert_eq!(&wire[..3], &[0x09, 0xac, 0x02]);
+    assert_eq!(wire.len(), 3 + 300);
+}
+
+#[test]
+fn rejects_malformed_frames() {
+    assert_eq!(Frame::decode(&[]).unwrap_err(), FrameDecodeError::TooShort);
+    assert_eq!(
+        Frame::decode(&[0x8a]).unwrap_err(),
+        FrameDecodeError::TooShort
+    );
+    assert_eq!(
+        Frame::decode(&[0x07, 0x00]).unwrap_err(),
+        FrameDecodeError::UnknownType(7)
+    );
+    assert_eq!(
+        Frame::decode(&[0x02, 0x05, b'h', b'i']).unwrap_err(),
+        FrameDecodeError::LengthMismatch {
+            declared: 5,
+            actual: 2
+        }
+    );
+}
+
+fn sample_frames() -> Vec<Frame> {
+    vec![
+        Frame {
+            t: FrameType::NewStream,
+            stream_id: 0,
+            payload: Bytes::new(),
+        },
+        Frame {
+            t: FrameType::MessageInitiator,
+            stream_id: 0,
+            payload: Bytes::from(vec![7u8; 300]),
+        },
+        Frame {
+            t: FrameType::CloseInitiator,
+            stream_id: 0,
+            payload: Bytes::new(),
+        },
+    ]
+}
+
+#[test]
+fn codec_drains_every_complete_frame_and_keeps_partial_ones() {
+    let frames = sample_frames();
+    let mut wire = BytesMut::new();
+    for frame in &frames {
+        MplexCodec::new().encode(frame.clone(), &mut wire).unwrap();
+    }
+    let wire = wire.freeze();
+
+    // All frames in one buffer.
+    let mut codec = MplexCodec::new();
+    let mut buf = BytesMut::from(&wire[..]);
+    for frame in &frames {
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            (decoded.t, decoded.payload),
+            (frame.t, frame.payload.clone())
+        );
+    }
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert!(buf.is_empty());
+
+    // One byte at a time: each frame appears exactly when it is complete.
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+    let mut ends = Vec::new();
+    for (i, byte) in wire.iter().enumerate() {
+        buf.extend_from_slice(&[*byte]);
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            decoded.push(frame.t);
+            ends.push(i + 1);
+        }
+    }
+    assert_eq!(
+        decoded,
+        frames.iter().map(|f| f.t).collect::<Vec<_>>(),
+        "{ends:?}"
+    );
+    assert_eq!(ends, [2, 2 + 3 + 300, 2 + 3 + 300 + 2]);
+
+    let err = codec
+        .decode(&mut BytesMut::from(&[0x07, 0x00][..]))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // An oversized frame is refused from its header alone.
+    let mut codec = MplexCodec::with_max_frame_size(256);
+    let err = codec.decode(&mut BytesMut::from(&wire[2..5])).unwrap_err();
+    assert_eq!(
+        err.get_ref().unwrap().downcast_ref::<FrameDecodeError>(),
+        Some(&FrameDecodeError::TooLarge(300))
+    );
+}
+
+async fn expect_bytes(peer: &EncryptedStream, expected: &[u8]) {
+    let mut got = vec![0u8; expected.len()];
+    peer.read_exact(&mut got).await.unwrap();
+    assert_eq!(got, expected);
+}
+
+/// Drive our `Muxer` against a peer that speaks raw mplex bytes.
+#[tokio::test]
+async fn muxer_interoperates_with_raw_mplex_peer() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    // Our stream 0: NewStream, then a message with the initiator flag.
+    let mut outbound = mux.open_stream().await.unwrap();
+    assert_eq!(
+        outbound.id(),
+        StreamId {
+            num: 0,
+            local: true
+        }
+    );
+    expect_bytes(&peer, &[0x00, 0x00]).await;
+    outbound.write_all(b"hi").await.unwrap();
+    outbound.flush().await.unwrap();
+    expect_bytes(&peer, &[0x02, 0x02, b'h', b'i']).await;
+
+    // The peer opens its own stream 0 and writes on both streams, with all
+    // three frames coalesced into one Noise message.
+    peer.send(&[0x00, 0x00, 0x02, 0x01, b'x', 0x01, 0x01, b'y'])
+        .await
+        .unwrap();
+    let mut inbound = mux.accept_stream().await.unwrap();
+    assert_eq!(
+        inbound.id(),
+        StreamId {
+            num: 0,
+            local: false
+        }
+    );
+    let mut byte = [0u8; 1];
+    inbound.read_exact(&mut byte).await.unwrap();
+    assert_eq!(&byte, b"x");
+    outbound.read_exact(&mut byte).await.unwrap();
+    assert_eq!(&byte, b"y");
+
+    // Replies on the inbound stream carry the receiver flag.
+    inbound.write_all(b"ok").await.unwrap();
+    inbound.flush().await.unwrap();
+    expect_bytes(&peer, &[0x01, 0x02, b'o', b'k']).await;
+    outbound.shutdown().await.unwrap();
+    expect_bytes(&peer, &[0x04, 0x00]).await;
+    // Dropping a stream closes it too.
+    drop(inbound);
+    expect_bytes(&peer, &[0x03, 0x00]).await;
+}
+
+#[tokio::test]
+async fn muxer_survives_fragmented_frames() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    // Frames split at arbitrary points across Noise messages, with the end
+    // of one and the start of the next sharing a message.
+    let mut wire = vec![0x00, 0x00];
+    wire.extend_from_slice(&message(0, &[9u8; 200]));
+    wire.extend_from_slice(&message(0, b"end"));
+    for chunk in wire.chunks(3) {
+        peer.send(chunk).await.unwrap();
+    }
+    let mut inbound = mux.accept_stream().await.unwrap();
+    let mut got = vec![0u8; 203];
+    inbound.read_exact(&mut got).await.unwrap();
+    assert_eq!(&got[..200], &[9u8; 200][..]);
+    assert_eq!(&got[200..], b"end");
+}
+
+#[tokio::test]
+async fn two_muxers_exchange_data() {
+    let (a, b) = stream_pair().await;
+    let (a, b) = (Muxer::new(a), Muxer::new(b));
+    a.start_reader();
+    b.start_reader();
+
+    let mut outbound = a.open_stream().await.unwrap();
+    let mut inbound = b.accept_stream().await.unwrap();
+    assert_eq!(inbound.id().num, outbound.id().num);
+
+    // Large enough to span several Noise frames and substream writes.
+    let big: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    outbound.write_all(&big).await.unwrap();
+    outbound.flush().await.unwrap();
+    let mut received = vec![0u8; big.len()];
+    inbound.read_exact(&mut received).await.unwrap();
+    assert!(received == big);
+
+    // Both ends are the same type, so one helper serves either side.
+    async fn echo_once(stream: &mut Substream<Muxer>, msg: &[u8]) {
+        stream.write_all(msg).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+    echo_once(&mut inbound, b"pong").await;
+    let mut pong = [0u8; 4];
+    outbound.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"pong");
+}
+
+#[tokio::test]
+async fn half_close_keeps_the_other_direction_open() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    let mut outbound = mux.open_stream().await.unwrap();
+    expect_bytes(&peer, &[0x00, 0x00]).await;
+    outbound.shutdown().await.unwrap();
+    expect_bytes(&peer, &[0x04, 0x00]).await;
+    assert_eq!(outbound.state(), StreamState::LocalClosed);
+    let err = outbound.write_all(b"late").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+    // The peer can still answer until it closes its side as well.
+    peer.send(&[0x01, 0x02, b'o', b'k']).await.unwrap();
+    let mut ok = [0u8; 2];
+    outbound.read_exact(&mut ok).await.unwrap();
+    assert_eq!(&ok, b"ok");
+    peer.send(&[0x03, 0x00]).await.unwrap();
+    assert_eq!(outbound.read(&mut ok).await.unwrap(), 0);
+    assert_eq!(outbound.state(), StreamState::Closed);
+    assert_eq!(mux.stream_state(outbound.id()).await, None);
+}
+
+#[tokio::test]
+async fn reset_fails_pending_reads_and_writes() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    // The peer opens its streams 1 and 2, then resets 1 while we wait on it.
+    peer.send(&[0x08, 0x00, 0x10, 0x00]).await.unwrap();
+    let mut first = mux.accept_stream().await.unwrap();
+    let mut second = mux.accept_stream().await.unwrap();
+    let pending = tokio::spawn(async move {
+        let mut buf = [0u8; 1];
+        let err = first.read(&mut buf).await.unwrap_err();
+        (first, err)
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    peer.send(&[0x0e, 0x00]).await.unwrap();
+    let (mut first, err) = pending.await.unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(first.state(), StreamState::Reset);
+    let err = first.write_all(b"x").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+
+    // Resetting locally tells the peer with the receiver flag.
+    assert_eq!(mux.stream_state(second.id()).await, Some(StreamState::Open));
+    second.reset().await.unwrap();
+    expect_bytes(&peer, &[0x15, 0x00]).await;
+    assert_eq!(second.state(), StreamState::Reset);
+    let err = second.read(&mut [0u8; 1]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+}
+
+fn message(stream_id: u64, payload: &[u8]) -> Bytes {
+    Frame {
+        t: FrameType::MessageInitiator,
+        stream_id,
+        payload: Bytes::copy_from_slice(payload),
+    }
+    .encode()
+}
+
+#[tokio::test]
+async fn slow_stream_does_not_stall_the_others() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    // The peer opens streams 0 and 1 (1 << 3 = 0x08).
+    peer.send(&[0x00, 0x00, 0x08, 0x00]).await.unwrap();
+    let _unread = mux.accept_stream().await.unwrap();
+    let mut fast = mux.accept_stream().await.unwrap();
+
+    // Far more messages than any channel between reader and application
+    // holds, on a stream nobody reads.
+    for _ in 0..100 {
+        peer.send(&message(0, &[0u8; 1024])).await.unwrap();
+    }
+    peer.send(&message(1, b"x")).await.unwrap();
+    let mut byte = [0u8; 1];
+    tokio::time::timeout(Duration::from_secs(2), fast.read_exact(&mut byte))
+        .await
+        .expect("stream 1 stalled behind stream 0")
+        .unwrap();
+    assert_eq!(&byte, b"x");
+}
+
+/// Overrun a 4 KiB window on a stream the application does not read yet.
+async fn overrun(policy: OverflowPolicy) -> (Arc<Muxer>, EncryptedStream, Substream<Muxer>) {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::with_flow_control(
+        ours,
+        FlowControl {
+            receive_window: 4096,
+            overflow: policy,
+        },
+    );
+    mux.start_reader();
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    let inbound = mux.accept_stream().await.unwrap();
+    for _ in 0..10 {
+        peer.send(&message(0, &[1u8; 1024])).await.unwrap();
+    }
+    (mux, peer, inbound)
+}
+
+/// Read until the stream has been quiet for a while.
+async fn read_until_idle(stream: &mut Substream<Muxer>) -> usize {
+    let mut total = 0;
+    let mut buf = [0u8; 4096];
+    while let Ok(Ok(n @ 1..)) =
+        tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await
+    {
+        total += n;
+    }
+    total
+}
+
+#[tokio::test]
+async fn overflow_policy_applies_to_peers_that_ignore_the_window() {
+    // Reset: the peer is told, and our reader sees the reset.
+    let (_mux, peer, mut inbound) = overrun(OverflowPolicy::Reset).await;
+    expect_bytes(&peer, &[0x05, 0x00]).await;
+    let mut rest = Vec::new();
+    let err = inbound.read_to_end(&mut rest).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(inbound.state(), StreamState::Reset);
+
+    // Drop: excess frames are lost, but once the reader catches up the
+    // stream carries data again.
+    let (_mux, peer, mut inbound) = overrun(OverflowPolicy::Drop).await;
+    let total = read_until_idle(&mut inbound).await;
+    assert!((4096..10 * 1024).contains(&total), "{total}");
+    peer.send(&message(0, b"tail")).await.unwrap();
+    let mut tail = [0u8; 4];
+    inbound.read_exact(&mut tail).await.unwrap();
+    assert_eq!(&tail, b"tail");
+
+    // Buffer: nothing is lost.
+    let (_mux, peer, mut inbound) = overrun(OverflowPolicy::Buffer).await;
+    peer.send(&message(0, b"tail")).await.unwrap();
+    let mut all = vec![0u8; 10 * 1024 + 4];
+    inbound.read_exact(&mut all).await.unwrap();
+    assert_eq!(&all[10 * 1024..], b"tail");
+}
+
+/// A muxer with tight limits against a raw peer.
+async fn limited(flow: FlowControl, limits: Limits) -> (Arc<Muxer>, EncryptedStream) {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::with_limits(ours, flow, limits);
+    mux.start_reader();
+    (mux, peer)
+}
+
+/// The peer sees the connection end once we give up on it.
+async fn expect_closed(peer: &EncryptedStream) {
+    let closed = tokio::time::timeout(Duration::from_secs(2), peer.recv()).await;
+    assert!(matches!(closed, Ok(Err(_))), "connection still open");
+}
+
+#[tokio::test]
+async fn stream_limits_refuse_extra_streams() {
+    let limits = Limits {
+        max_inbound_streams: 2,
+        max_outbound_streams: 1,
+        ..Limits::default()
+    };
+    let (mux, peer) = limited(FlowControl::default(), limits).await;
+
+    // The peer's third stream (number 2) is reset straight away.
+    peer.send(&[0x00, 0x00, 0x08, 0x00, 0x10, 0x00])
+        .await
+        .unwrap();
+    expect_bytes(&peer, &[0x15, 0x00]).await;
+    let _first = mux.accept_stream().await.unwrap();
+    let _second = mux.accept_stream().await.unwrap();
+    assert_eq!(
+        mux.violations(),
+        Violations {
+            inbound_stream_limit: 1,
+            ..Violations::default()
+        }
+    );
+
+    // Our own streams are capped too, until one finishes.
+    let mut outbound = mux.open_stream().await.unwrap();
+    expect_bytes(&peer, &[0x00, 0x00]).await;
+    let err = mux.open_stream().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    outbound.reset().await.unwrap();
+    expect_bytes(&peer, &[0x06, 0x00]).await;
+    mux.open_stream().await.unwrap();
+}
+
+#[tokio::test]
+async fn reused_stream_id_resets_the_stream() {
+    let (mux, peer) = limited(FlowControl::default(), Limits::default()).await;
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    let mut first = mux.accept_stream().await.unwrap();
+
+    // A second NewStream for the live stream 0 is refused, and the stream
+    // it collides with does not survive either.
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    expect_bytes(&peer, &[0x05, 0x00]).await;
+    let err = first.read(&mut [0u8; 1]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(
+        mux.violations(),
+        Violations {
+            duplicate_stream: 1,
+            ..Violations::default()
+        }
+    );
+
+    // Once it is gone, the id may be used again.
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    let mut again = mux.accept_stream().await.unwrap();
+    peer.send(&message(0, b"hi")).await.unwrap();
+    let mut buf = [0u8; 2];
+    again.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hi");
+}
+
+#[tokio::test]
+async fn oversized_frames_end_the_connection() {
+    let limits = Limits {
+        max_frame_size: 1024,
+        ..Limits::default()
+    };
+    let (mux, peer) = limited(FlowControl::default(), limits).await;
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    let mut inbound = mux.accept_stream().await.unwrap();
+
+    // Only the header of a 2000-byte message: it is refused unread.
+    peer.send(&[0x02, 0xd0, 0x0f]).await.unwrap();
+    let err = inbound.read(&mut [0u8; 1]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    expect_closed(&peer).await;
+    assert_eq!(mux.violations().frame_too_large, 1);
+}
+
+#[tokio::test]
+async fn buffer_limits_reset_the_stream_or_end_the_connection() {
+    let buffer_all = FlowControl {
+        receive_window: 1024,
+        overflow: OverflowPolicy::Buffer,
+    };
+
+    // Per stream: the stream is reset once it holds more than 4 KiB.
+    let limits = Limits {
+        max_stream_buffer: 4096,
+        ..Limits::default()
+    };
+    let (mux, peer) = limited(buffer_all, limits).await;
+    peer.send(&[0x00, 0x00]).await.unwrap();
+    let _unread = mux.accept_stream().await.unwrap();
+    for _ in 0..6 {
+        peer.send(&message(0, &[1u8; 1024])).await.unwrap();
+    }
+    expect_bytes(&peer, &[0x05, 0x00]).await;
+    assert_eq!(mux.violations().stream_buffer_limit, 1);
+
+    // Per connection: two streams under their own limit add up past it.
+    let limits = Limits {
+        max_connection_buffer: 6 * 1024,
+        ..Limits::default()
+    };
+    let (mux, peer) = limited(buffer_all, limits).await;
+    peer.send(&[0x00, 0x00, 0x08, 0x00]).await.unwrap();
+    let _a = mux.accept_stream().await.unwrap();
+    let _b = mux.accept_stream().await.unwrap();
+    for _ in 0..4 {
+        peer.send(&message(0, &[1u8; 1024])).await.unwrap();
+        peer.send(&message(1, &[1u8; 1024])).await.unwrap();
+    }
+    expect_closed(&peer).await;
+    assert_eq!(mux.violations().connection_buffer_limit, 1);
+}
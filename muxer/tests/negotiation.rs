@@ -0,0 +1,191 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use common::testing::stream_pair;
+use muxer::{Frame, FrameType, Muxer, StreamMuxer, StreamState, Yamux};
+use negotiation::{MULTISTREAM_PROTOCOL, NegotiationError, Version, encode_message};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PING: &str = "/ping/1.0.0";
+const CHAT: &str = "/chat/1.0.0";
+
+async fn mplex_pair() -> (Arc<Muxer>, Arc<Muxer>) {
+    let (a, b) = stream_pair().await;
+    let (a, b) = (Muxer::new(a), Muxer::new(b));
+    a.start_reader();
+    b.start_reader();
+    (a, b)
+}
+
+/// Accept one stream and negotiate it as the listener.
+fn listen<M: StreamMuxer>(
+    mux: &Arc<M>,
+    protocols: &'static [&'static str],
+) -> tokio::task::JoinHandle<Result<negotiation::Negotiated<muxer::Substream<M>>, NegotiationError>>
+{
+    let mux = Arc::clone(mux);
+    tokio::spawn(async move {
+        let substream = mux.accept_stream().await.unwrap();
+        substream.select_inbound(protocols).await
+    })
+}
+
+#[tokio::test]
+async fn dialer_moves_past_refused_protocols() {
+    let (a, b) = mplex_pair().await;
+    let listener = listen(&b, &[PING]);
+
+    let substream = a.open_stream().await.unwrap();
+    let mut outbound = substream
+        .select_outbound(&[CHAT, PING], Version::V1)
+        .await
+        .unwrap();
+    assert_eq!(outbound.protocol(), PING);
+    assert!(outbound.is_confirmed());
+    let mut inbound = listener.await.unwrap().unwrap();
+    assert_eq!(inbound.protocol(), PING);
+
+    outbound.write_all(b"PING").await.unwrap();
+    outbound.flush().await.unwrap();
+    let mut ping = [0u8; 4];
+    inbound.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"PING");
+}
+
+#[tokio::test]
+async fn lazy_dialer_sends_data_before_the_answer() {
+    let (a, b) = stream_pair().await;
+    let (a, b) = (Yamux::new(a, true), Yamux::new(b, false));
+    a.start();
+    b.start();
+    let listener = listen(&b, &[CHAT, PING]);
+
+    let substream = a.open_stream().await.unwrap();
+    let mut outbound = substream
+        .select_outbound(&[PING], Version::V1Lazy)
+        .await
+        .unwrap();
+    assert!(!outbound.is_confirmed());
+    outbound.write_all(b"PING").await.unwrap();
+    outbound.flush().await.unwrap();
+
+    let mut inbound = listener.await.unwrap().unwrap();
+    let mut ping = [0u8; 4];
+    inbound.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"PING");
+    inbound.write_all(b"PONG").await.unwrap();
+    inbound.flush().await.unwrap();
+    outbound.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"PONG");
+    assert!(outbound.is_confirmed());
+}
+
+#[tokio::test]
+async fn lazy_proposal_and_answer_match_the_wire_format() {
+    let (ours, peer) = stream_pair().await;
+    let mux = Muxer::new(ours);
+    mux.start_reader();
+
+    let substream = mux.open_stream().await.unwrap();
+    let mut outbound = substream
+        .select_outbound(&[PING], Version::V1Lazy)
+        .await
+        .unwrap();
+    let mut proposal = encode_message(MULTISTREAM_PROTOCOL.as_bytes());
+    proposal.extend_from_slice(&encode_message(PING.as_bytes()));
+    let mut expected = vec![0x00, 0x00];
+    expected.extend_from_slice(&message(FrameType::MessageInitiator, &proposal));
+    let mut got = vec![0u8; expected.len()];
+    peer.read_exact(&mut got).await.unwrap();
+    assert_eq!(got, expected);
+
+    // The answer and the first data arrive in one frame.
+    let mut answer = proposal.clone();
+    answer.extend_from_slice(b"hi");
+    peer.send(&message(FrameType::MessageReceiver, &answer))
+        .await
+        .unwrap();
+    let mut hi = [0u8; 2];
+    outbound.read_exact(&mut hi).await.unwrap();
+    assert_eq!(&hi, b"hi");
+    assert!(outbound.is_confirmed());
+}
+
+fn message(t: FrameType, payload: &[u8]) -> Vec<u8> {
+    Frame {
+        t,
+        stream_id: 0,
+        payload: payload.to_vec().into(),
+    }
+    .encode()
+    .to_vec()
+}
+
+#[tokio::test]
+async fn no_common_protocol_resets_the_stream() {
+    let (a, b) = mplex_pair().await;
+
+    // V1: the dialer learns during negotiation and resets the stream, which
+    // ends the listener's side too.
+    let listener = listen(&b, &[PING]);
+    let substream = a.open_stream().await.unwrap();
+    let id = substream.id();
+    let err = substream
+        .select_outbound(&[CHAT], Version::V1)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, NegotiationError::NoCommonProtocol { .. }));
+    assert_eq!(a.stream_state(id).await, None);
+    let err = listener.await.unwrap().unwrap_err();
+    assert!(
+        matches!(&err, NegotiationError::Io(e) if e.kind() == ErrorKind::ConnectionReset),
+        "{err:?}"
+    );
+
+    // V1Lazy: the refusal shows up on the first read.
+    let listener = listen(&b, &[PING]);
+    let substream = a.open_stream().await.unwrap();
+    let mut outbound = substream
+        .select_outbound(&[CHAT], Version::V1Lazy)
+        .await
+        .unwrap();
+    let err = outbound.read(&mut [0u8; 1]).await.unwrap_err();
+    let refused = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<NegotiationError>());
+    assert!(
+        matches!(refused, Some(NegotiationError::NoCommonProtocol { .. })),
+        "{err:?}"
+    );
+    outbound.get_mut().reset().await.unwrap();
+    assert_eq!(outbound.get_ref().state(), StreamState::Reset);
+    assert!(listener.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn lazy_refusal_on_a_split_stream_resets_it() {
+    let (a, b) = mplex_pair().await;
+    let listener = listen(&b, &[PING]);
+    let substream = a.open_stream().await.unwrap();
+    let id = substream.id();
+    let outbound = substream
+        .select_outbound(&[CHAT], Version::V1Lazy)
+        .await
+        .unwrap();
+
+    // The write half is kept elsewhere while a task reads; the refusal
+    // reaches that task, which hands its half back to reset the stream.
+    let (mut reader, mut writer) = tokio::io::split(outbound);
+    writer.write_all(b"hello\n").await.unwrap();
+    let reader = tokio::spawn(async move {
+        assert!(reader.read(&mut [0u8; 1]).await.is_err());
+        reader
+    })
+    .await
+    .unwrap();
+    let mut outbound = reader.unsplit(writer);
+    outbound.get_mut().reset().await.unwrap();
+
+    assert_eq!(a.stream_state(id).await, None);
+    assert!(listener.await.unwrap().is_err());
+    assert!(outbound.write_all(b"more").await.is_err());
+}
@@ -0,0 +1,605 @@
+use std::{io::ErrorKind, time::Duration};
+
+use common::{
+    EncryptedStream,
+    testing::{stream_pair, transports},
+};
+use muxer::{
+    FlowControl, Limits, MAX_FRAME_SIZE, OverflowPolicy, StreamId, StreamState, Substream,
+    Violations,
+    yamux::{
+        Flags, FrameType, GO_AWAY_INTERNAL_ERROR, GO_AWAY_PROTOCOL_ERROR, HEADER_LEN, Header,
+        INITIAL_WINDOW, Yamux, YamuxError,
+    },
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Headers as the spec lays them out: version, type, flags, stream id, length.
+const VECTORS: &[(FrameType, Flags, u32, u32, [u8; HEADER_LEN])] = &[
+    (
+        FrameType::Data,
+        Flags::SYN,
+        1,
+        5,
+        [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 5],
+    ),
+    (
+        FrameType::WindowUpdate,
+        Flags::ACK,
+        2,
+        0,
+        [0, 1, 0, 2, 0, 0, 0, 2, 0, 0, 0, 0],
+    ),
+    (
+        FrameType::WindowUpdate,
+        Flags::NONE,
+        3,
+        262_144,
+        [0, 1, 0, 0, 0, 0, 0, 3, 0, 4, 0, 0],
+    ),
+    (
+        FrameType::Data,
+        Flags(0x4 | 0x8),
+        0x0102_0304,
+        0,
+        [0, 0, 0, 12, 1, 2, 3, 4, 0, 0, 0, 0],
+    ),
+    (
+        FrameType::Ping,
+        Flags::SYN,
+        0,
+        42,
+        [0, 2, 0, 1, 0, 0, 0, 0, 0, 0, 0, 42],
+    ),
+    (
+        FrameType::GoAway,
+        Flags::NONE,
+        0,
+        1,
+        [0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+    ),
+];
+
+#[test]
+fn headers_match_reference_bytes() {
+    for &(t, flags, stream_id, length, wire) in VECTORS {
+        let header = Header {
+            t,
+            flags,
+            stream_id,
+            length,
+        };
+        assert_eq!(header.encode(), wire, "{header:?}");
+        assert_eq!(Header::decode(&wire).unwrap(), header);
+    }
+}
+
+#[test]
+fn rejects_unknown_versions_and_types() {
+    let mut wire = [0u8; HEADER_LEN];
+    wire[0] = 1;
+    assert!(matches!(
+        Header::decode(&wire),
+        Err(YamuxError::UnsupportedVersion(1))
+    ));
+    wire[0] = 0;
+    wire[1] = 4;
+    assert!(matches!(
+        Header::decode(&wire),
+        Err(YamuxError::UnknownType(4))
+    ));
+}
+
+fn header(t: FrameType, flags: Flags, stream_id: u32, length: u32) -> [u8; HEADER_LEN] {
+    Header {
+        t,
+        flags,
+        stream_id,
+        length,
+    }
+    .encode()
+}
+
+/// Read one frame the way a raw yamux peer would.
+async fn read_frame<R, W>(peer: &EncryptedStream<R, W>) -> (Header, Vec<u8>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut raw = [0u8; HEADER_LEN];
+    peer.read_exact(&mut raw).await.unwrap();
+    let header = Header::decode(&raw).unwrap();
+    let mut body = Vec::new();
+    if header.t == FrameType::Data {
+        body.resize(header.length as usize, 0);
+        peer.read_exact(&mut body).await.unwrap();
+    }
+    (header, body)
+}
+
+/// Start a dialer session against a raw peer and answer its first keepalive.
+async fn session_with_raw_peer() -> (std::sync::Arc<Yamux>, EncryptedStream) {
+    raw_session(FlowControl::default()).await
+}
+
+async fn raw_session(flow: FlowControl) -> (std::sync::Arc<Yamux>, EncryptedStream) {
+    limited_session(flow, Limits::default()).await
+}
+
+async fn limited_session(
+    flow: FlowControl,
+    limits: Limits,
+) -> (std::sync::Arc<Yamux>, EncryptedStream) {
+    let (ours, peer) = stream_pair().await;
+    let session = Yamux::with_limits(ours, true, flow, limits);
+    session.start();
+
+    let (ping, _) = read_frame(&peer).await;
+    assert_eq!((ping.t, ping.flags), (FrameType::Ping, Flags::SYN));
+    peer.send(&header(FrameType::Ping, Flags::ACK, 0, ping.length))
+        .await
+        .unwrap();
+    (session, peer)
+}
+
+#[tokio::test]
+async fn session_interoperates_with_raw_yamux_peer() {
+    let (session, peer) = session_with_raw_peer().await;
+
+    // The dialer's first stream is 1, opened with a SYN window update.
+    let mut outbound = session.open_stream().await.unwrap();
+    assert_eq!(
+        outbound.id(),
+        StreamId {
+            num: 1,
+            local: true
+        }
+    );
+    let (syn, _) = read_frame(&peer).await;
+    assert_eq!(
+        syn.encode(),
+        header(FrameType::WindowUpdate, Flags::SYN, 1, 0)
+    );
+    outbound.write_all(b"hi").await.unwrap();
+    outbound.flush().await.unwrap();
+    let (data, body) = read_frame(&peer).await;
+    assert_eq!(data.encode(), header(FrameType::Data, Flags::NONE, 1, 2));
+    assert_eq!(body, b"hi");
+
+    // The peer acknowledges, answers, and opens stream 2 with data, all in
+    // one Noise message.
+    let mut burst = Vec::new();
+    burst.extend_from_slice(&header(FrameType::WindowUpdate, Flags::ACK, 1, 0));
+    burst.extend_from_slice(&header(FrameType::Data, Flags::NONE, 1, 2));
+    burst.extend_from_slice(b"yo");
+    burst.extend_from_slice(&header(FrameType::Data, Flags::SYN, 2, 1));
+    burst.extend_from_slice(b"x");
+    peer.send(&burst).await.unwrap();
+
+    let mut inbound = session.accept_stream().await.unwrap();
+    assert_eq!(
+        inbound.id(),
+        StreamId {
+            num: 2,
+            local: false
+        }
+    );
+    let (ack, _) = read_frame(&peer).await;
+    assert_eq!(
+        ack.encode(),
+        header(FrameType::WindowUpdate, Flags::ACK, 2, 0)
+    );
+    let mut yo = [0u8; 2];
+    outbound.read_exact(&mut yo).await.unwrap();
+    assert_eq!(&yo, b"yo");
+    let mut x = [0u8; 1];
+    inbound.read_exact(&mut x).await.unwrap();
+    assert_eq!(&x, b"x");
+
+    // Pings are echoed with ACK and the same opaque value.
+    peer.send(&header(FrameType::Ping, Flags::SYN, 0, 42))
+        .await
+        .unwrap();
+    let (pong, _) = read_frame(&peer).await;
+    assert_eq!(pong.encode(), header(FrameType::Ping, Flags::ACK, 0, 42));
+
+    // A FIN from the peer ends the stream for our reader.
+    peer.send(&header(FrameType::WindowUpdate, Flags::FIN, 2, 0))
+        .await
+        .unwrap();
+    assert_eq!(inbound.read(&mut x).await.unwrap(), 0);
+
+    outbound.shutdown().await.unwrap();
+    let (fin, _) = read_frame(&peer).await;
+    assert_eq!(
+        fin.encode(),
+        header(FrameType::WindowUpdate, Flags::FIN, 1, 0)
+    );
+}
+
+#[tokio::test]
+async fn sender_stops_at_the_window_until_credit_arrives() {
+    let (session, peer) = session_with_raw_peer().await;
+    let mut outbound = session.open_stream().await.unwrap();
+    read_frame(&peer).await;
+
+    let total = INITIAL_WINDOW as usize + 100_000;
+    let sender = tokio::spawn(async move {
+        outbound.write_all(&vec![1u8; total]).await?;
+        outbound.flush().await?;
+        Ok::<_, std::io::Error>(outbound)
+    });
+
+    let mut received = 0;
+    while received < INITIAL_WINDOW as usize {
+        received += read_frame(&peer).await.1.len();
+    }
+    assert_eq!(received, INITIAL_WINDOW as usize);
+    let mut raw = [0u8; HEADER_LEN];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), peer.read_exact(&mut raw))
+            .await
+            .is_err(),
+        "sent past the window"
+    );
+
+    peer.send(&header(FrameType::WindowUpdate, Flags::NONE, 1, 100_000))
+        .await
+        .unwrap();
+    while received < total {
+        received += read_frame(&peer).await.1.len();
+    }
+    assert_eq!(received, total);
+    sender.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn two_sessions_exchange_more_than_a_window() {
+    let (a, b) = stream_pair().await;
+    let (a, b) = (Yamux::new(a, true), Yamux::new(b, false));
+    a.start();
+    b.start();
+    assert!(a.ping().await.is_ok());
+
+    let mut outbound = a.open_stream().await.unwrap();
+    outbound.write_all(b"hello").await.unwrap();
+    outbound.flush().await.unwrap();
+    let mut inbound = b.accept_stream().await.unwrap();
+    assert_eq!(inbound.id().num, outbound.id().num);
+    assert!(!inbound.id().local);
+
+    // A stream nobody reads stalls on its own window ...
+    let mut stalled = a.open_stream().await.unwrap();
+    let stuck = tokio::spawn(async move {
+        stalled
+            .write_all(&vec![0u8; 4 * INITIAL_WINDOW as usize])
+            .await?;
+        stalled.flush().await
+    });
+
+    // ... while this one keeps going far past its initial window.
+    let big: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let sender = tokio::spawn({
+        let big = big.clone();
+        async move {
+            outbound.write_all(&big).await?;
+            outbound.flush().await?;
+            Ok::<_, std::io::Error>(outbound)
+        }
+    });
+    let mut received = vec![0u8; 5 + big.len()];
+    inbound.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received[..5], b"hello");
+    assert!(received[5..] == big[..]);
+    let mut outbound = sender.await.unwrap().unwrap();
+    assert!(!stuck.is_finished());
+
+    inbound.write_all(b"pong").await.unwrap();
+    inbound.flush().await.unwrap();
+    let mut pong = [0u8; 4];
+    outbound.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"pong");
+    stuck.abort();
+}
+
+#[tokio::test]
+async fn half_close_and_reset_between_sessions() {
+    let (a, b) = stream_pair().await;
+    let (a, b) = (Yamux::new(a, true), Yamux::new(b, false));
+    a.start();
+    b.start();
+
+    // a closes its side first, yet still hears b's reply.
+    let mut outbound = a.open_stream().await.unwrap();
+    outbound.write_all(b"question").await.unwrap();
+    outbound.shutdown().await.unwrap();
+    assert_eq!(outbound.state(), StreamState::LocalClosed);
+    let mut inbound = b.accept_stream().await.unwrap();
+    let mut question = Vec::new();
+    inbound.read_to_end(&mut question).await.unwrap();
+    assert_eq!(question, b"question");
+    assert_eq!(inbound.state(), StreamState::RemoteClosed);
+    inbound.write_all(b"answer").await.unwrap();
+    inbound.shutdown().await.unwrap();
+    assert_eq!(inbound.state(), StreamState::Closed);
+    let mut answer = Vec::new();
+    outbound.read_to_end(&mut answer).await.unwrap();
+    assert_eq!(answer, b"answer");
+    assert_eq!(outbound.state(), StreamState::Closed);
+    assert_eq!(a.stream_state(outbound.id()).await, None);
+
+    // A reset reaches the peer's pending read and later writes.
+    let mut outbound = a.open_stream().await.unwrap();
+    outbound.write_all(b"x").await.unwrap();
+    outbound.flush().await.unwrap();
+    let mut inbound = b.accept_stream().await.unwrap();
+    let mut x = [0u8; 1];
+    inbound.read_exact(&mut x).await.unwrap();
+    let pending = tokio::spawn(async move {
+        let err = inbound.read(&mut [0u8; 1]).await.unwrap_err();
+        (inbound, err)
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    outbound.reset().await.unwrap();
+    let (mut inbound, err) = pending.await.unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(inbound.state(), StreamState::Reset);
+    let err = inbound.write_all(b"y").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+}
+
+#[tokio::test]
+async fn larger_windows_are_announced_and_overruns_end_the_session() {
+    let window = INITIAL_WINDOW + 64 * 1024;
+    let (session, peer) = raw_session(FlowControl {
+        receive_window: window,
+        overflow: OverflowPolicy::Reset,
+    })
+    .await;
+
+    // The extra 64 KiB ride on the SYN.
+    let _outbound = session.open_stream().await.unwrap();
+    let (syn, _) = read_frame(&peer).await;
+    assert_eq!(
+        syn.encode(),
+        header(FrameType::WindowUpdate, Flags::SYN, 1, 64 * 1024)
+    );
+
+    // Sending past the window is a protocol error, whatever the policy.
+    let chunk = vec![0u8; 64 * 1024];
+    for _ in 0..=window as usize / chunk.len() {
+        let mut frame = header(FrameType::Data, Flags::NONE, 1, chunk.len() as u32).to_vec();
+        frame.extend_from_slice(&chunk);
+        peer.send(&frame).await.unwrap();
+    }
+    assert_go_away(&session, &peer).await;
+}
+
+#[tokio::test]
+async fn reader_keeps_going_while_the_write_side_is_stuck() {
+    // A pipe this small fills up as soon as the peer stops reading.
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let (a, b) = transports();
+    let (or, ow) = tokio::io::split(ours);
+    let (tr, tw) = tokio::io::split(theirs);
+    let peer = EncryptedStream::new(b, tr, tw);
+    let session = Yamux::new(EncryptedStream::new(a, or, ow), true);
+    session.start();
+    let (ping, _) = read_frame(&peer).await;
+    peer.send(&header(FrameType::Ping, Flags::ACK, 0, ping.length))
+        .await
+        .unwrap();
+
+    let outbound = session.open_stream().await.unwrap();
+    read_frame(&peer).await;
+    let (mut reader, mut writer) = tokio::io::split(outbound);
+    let _stuck = tokio::spawn(async move { writer.write_all(&vec![0u8; 200 * 1024]).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The ping wants an answer that cannot be written yet; the data behind
+    // it must still get through.
+    peer.send(&header(FrameType::Ping, Flags::SYN, 0, 7))
+        .await
+        .unwrap();
+    peer.send(&data(1, b"still here")).await.unwrap();
+    let mut buf = [0u8; 10];
+    tokio::time::timeout(Duration::from_secs(2), reader.read_exact(&mut buf))
+        .await
+        .expect("the reader waited on the writer")
+        .unwrap();
+    assert_eq!(&buf, b"still here");
+}
+
+/// The session answers a protocol error with GoAway and then closes.
+async fn assert_go_away(session: &std::sync::Arc<Yamux>, peer: &EncryptedStream) {
+    let (go_away, _) = read_frame(peer).await;
+    assert_eq!(
+        go_away.encode(),
+        header(FrameType::GoAway, Flags::NONE, 0, GO_AWAY_PROTOCOL_ERROR)
+    );
+    assert_eq!(
+        peer.recv().await.unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+    assert!(session.open_stream().await.is_err());
+}
+
+#[tokio::test]
+async fn oversized_data_header_is_rejected_before_its_body_arrives() {
+    let (session, peer) = session_with_raw_peer().await;
+    let _outbound = session.open_stream().await.unwrap();
+    read_frame(&peer).await;
+
+    // Claims 4 GiB and never delivers; the session must not wait for it.
+    peer.send(&header(FrameType::Data, Flags::NONE, 1, u32::MAX))
+        .await
+        .unwrap();
+    assert_go_away(&session, &peer).await;
+}
+
+#[tokio::test]
+async fn frames_for_unknown_streams_are_held_to_the_frame_size() {
+    let (session, peer) = session_with_raw_peer().await;
+    peer.send(&header(
+        FrameType::Data,
+        Flags::NONE,
+        7,
+        MAX_FRAME_SIZE as u32 + 1,
+    ))
+    .await
+    .unwrap();
+    assert_go_away(&session, &peer).await;
+    assert_eq!(session.violations().frame_too_large, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_ping_ends_the_session() {
+    let (session, peer) = session_with_raw_peer().await;
+    let mut inbound = peer_opens(&session, &peer, 2).await;
+    let accept = tokio::spawn({
+        let session = std::sync::Arc::clone(&session);
+        async move { session.accept_stream().await.is_none() }
+    });
+
+    // The next keepalive goes unanswered.
+    let (ping, _) = read_frame(&peer).await;
+    assert_eq!((ping.t, ping.flags), (FrameType::Ping, Flags::SYN));
+    let mut buf = [0u8; 1];
+    assert_eq!(
+        inbound.read(&mut buf).await.unwrap_err().kind(),
+        ErrorKind::ConnectionReset
+    );
+    assert!(accept.await.unwrap());
+    assert!(session.open_stream().await.is_err());
+
+    let (go_away, _) = read_frame(&peer).await;
+    assert_eq!(
+        go_away.encode(),
+        header(FrameType::GoAway, Flags::NONE, 0, GO_AWAY_INTERNAL_ERROR)
+    );
+    assert_eq!(
+        peer.recv().await.unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+/// Open a stream from the raw peer and accept it.
+async fn peer_opens(
+    session: &std::sync::Arc<Yamux>,
+    peer: &EncryptedStream,
+    id: u32,
+) -> Substream<Yamux> {
+    peer.send(&header(FrameType::WindowUpdate, Flags::SYN, id, 0))
+        .await
+        .unwrap();
+    let (ack, _) = read_frame(peer).await;
+    assert_eq!(
+        ack.encode(),
+        header(FrameType::WindowUpdate, Flags::ACK, id, 0)
+    );
+    session.accept_stream().await.unwrap()
+}
+
+fn data(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut frame = header(FrameType::Data, Flags::NONE, id, body.len() as u32).to_vec();
+    frame.extend_from_slice(body);
+    frame
+}
+
+#[tokio::test]
+async fn stream_limits_refuse_extra_streams() {
+    let limits = Limits {
+        max_inbound_streams: 2,
+        max_outbound_streams: 1,
+        ..Limits::default()
+    };
+    let (session, peer) = limited_session(FlowControl::default(), limits).await;
+
+    // The peer's third stream is reset straight away.
+    let _first = peer_opens(&session, &peer, 2).await;
+    let _second = peer_opens(&session, &peer, 4).await;
+    peer.send(&header(FrameType::WindowUpdate, Flags::SYN, 6, 0))
+        .await
+        .unwrap();
+    let (rst, _) = read_frame(&peer).await;
+    assert_eq!(
+        rst.encode(),
+        header(FrameType::WindowUpdate, Flags::RST, 6, 0)
+    );
+    assert_eq!(
+        session.violations(),
+        Violations {
+            inbound_stream_limit: 1,
+            ..Violations::default()
+        }
+    );
+
+    // Our own streams are capped too, until one finishes.
+    let mut outbound = session.open_stream().await.unwrap();
+    read_frame(&peer).await;
+    assert!(matches!(
+        session.open_stream().await,
+        Err(YamuxError::OutboundStreamLimit)
+    ));
+    outbound.reset().await.unwrap();
+    let (rst, _) = read_frame(&peer).await;
+    assert_eq!(
+        rst.encode(),
+        header(FrameType::WindowUpdate, Flags::RST, 1, 0)
+    );
+    session.open_stream().await.unwrap();
+}
+
+#[tokio::test]
+async fn oversized_frames_end_the_session() {
+    let limits = Limits {
+        max_frame_size: 1024,
+        ..Limits::default()
+    };
+    let (session, peer) = limited_session(FlowControl::default(), limits).await;
+    let mut inbound = peer_opens(&session, &peer, 2).await;
+
+    // Well inside the window, but over the frame size.
+    peer.send(&header(FrameType::Data, Flags::NONE, 2, 2000))
+        .await
+        .unwrap();
+    let err = inbound.read(&mut [0u8; 1]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_go_away(&session, &peer).await;
+    assert_eq!(session.violations().frame_too_large, 1);
+}
+
+#[tokio::test]
+async fn buffer_limits_reset_the_stream_or_end_the_session() {
+    // Per stream: the stream is reset once it holds more than 4 KiB.
+    let limits = Limits {
+        max_stream_buffer: 4096,
+        ..Limits::default()
+    };
+    let (session, peer) = limited_session(FlowControl::default(), limits).await;
+    let _unread = peer_opens(&session, &peer, 2).await;
+    for _ in 0..6 {
+        peer.send(&data(2, &[1u8; 1024])).await.unwrap();
+    }
+    let (rst, _) = read_frame(&peer).await;
+    assert_eq!(
+        rst.encode(),
+        header(FrameType::WindowUpdate, Flags::RST, 2, 0)
+    );
+    assert_eq!(session.violations().stream_buffer_limit, 1);
+
+    // Per session: two streams under their own limit add up past it.
+    let limits = Limits {
+        max_connection_buffer: 6 * 1024,
+        ..Limits::default()
+    };
+    let (session, peer) = limited_session(FlowControl::default(), limits).await;
+    let _a = peer_opens(&session, &peer, 2).await;
+    let _b = peer_opens(&session, &peer, 4).await;
+    for _ in 0..5 {
+        peer.send(&data(2, &[1u8; 1024])).await.unwrap();
+        peer.send(&data(4, &[1u8; 1024])).await.unwrap();
+    }
+    assert_go_away(&session, &peer).await;
+    assert_eq!(session.violations().connection_buffer_limit, 1);
+}
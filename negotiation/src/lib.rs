@@ -1,101 +1,331 @@
-use common::EncryptedStream;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use common::{EncryptedStream, varint};
+use std::{future::Future, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
 
-pub async fn negotiate_protocol(
-    stream: &mut EncryptedStream,
-    is_initiator: bool,
-    supported_protocols: &Vec<&'static str>,
-) -> tokio::io::Result<String> {
-    if is_initiator {
-        println!("[negotiate_protocol] -> Sending /multistream/1.0.0");
-        let _ = stream.send(b"/multistream/1.0.0\n").await;
+mod negotiated;
+pub use negotiated::{Negotiated, Selected, Version, dialer_select, listener_select};
+
+/// Protocol id of multistream-select itself, exchanged first by both peers.
+pub const MULTISTREAM_PROTOCOL: &str = "/multistream/1.0.0";
+/// Reply sent by the listener when it does not support the proposed protocol.
+pub const NA: &str = "na";
+/// Request for the list of protocols supported by the listener.
+pub const LS: &str = "ls";
+/// Upper bound on the length of a single message (including the trailing `\n`).
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+/// How long a complete negotiation may take before it is abandoned.
+pub const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug)]
+pub enum NegotiationError {
+    #[error("unsupported negotiation protocol: {0}")]
+    UnsupportedProtocol(String),
+    /// The listener answered `na` to every protocol the dialer proposed.
+    #[error("no common protocol, proposed {proposed:?}")]
+    NoCommonProtocol { proposed: Vec<String> },
+    #[error("malformed message: {0}")]
+    MalformedMessage(String),
+    #[error("i/o error: {0}")]
+    Io(#[from] tokio::io::Error),
+    #[error("negotiation timed out")]
+    Timeout,
+}
+
+/// Encode `body` as a multistream-select message: `uvarint(len) || body || '\n'`,
+/// where `len` counts the trailing newline.
+pub fn encode_message(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 1 + varint::MAX_LEN);
+    varint::encode(body.len() as u64 + 1, &mut out);
+    out.extend_from_slice(body);
+    out.push(b'\n');
+    out
+}
+
+/// Try to decode one message from the front of `buf`.
+/// Returns `Ok(None)` when more bytes are needed, otherwise the message body
+/// (without the trailing `\n`) and the number of bytes consumed.
+pub fn decode_message(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, NegotiationError> {
+    let (len, prefix) = match varint::decode(buf) {
+        Ok(v) => v,
+        Err(varint::DecodeError::Incomplete) => return Ok(None),
+        Err(e) => return Err(malformed(e.to_string())),
+    };
+    let len = check_len(len)?;
+
+    if buf.len() < prefix + len {
+        return Ok(None);
+    }
+    let msg = &buf[prefix..prefix + len];
+    if msg[len - 1] != b'\n' {
+        return Err(malformed("message is not newline terminated"));
+    }
+    Ok(Some((msg[..len - 1].to_vec(), prefix + len)))
+}
+
+/// Read one message from a byte stream.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, NegotiationError> {
+    let mut prefix = Vec::with_capacity(varint::MAX_LEN);
+    let len = loop {
+        prefix.push(reader.read_u8().await?);
+        match varint::decode(&prefix) {
+            Ok((len, _)) => break len,
+            Err(varint::DecodeError::Incomplete) => continue,
+            Err(e) => return Err(malformed(e.to_string())),
+        }
+    };
+    let len = check_len(len)?;
+
+    let mut msg = vec![0u8; len];
+    reader.read_exact(&mut msg).await?;
+    if msg.pop() != Some(b'\n') {
+        return Err(malformed("message is not newline terminated"));
+    }
+    Ok(msg)
+}
+
+/// Write one message to a byte stream.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    body: &[u8],
+) -> tokio::io::Result<()> {
+    writer.write_all(&encode_message(body)).await?;
+    writer.flush().await
+}
+
+fn check_len(len: u64) -> Result<usize, NegotiationError> {
+    match len as usize {
+        0 => Err(malformed("empty message")),
+        n if n > MAX_MESSAGE_LEN => Err(malformed(format!("message too large: {n}"))),
+        n => Ok(n),
+    }
+}
+
+fn malformed(msg: impl Into<String>) -> NegotiationError {
+    NegotiationError::MalformedMessage(msg.into())
+}
+
+/// A channel multistream-select messages can be exchanged over.
+pub trait MessageIo: Send {
+    /// Send one message; `body` excludes the length prefix and the trailing `\n`.
+    fn send_message(
+        &mut self,
+        body: &[u8],
+    ) -> impl Future<Output = Result<(), NegotiationError>> + Send;
+    /// Receive one message, returning its body.
+    fn recv_message(&mut self) -> impl Future<Output = Result<Vec<u8>, NegotiationError>> + Send;
+}
+
+/// Multistream-select over a plain byte stream, e.g. the TCP halves before
+/// the security upgrade or a muxer substream.
+pub struct StreamIo<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+}
+
+impl<'a, R, W> StreamIo<'a, R, W> {
+    pub fn new(reader: &'a mut R, writer: &'a mut W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl<R, W> MessageIo for StreamIo<'_, R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn send_message(&mut self, body: &[u8]) -> Result<(), NegotiationError> {
+        Ok(write_message(self.writer, body).await?)
+    }
+
+    async fn recv_message(&mut self) -> Result<Vec<u8>, NegotiationError> {
+        read_message(self.reader).await
     }
+}
+
+/// Multistream-select over an `EncryptedStream`. Every outgoing message is
+/// sent as its own Noise frame; incoming frames are buffered so messages the
+/// peer coalesced or split across frames are still decoded correctly.
+pub struct EncryptedIo<'a, R = OwnedReadHalf, W = OwnedWriteHalf> {
+    stream: &'a EncryptedStream<R, W>,
+    buf: Vec<u8>,
+}
+
+impl<'a, R, W> EncryptedIo<'a, R, W> {
+    pub fn new(stream: &'a EncryptedStream<R, W>) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<R, W> MessageIo for EncryptedIo<'_, R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn send_message(&mut self, body: &[u8]) -> Result<(), NegotiationError> {
+        Ok(self.stream.send(&encode_message(body)).await?)
+    }
+
+    async fn recv_message(&mut self) -> Result<Vec<u8>, NegotiationError> {
+        loop {
+            if let Some((msg, consumed)) = decode_message(&self.buf)? {
+                self.buf.drain(..consumed);
+                return Ok(msg);
+            }
+            let chunk = self.stream.recv().await?;
+            self.buf.extend_from_slice(&chunk);
+        }
+    }
+}
+
+pub async fn negotiate_protocol<R, W>(
+    stream: &mut EncryptedStream<R, W>,
+    is_initiator: bool,
+    supported_protocols: &[&'static str],
+) -> Result<String, NegotiationError>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    select_protocol(
+        &mut EncryptedIo::new(stream),
+        is_initiator,
+        supported_protocols,
+    )
+    .await
+}
+
+/// Run multistream-select over any `MessageIo`: exchange the
+/// `/multistream/1.0.0` header, then agree on one of `supported_protocols`.
+///
+/// The dialer (`is_initiator`) proposes the protocols in order and moves on to
+/// the next one whenever the listener answers `na`. The listener accepts the
+/// first proposal that is contained in `supported_protocols`.
+///
+/// Gives up with `NegotiationError::Timeout` after `NEGOTIATION_TIMEOUT`.
+pub async fn select_protocol<T: MessageIo>(
+    io: &mut T,
+    is_initiator: bool,
+    supported_protocols: &[&str],
+) -> Result<String, NegotiationError> {
+    tokio::time::timeout(
+        NEGOTIATION_TIMEOUT,
+        select_protocol_inner(io, is_initiator, supported_protocols),
+    )
+    .await
+    .map_err(|_| NegotiationError::Timeout)?
+}
 
-    let response = stream.recv().await.unwrap();
+async fn select_protocol_inner<T: MessageIo>(
+    io: &mut T,
+    is_initiator: bool,
+    supported_protocols: &[&str],
+) -> Result<String, NegotiationError> {
+    println!("[negotiate_protocol] -> Sending {MULTISTREAM_PROTOCOL}");
+    io.send_message(MULTISTREAM_PROTOCOL.as_bytes()).await?;
+
+    let response = io.recv_message().await?;
     let proto = String::from_utf8_lossy(&response);
     println!(
         "[negotiate_protocol] <- Received negotiation protocol: {}",
         proto
     );
 
-    if proto.trim() == "/multistream/1.0.0" {
-        if !is_initiator {
-            println!("[negotiate_protocol] -> Sending /multistream/1.0.0");
-            let _ = stream.send(b"/multistream/1.0.0\n").await;
-        }
-        println!("[negotiate_protocol] Entering subprotocol negotiation");
-        if let Some(transport) = negotiate(stream, is_initiator, &supported_protocols)
-            .await
-            .unwrap()
-        {
-            println!("[negotiate_protocol] ✅ Agreed on protocol: {transport}");
-            return Ok(transport);
-        } else {
-            eprintln!("[negotiate_protocol] ❌ Unimplemented protocol");
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Unsupported negotiation protocol: {}", proto),
-            ));
+    if proto != MULTISTREAM_PROTOCOL {
+        eprintln!("[negotiate_protocol] Unsupported negotiation protocol: {proto}");
+        return Err(NegotiationError::UnsupportedProtocol(proto.into_owned()));
+    }
+
+    println!("[negotiate_protocol] Entering subprotocol negotiation");
+    let agreed = negotiate(io, is_initiator, supported_protocols).await?;
+    println!("[negotiate_protocol] ✅ Agreed on protocol: {agreed}");
+    Ok(agreed)
+}
+
+/// Ask the listener which protocols it supports. Must be called after the
+/// multistream header has been exchanged.
+pub async fn list_protocols<T: MessageIo>(io: &mut T) -> Result<Vec<String>, NegotiationError> {
+    io.send_message(LS.as_bytes()).await?;
+    let body = io.recv_message().await?;
+
+    let mut protocols = Vec::new();
+    let mut rest = &body[..];
+    while !rest.is_empty() {
+        match decode_message(rest)? {
+            Some((proto, consumed)) => {
+                protocols.push(String::from_utf8_lossy(&proto).into_owned());
+                rest = &rest[consumed..];
+            }
+            None => return Err(malformed("truncated ls response")),
         }
-    } else {
-        eprintln!(
-            "[negotiate_protocol] Unsupported negotiation protocol: {other}",
-            other = proto
-        );
-        std::process::exit(1);
     }
+    Ok(protocols)
+}
+
+fn encode_ls_response(supported_protocols: &[&str]) -> Vec<u8> {
+    supported_protocols
+        .iter()
+        .flat_map(|p| encode_message(p.as_bytes()))
+        .collect()
 }
 
-async fn negotiate(
-    stream: &mut EncryptedStream,
+async fn negotiate<T: MessageIo>(
+    io: &mut T,
     is_initiator: bool,
-    supported_protocols: &Vec<&'static str>,
-) -> tokio::io::Result<Option<String>> {
+    supported_protocols: &[&str],
+) -> Result<String, NegotiationError> {
     println!("[negotiate] Started negotiation, initiator={is_initiator}");
 
     if is_initiator {
-        let mut input = String::new();
-        let mut stdin_reader = BufReader::new(tokio::io::stdin());
-        println!(
-            "[negotiate][initiator] Available protocols: {:?}",
-            supported_protocols
-        );
-
-        stdin_reader.read_line(&mut input).await?;
-        let proto = input.trim();
-        println!("[negotiate][initiator] Proposing protocol: {proto}");
-
-        stream.send(format!("{proto}\n").as_bytes()).await?;
-
-        let response = stream.recv().await.unwrap();
-        let line = String::from_utf8_lossy(&response);
-        println!("[negotiate][initiator] <- Received response: {}", line);
-
-        if line.trim() == proto {
-            println!("[negotiate][initiator] ✅ Negotiated protocol: {proto}");
-            Ok(Some(proto.to_string()))
-        } else {
-            println!(
-                "[negotiate][initiator] ❌ Protocol rejected by responder: {}",
-                line.trim()
-            );
-            Ok(None)
+        for proto in supported_protocols {
+            println!("[negotiate][initiator] Proposing protocol: {proto}");
+            io.send_message(proto.as_bytes()).await?;
+
+            let response = io.recv_message().await?;
+            let line = String::from_utf8_lossy(&response);
+            println!("[negotiate][initiator] <- Received response: {}", line);
+
+            if line == *proto {
+                println!("[negotiate][initiator] ✅ Negotiated protocol: {proto}");
+                return Ok(proto.to_string());
+            } else if line == NA {
+                println!("[negotiate][initiator] ❌ Protocol rejected by responder: {proto}");
+            } else {
+                return Err(malformed(format!("unexpected response: {line}")));
+            }
         }
+
+        Err(NegotiationError::NoCommonProtocol {
+            proposed: supported_protocols.iter().map(|p| p.to_string()).collect(),
+        })
     } else {
-        println!("[negotiate][responder] Waiting for initiator proposal");
-        let response = stream.recv().await.unwrap();
-        let line = String::from_utf8_lossy(&response);
-        let proposal = line.trim();
-        println!("[negotiate][responder] <- Received proposal: {proposal}");
-
-        if supported_protocols.contains(&proposal) {
-            println!("[negotiate][responder] ✅ Accepting proposal: {proposal}");
-            stream.send(format!("{}\n", proposal).as_bytes()).await?;
-            Ok(Some(proposal.to_string()))
-        } else {
-            eprintln!("[negotiate][responder] ❌ Unsupported proposal: {proposal}, replying 'na'");
-            stream.send(b"na\n").await?;
-            Ok(None)
+        loop {
+            println!("[negotiate][responder] Waiting for initiator proposal");
+            let response = io.recv_message().await?;
+            let proposal = String::from_utf8_lossy(&response);
+            println!("[negotiate][responder] <- Received proposal: {proposal}");
+
+            if proposal == LS {
+                println!("[negotiate][responder] -> Listing supported protocols");
+                io.send_message(&encode_ls_response(supported_protocols))
+                    .await?;
+            } else if supported_protocols.contains(&proposal.as_ref()) {
+                println!("[negotiate][responder] ✅ Accepting proposal: {proposal}");
+                io.send_message(&response).await?;
+                return Ok(proposal.into_owned());
+            } else {
+                eprintln!(
+                    "[negotiate][responder] ❌ Unsupported proposal: {proposal}, replying 'na'"
+                );
+                io.send_message(NA.as_bytes()).await?;
+            }
         }
     }
 }
@@ -0,0 +1,256 @@
+//! multistream-select over an arbitrary byte stream, such as a muxer
+//! substream, including the optimistic `V1Lazy` mode.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    MULTISTREAM_PROTOCOL, MessageIo, NA, NegotiationError, decode_message, encode_message,
+    read_message, select_protocol, write_message,
+};
+
+/// How the dialer runs the negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// Wait for the listener to accept a protocol before using the stream.
+    V1,
+    /// With a single protocol, send the header and the proposal together
+    /// and start using the stream at once; the listener's answer is checked
+    /// on the first read. With several protocols this falls back to `V1`.
+    V1Lazy,
+}
+
+/// What a negotiation settled on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    pub protocol: String,
+    /// `false` while a `V1Lazy` proposal still awaits the listener's answer.
+    pub confirmed: bool,
+}
+
+/// Propose `protocols` in order as the dialer.
+pub async fn dialer_select<S>(
+    io: &mut S,
+    protocols: &[&str],
+    version: Version,
+) -> Result<Selected, NegotiationError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if let (Version::V1Lazy, [protocol]) = (version, protocols) {
+        println!("[dialer_select] -> Optimistically proposing {protocol}");
+        let mut msg = encode_message(MULTISTREAM_PROTOCOL.as_bytes());
+        msg.extend_from_slice(&encode_message(protocol.as_bytes()));
+        io.write_all(&msg).await?;
+        io.flush().await?;
+        return Ok(Selected {
+            protocol: protocol.to_string(),
+            confirmed: false,
+        });
+    }
+    let protocol = select_protocol(&mut DuplexIo(io), true, protocols).await?;
+    Ok(Selected {
+        protocol,
+        confirmed: true,
+    })
+}
+
+/// Accept the first proposal found in `protocols` as the listener.
+pub async fn listener_select<S>(
+    io: &mut S,
+    protocols: &[&str],
+) -> Result<Selected, NegotiationError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let protocol = select_protocol(&mut DuplexIo(io), false, protocols).await?;
+    Ok(Selected {
+        protocol,
+        confirmed: true,
+    })
+}
+
+/// Messages over one stream that both reads and writes.
+struct DuplexIo<'a, S>(&'a mut S);
+
+impl<S> MessageIo for DuplexIo<'_, S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send_message(&mut self, body: &[u8]) -> Result<(), NegotiationError> {
+        Ok(write_message(self.0, body).await?)
+    }
+
+    async fn recv_message(&mut self) -> Result<Vec<u8>, NegotiationError> {
+        read_message(self.0).await
+    }
+}
+
+/// Where a lazy dialer is in reading the listener's answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Confirm {
+    Header,
+    Protocol,
+    Done,
+    Failed,
+}
+
+/// A stream tagged with the protocol agreed on it. Writes go straight
+/// through; reads first finish any confirmation `V1Lazy` left pending, and
+/// fail if the listener refused the protocol.
+#[derive(Debug)]
+pub struct Negotiated<S> {
+    inner: S,
+    protocol: String,
+    confirm: Confirm,
+    /// Bytes read past the listener's answer, handed out first.
+    buf: Vec<u8>,
+}
+
+impl<S> Negotiated<S> {
+    pub fn new(inner: S, selected: Selected) -> Self {
+        Self {
+            inner,
+            protocol: selected.protocol,
+            confirm: if selected.confirmed {
+                Confirm::Done
+            } else {
+                Confirm::Header
+            },
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Whether the listener is known to have accepted the protocol.
+    pub fn is_confirmed(&self) -> bool {
+        self.confirm == Confirm::Done
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> Negotiated<S> {
+    /// Wait for the listener's answer to a `V1Lazy` proposal, for a dialer
+    /// that wants to know before it reads.
+    pub async fn confirm(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_confirm(cx)).await
+    }
+
+    fn poll_confirm(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let expected = match self.confirm {
+                Confirm::Done => return Poll::Ready(Ok(())),
+                Confirm::Failed => {
+                    return Poll::Ready(Err(io::Error::other("protocol negotiation failed")));
+                }
+                Confirm::Header => MULTISTREAM_PROTOCOL,
+                Confirm::Protocol => self.protocol.as_str(),
+            };
+            let decoded = decode_message(&self.buf).map_err(into_io);
+            match decoded {
+                Ok(Some((msg, consumed))) => {
+                    self.buf.drain(..consumed);
+                    if msg == expected.as_bytes() {
+                        self.confirm = match self.confirm {
+                            Confirm::Header => Confirm::Protocol,
+                            _ => Confirm::Done,
+                        };
+                        continue;
+                    }
+                    let err = match self.confirm {
+                        Confirm::Protocol if msg == NA.as_bytes() => {
+                            NegotiationError::NoCommonProtocol {
+                                proposed: vec![self.protocol.clone()],
+                            }
+                        }
+                        Confirm::Header => NegotiationError::UnsupportedProtocol(
+                            String::from_utf8_lossy(&msg).into_owned(),
+                        ),
+                        _ => NegotiationError::MalformedMessage(format!(
+                            "unexpected response: {}",
+                            String::from_utf8_lossy(&msg)
+                        )),
+                    };
+                    self.confirm = Confirm::Failed;
+                    return Poll::Ready(Err(into_io(err)));
+                }
+                Ok(None) => {
+                    let mut chunk = [0u8; 1024];
+                    let mut read = ReadBuf::new(&mut chunk);
+                    if let Err(e) = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read)) {
+                        self.confirm = Confirm::Failed;
+                        return Poll::Ready(Err(e));
+                    }
+                    if read.filled().is_empty() {
+                        self.confirm = Confirm::Failed;
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    self.buf.extend_from_slice(read.filled());
+                }
+                Err(e) => {
+                    self.confirm = Confirm::Failed;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn into_io(e: NegotiationError) -> io::Error {
+    match e {
+        NegotiationError::Io(e) => e,
+        NegotiationError::MalformedMessage(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+        other => io::Error::other(other),
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Negotiated<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_confirm(cx))?;
+        if !this.buf.is_empty() {
+            let n = this.buf.len().min(buf.remaining());
+            buf.put_slice(&this.buf[..n]);
+            this.buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Negotiated<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,99 @@
+use negotiation::{
+    MULTISTREAM_PROTOCOL, NEGOTIATION_TIMEOUT, NegotiationError, StreamIo, encode_message,
+    select_protocol,
+};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream, duplex, split},
+    time::Instant,
+};
+
+async fn negotiate(
+    stream: DuplexStream,
+    is_initiator: bool,
+    protocols: &'static [&'static str],
+) -> Result<String, NegotiationError> {
+    let (mut r, mut w) = split(stream);
+    select_protocol(&mut StreamIo::new(&mut r, &mut w), is_initiator, protocols).await
+}
+
+#[tokio::test]
+async fn dialer_walks_its_preference_list() {
+    let (a, b) = duplex(1024);
+    let listener = tokio::spawn(negotiate(b, false, &["/yamux/1.0.0", "/mplex/6.7.0"]));
+    let agreed = negotiate(a, true, &["/unknown/1.0.0", "/mplex/6.7.0"])
+        .await
+        .unwrap();
+    assert_eq!(agreed, "/mplex/6.7.0");
+    assert_eq!(listener.await.unwrap().unwrap(), "/mplex/6.7.0");
+}
+
+#[tokio::test]
+async fn dialer_gets_no_common_protocol_when_every_proposal_is_refused() {
+    let (a, b) = duplex(1024);
+    let listener = tokio::spawn(negotiate(b, false, &["/yamux/1.0.0"]));
+    let err = negotiate(a, true, &["/mplex/6.7.0", "/chat/1.0.0"])
+        .await
+        .unwrap_err();
+    match err {
+        NegotiationError::NoCommonProtocol { proposed } => {
+            assert_eq!(proposed, ["/mplex/6.7.0", "/chat/1.0.0"]);
+        }
+        other => panic!("expected NoCommonProtocol, got {other:?}"),
+    }
+
+    // The dialer hangs up after its last proposal; the listener sees EOF
+    // instead of agreeing on anything.
+    let err = listener.await.unwrap().unwrap_err();
+    assert!(matches!(err, NegotiationError::Io(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn wrong_multistream_header_is_unsupported_protocol() {
+    let (a, mut b) = duplex(1024);
+    b.write_all(&encode_message(b"/multistream/2.0.0"))
+        .await
+        .unwrap();
+    let err = negotiate(a, true, &["/chat/1.0.0"]).await.unwrap_err();
+    match err {
+        NegotiationError::UnsupportedProtocol(proto) => assert_eq!(proto, "/multistream/2.0.0"),
+        other => panic!("expected UnsupportedProtocol, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn garbage_header_is_malformed() {
+    let (a, mut b) = duplex(1024);
+    // Length 5, but the body is not newline terminated.
+    b.write_all(b"\x05hello").await.unwrap();
+    let err = negotiate(a, false, &["/chat/1.0.0"]).await.unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn unexpected_answer_to_a_proposal_is_malformed() {
+    let (a, mut b) = duplex(1024);
+    b.write_all(&encode_message(MULTISTREAM_PROTOCOL.as_bytes()))
+        .await
+        .unwrap();
+    b.write_all(&encode_message(b"/something/else"))
+        .await
+        .unwrap();
+    let err = negotiate(a, true, &["/chat/1.0.0"]).await.unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn stalled_peer_times_out() {
+    // Keep the other end open without ever writing to it.
+    let (a, _stalled) = duplex(1024);
+    let started = Instant::now();
+    let err = negotiate(a, true, &["/chat/1.0.0"]).await.unwrap_err();
+    assert!(matches!(err, NegotiationError::Timeout), "{err:?}");
+    assert!(started.elapsed() >= NEGOTIATION_TIMEOUT);
+}
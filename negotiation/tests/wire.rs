@@ -0,0 +1,159 @@
+use negotiation::{
+    LS, MULTISTREAM_PROTOCOL, MessageIo, NegotiationError, StreamIo, decode_message,
+    encode_message, list_protocols, read_message, select_protocol, write_message,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex, split};
+
+const HEADER: &[u8] = b"\x13/multistream/1.0.0\n";
+
+#[test]
+fn encodes_length_prefix_and_newline() {
+    assert_eq!(encode_message(MULTISTREAM_PROTOCOL.as_bytes()), HEADER);
+    assert_eq!(encode_message(b"na"), b"\x03na\n");
+    assert_eq!(encode_message(b""), b"\x01\n");
+
+    // 127 bytes of body plus the newline need a two-byte prefix.
+    let body = vec![b'x'; 127];
+    let encoded = encode_message(&body);
+    assert_eq!(&encoded[..2], &[0x80, 0x01]);
+    assert_eq!(&encoded[2..129], &body[..]);
+    assert_eq!(encoded[129], b'\n');
+    assert_eq!(encoded.len(), 130);
+}
+
+#[test]
+fn decodes_one_message_at_a_time() {
+    let mut buf = HEADER.to_vec();
+    buf.extend_from_slice(b"\x03na\n");
+
+    let (body, consumed) = decode_message(&buf).unwrap().unwrap();
+    assert_eq!(body, MULTISTREAM_PROTOCOL.as_bytes());
+    assert_eq!(consumed, HEADER.len());
+
+    let (body, consumed) = decode_message(&buf[consumed..]).unwrap().unwrap();
+    assert_eq!(body, b"na");
+    assert_eq!(consumed, 4);
+}
+
+#[test]
+fn decode_waits_for_the_whole_message() {
+    for end in 0..HEADER.len() {
+        assert!(
+            decode_message(&HEADER[..end]).unwrap().is_none(),
+            "prefix of {end} bytes"
+        );
+    }
+}
+
+#[test]
+fn decode_rejects_non_minimal_length() {
+    let err = decode_message(b"\x83\x00na\n").unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn decode_rejects_overflowing_length() {
+    let mut buf = vec![0xff; 9];
+    buf.push(0x02);
+    let err = decode_message(&buf).unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn decode_rejects_missing_newline() {
+    let err = decode_message(b"\x03nax").unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn decode_rejects_empty_and_oversized_messages() {
+    let err = decode_message(b"\x00").unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+
+    // 64 KiB + 1, just over MAX_MESSAGE_LEN.
+    let err = decode_message(&[0x81, 0x80, 0x04]).unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn read_message_rejects_missing_newline() {
+    let (mut a, mut b) = duplex(64);
+    a.write_all(b"\x03nax").await.unwrap();
+    let err = read_message(&mut b).await.unwrap_err();
+    assert!(
+        matches!(err, NegotiationError::MalformedMessage(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn write_then_read_round_trips() {
+    let (mut a, mut b) = duplex(64);
+    write_message(&mut a, b"/chat/1.0.0").await.unwrap();
+    assert_eq!(read_message(&mut b).await.unwrap(), b"/chat/1.0.0");
+}
+
+#[tokio::test]
+async fn ls_response_is_a_message_of_messages() {
+    let (dialer, listener) = duplex(1024);
+    let server = tokio::spawn(async move {
+        let (mut r, mut w) = split(listener);
+        select_protocol(&mut StreamIo::new(&mut r, &mut w), false, &["/a", "/bb"]).await
+    });
+
+    let (mut r, mut w) = split(dialer);
+    w.write_all(HEADER).await.unwrap();
+    w.write_all(&encode_message(LS.as_bytes())).await.unwrap();
+
+    let mut header = vec![0u8; HEADER.len()];
+    r.read_exact(&mut header).await.unwrap();
+    assert_eq!(header, HEADER);
+
+    // Outer length 10 = two inner messages (4 + 5 bytes) + the outer '\n'.
+    let expected: &[u8] = b"\x0a\x03/a\n\x04/bb\n\n";
+    let mut response = vec![0u8; expected.len()];
+    r.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+
+    // The listener keeps waiting for a real proposal afterwards.
+    w.write_all(&encode_message(b"/bb")).await.unwrap();
+    let mut accepted = vec![0u8; 5];
+    r.read_exact(&mut accepted).await.unwrap();
+    assert_eq!(accepted, b"\x04/bb\n");
+    assert_eq!(server.await.unwrap().unwrap(), "/bb");
+}
+
+#[tokio::test]
+async fn list_protocols_decodes_the_ls_response() {
+    let (dialer, listener) = duplex(1024);
+    tokio::spawn(async move {
+        let (mut r, mut w) = split(listener);
+        let _ = select_protocol(&mut StreamIo::new(&mut r, &mut w), false, &["/a", "/bb"]).await;
+    });
+
+    let (mut r, mut w) = split(dialer);
+    let mut io = StreamIo::new(&mut r, &mut w);
+    io.send_message(MULTISTREAM_PROTOCOL.as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(
+        io.recv_message().await.unwrap(),
+        MULTISTREAM_PROTOCOL.as_bytes()
+    );
+    assert_eq!(list_protocols(&mut io).await.unwrap(), ["/a", "/bb"]);
+}
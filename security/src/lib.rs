@@ -1,210 +1,315 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use snow::{Builder, Keypair, TransportState};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+use common::{
+    identity::{self, PeerId, PublicKey},
+    noise::NoiseTransport,
+    protobuf::{self, FieldValue},
 };
+use negotiation::{NegotiationError, StreamIo, select_protocol};
+use snow::{Builder, HandshakeState, Keypair};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
-pub async fn negotiate_security_protocol(
-    reader: &mut BufReader<OwnedReadHalf>,
-    writer: &mut OwnedWriteHalf,
+/// Protocol id of libp2p Noise, negotiated via multistream-select.
+pub const NOISE_PROTOCOL: &str = "/noise";
+/// Noise protocol name mandated by the libp2p Noise spec.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// Prefix of the message signed by the identity key to bind it to the static key.
+const STATIC_KEY_DOMAIN: &[u8] = b"noise-libp2p-static-key:";
+/// How long the Noise handshake may take once the protocol has been agreed.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecurityError {
+    #[error("security negotiation failed: {0}")]
+    Negotiation(#[from] NegotiationError),
+    #[error("unsupported security protocol: {0}")]
+    UnsupportedProtocol(String),
+    #[error("malformed handshake message: {0}")]
+    MalformedMessage(String),
+    #[error("remote identity signature over the static key is invalid")]
+    InvalidSignature,
+    #[error("expected peer {expected} but the remote is {actual}")]
+    PeerIdMismatch { expected: PeerId, actual: PeerId },
+    #[error("noise handshake failed: {0}")]
+    HandshakeFailed(#[from] snow::Error),
+    #[error("i/o error: {0}")]
+    Io(#[from] tokio::io::Error),
+    #[error("security handshake timed out")]
+    Timeout,
+}
+
+/// Negotiate a security protocol and run its handshake. When `expected_peer`
+/// is set (typically by a dialer), a remote with any other `PeerId` is
+/// rejected with `SecurityError::PeerIdMismatch`.
+///
+/// The peer may send its handshake right behind its proposal, so `reader`
+/// can hold buffered bytes afterwards; keep reading through it, e.g. by
+/// putting it under the `EncryptedStream`, rather than its inner reader.
+pub async fn negotiate_security_protocol<R, W>(
+    reader: &mut BufReader<R>,
+    writer: &mut W,
     is_initiator: bool,
     supported_protocols: &HashMap<&'static str, Vec<&'static str>>,
-) -> TransportState {
-    if is_initiator {
-        println!("[negotiate_security_protocol] -> Sending /multistream/1.0.0");
-        let _ = writer.write_all(b"/multistream/1.0.0\n").await;
-    }
+    identity: &identity::Keypair,
+    expected_peer: Option<&PeerId>,
+) -> Result<(PeerId, NoiseTransport), SecurityError>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let Some(protocols) = supported_protocols.get("security") else {
+        eprintln!(
+            "[negotiate_security_protocol] No security protocols found in supported_protocols"
+        );
+        return Err(SecurityError::UnsupportedProtocol(
+            "no security protocols configured".to_string(),
+        ));
+    };
 
-    let mut line = String::new();
-    let _ = reader.read_line(&mut line).await;
-    println!(
-        "[negotiate_security_protocol] <- Received negotiation protocol: {}",
-        line.trim()
-    );
+    println!("[negotiate_security_protocol] Entering security negotiation");
+    let proto =
+        select_protocol(&mut StreamIo::new(reader, writer), is_initiator, protocols).await?;
 
-    if line.trim() == "/multistream/1.0.0" {
-        if !is_initiator {
-            println!("[negotiate_security_protocol] -> Sending /multistream/1.0.0");
-            let _ = writer.write_all(b"/multistream/1.0.0\n").await;
-        }
+    let secured = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        negotiate_security(reader, writer, is_initiator, &proto, identity),
+    )
+    .await
+    .map_err(|_| SecurityError::Timeout)??;
 
-        println!("[negotiate_security_protocol] Entering security negotiation");
-        if let Some(transport) =
-            negotiate_security(reader, writer, is_initiator, &supported_protocols)
-                .await
-                .unwrap()
-        {
-            println!("[negotiate_security_protocol] Security transport established");
-            return transport;
-        } else {
-            eprintln!("[negotiate_security_protocol] Unimplemented protocol");
-            std::process::exit(1);
-        }
-    } else {
+    if let Some(expected) = expected_peer
+        && *expected != secured.0
+    {
         eprintln!(
-            "[negotiate_security_protocol] Unsupported negotiation protocol: {}",
-            line.trim()
+            "[negotiate_security_protocol] Expected peer {expected}, remote is {}",
+            secured.0
         );
-        std::process::exit(1);
+        return Err(SecurityError::PeerIdMismatch {
+            expected: expected.clone(),
+            actual: secured.0,
+        });
     }
+
+    println!(
+        "[negotiate_security_protocol] Security transport established with {}",
+        secured.0
+    );
+    Ok(secured)
 }
 
-async fn negotiate_security(
-    reader: &mut BufReader<OwnedReadHalf>,
-    writer: &mut OwnedWriteHalf,
+async fn negotiate_security<R, W>(
+    reader: &mut BufReader<R>,
+    writer: &mut W,
     is_initiator: bool,
-    supported_protocols: &HashMap<&'static str, Vec<&'static str>>,
-) -> tokio::io::Result<Option<TransportState>> {
-    println!("[negotiate_security] Starting security negotiation, initiator={is_initiator}");
-    let private_key = generate_static_keypair().private;
-
-    if let Some(protocols) = supported_protocols.get("security") {
-        println!("[negotiate_security] Supported protocols: {:?}", protocols);
-
-        if is_initiator {
-            let mut input = String::new();
-            let mut stdin_reader = BufReader::new(tokio::io::stdin());
-
-            println!(
-                "[negotiate_security] Enter protocol to propose: {:?}",
-                protocols
-            );
-            stdin_reader.read_line(&mut input).await?;
-            let proto = input.trim();
-
-            println!("[negotiate_security] Proposing protocol: {proto}");
-            let _ = writer.write_all(format!("{proto}\n").as_bytes()).await;
-
-            let mut line = String::new();
-            let _ = reader.read_line(&mut line).await;
-            println!("[negotiate_security] Server responded: {}", line.trim());
-
-            if line.trim() == proto {
-                if proto == "/noise/xx" {
-                    println!(
-                        "[negotiate_security] Protocol {proto} accepted, running initiator handshake"
-                    );
-                    return Ok(Some(
-                        perform_noise_initiator_handshake(reader.get_mut(), writer, &private_key)
-                            .await
-                            .unwrap(),
-                    ));
-                } else {
-                    println!("[negotiate_security] Protocol {proto} not implemented yet");
-                    return Ok(None);
-                }
-            } else {
-                eprintln!("[negotiate_security] Server rejected protocol");
-                std::process::exit(1);
-            }
-        } else {
-            let mut line = String::new();
-            reader.read_line(&mut line).await?;
-            let proto = line.trim();
-            println!("[negotiate_security] Client proposed protocol: {proto}");
-
-            if protocols.contains(&proto) {
-                println!("[negotiate_security] Accepting protocol: {proto}");
-                let _ = writer.write_all(format!("{proto}\n").as_bytes()).await;
-                let transport =
-                    perform_noise_responder_handshake(reader.get_mut(), writer, &private_key).await;
-
-                println!("[negotiate_security] Completed responder handshake with {proto}");
-                return Ok(Some(transport.unwrap()));
-            } else {
-                eprintln!("[negotiate_security] Unsupported protocol {proto}, sending 'na'");
-                writer.write_all(b"na\n").await?;
-                return Ok(None);
-            }
-        }
-    } else {
-        panic!("[negotiate_security] No security protocols found in supported_protocols");
+    proto: &str,
+    identity: &identity::Keypair,
+) -> Result<(PeerId, NoiseTransport), SecurityError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    println!(
+        "[negotiate_security] Starting security upgrade with {proto}, initiator={is_initiator}"
+    );
+    if proto != NOISE_PROTOCOL {
+        println!("[negotiate_security] Protocol {proto} not implemented yet");
+        return Err(SecurityError::UnsupportedProtocol(proto.to_string()));
     }
+
+    let static_keypair = generate_static_keypair()?;
+
+    let secured = if is_initiator {
+        println!("[negotiate_security] Protocol {proto} accepted, running initiator handshake");
+        perform_noise_initiator_handshake(reader, writer, &static_keypair, identity).await?
+    } else {
+        println!("[negotiate_security] Accepted protocol {proto}, running responder handshake");
+        perform_noise_responder_handshake(reader, writer, &static_keypair, identity).await?
+    };
+    println!("[negotiate_security] Completed handshake with {proto}");
+    Ok(secured)
 }
 
-fn generate_static_keypair() -> Keypair {
+/// Generate the X25519 key used as the Noise static key. It is authenticated
+/// by the identity key through the handshake payload, so a fresh one can be
+/// used for every connection.
+pub fn generate_static_keypair() -> Result<Keypair, SecurityError> {
     println!("[generate_static_keypair] Generating static Noise keypair");
-    let builder: snow::Builder<'_> =
-        snow::Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap());
-    builder.generate_keypair().unwrap()
+    let builder: snow::Builder<'_> = snow::Builder::new(NOISE_PARAMS.parse()?);
+    Ok(builder.generate_keypair()?)
 }
 
-fn noise_builder(private_key: &[u8]) -> Builder<'_> {
+fn noise_builder(private_key: &[u8]) -> Result<Builder<'_>, SecurityError> {
     println!("[noise_builder] Building Noise XX state");
-    Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
-        .local_private_key(private_key)
-        .unwrap()
+    Ok(Builder::new(NOISE_PARAMS.parse()?).local_private_key(private_key)?)
+}
+
+/// Build the `NoiseHandshakePayload` protobuf: our identity key and its
+/// signature over `"noise-libp2p-static-key:" || static_public`.
+fn encode_handshake_payload(identity: &identity::Keypair, static_public: &[u8]) -> Vec<u8> {
+    let signature = identity.sign(&[STATIC_KEY_DOMAIN, static_public].concat());
+    let mut out = Vec::new();
+    protobuf::put_bytes_field(&mut out, 1, &identity.public().encode_protobuf());
+    protobuf::put_bytes_field(&mut out, 2, &signature);
+    out
+}
+
+/// Decode the remote `NoiseHandshakePayload` and check that its identity key
+/// signed the static key the remote used in this handshake.
+fn verify_handshake_payload(
+    payload: &[u8],
+    noise: &HandshakeState,
+) -> Result<PeerId, SecurityError> {
+    let malformed = |msg: String| SecurityError::MalformedMessage(msg);
+
+    let mut identity_key = None;
+    let mut identity_sig = None;
+    for field in protobuf::fields(payload) {
+        match field.map_err(|e| malformed(e.to_string()))? {
+            (1, FieldValue::Bytes(b)) => identity_key = Some(b),
+            (2, FieldValue::Bytes(b)) => identity_sig = Some(b),
+            _ => {}
+        }
+    }
+    let (Some(identity_key), Some(identity_sig)) = (identity_key, identity_sig) else {
+        return Err(malformed(
+            "payload is missing identity key or signature".to_string(),
+        ));
+    };
+
+    let remote_key =
+        PublicKey::try_decode_protobuf(identity_key).map_err(|e| malformed(e.to_string()))?;
+    let remote_static = noise
+        .get_remote_static()
+        .ok_or_else(|| malformed("remote static key not received".to_string()))?;
+
+    if !remote_key.verify(&[STATIC_KEY_DOMAIN, remote_static].concat(), identity_sig) {
+        return Err(SecurityError::InvalidSignature);
+    }
+    Ok(remote_key.to_peer_id())
+}
+
+/// Write one handshake message, prefixed with its length as a big-endian u16
+/// as required by the libp2p Noise spec.
+async fn write_handshake_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &[u8],
+) -> Result<(), SecurityError> {
+    let len = u16::try_from(msg.len()).map_err(|_| {
+        SecurityError::MalformedMessage(format!("message too large: {}", msg.len()))
+    })?;
+    let mut frame = Vec::with_capacity(2 + msg.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(msg);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one length-prefixed handshake message, however the bytes were split
+/// or coalesced on the wire.
+async fn read_handshake_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, SecurityError> {
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    if len == 0 {
+        return Err(SecurityError::MalformedMessage(
+            "empty handshake message".to_string(),
+        ));
+    }
+
+    let mut msg = vec![0u8; len];
+    reader.read_exact(&mut msg).await?;
+    Ok(msg)
 }
 
-pub async fn perform_noise_initiator_handshake(
-    reader: &mut OwnedReadHalf,
-    writer: &mut OwnedWriteHalf,
-    local_private: &[u8],
-) -> tokio::io::Result<TransportState> {
+/// Run the initiator side of the XX handshake. The responder's identity is
+/// learned from message 2 and ours is sent in message 3; returns the verified
+/// remote `PeerId` along with the per-direction transport ciphers.
+pub async fn perform_noise_initiator_handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    static_keypair: &Keypair,
+    identity: &identity::Keypair,
+) -> Result<(PeerId, NoiseTransport), SecurityError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
     println!("[initiator_handshake] Entered initiator Noise handshake");
-    let builder = noise_builder(local_private);
-    let mut noise = builder.build_initiator().unwrap();
+    let builder = noise_builder(&static_keypair.private)?;
+    let mut noise = builder.build_initiator()?;
 
     let mut buf = [0u8; 65535];
 
-    let len = noise.write_message(&[], &mut buf).unwrap_or(0);
+    let len = noise.write_message(&[], &mut buf)?;
     println!(
         "[initiator_handshake] -> Sending first handshake message ({} bytes)",
         len
     );
-    writer.write_all(&buf[..len]).await?;
+    write_handshake_message(writer, &buf[..len]).await?;
 
-    let mut msg = vec![0u8; 1024];
-    let n = reader.read(&mut msg).await.unwrap();
-    println!("[initiator_handshake] <- Received {} bytes", n);
-    noise.read_message(&msg[..n], &mut buf).unwrap();
+    let msg = read_handshake_message(reader).await?;
+    println!("[initiator_handshake] <- Received {} bytes", msg.len());
+    let payload_len = noise.read_message(&msg, &mut buf)?;
+    let remote_peer = verify_handshake_payload(&buf[..payload_len], &noise)?;
+    println!("[initiator_handshake] Verified remote peer {remote_peer}");
 
-    let len = noise.write_message(&[], &mut buf).unwrap();
+    let payload = encode_handshake_payload(identity, &static_keypair.public);
+    let len = noise.write_message(&payload, &mut buf)?;
     println!(
         "[initiator_handshake] -> Sending final handshake message ({} bytes)",
         len
     );
-    writer.write_all(&buf[..len]).await?;
+    write_handshake_message(writer, &buf[..len]).await?;
 
     println!("[initiator_handshake] Handshake complete, entering transport mode");
-    Ok(noise.into_transport_mode().unwrap())
+    Ok((remote_peer, NoiseTransport::from_handshake(noise)?))
 }
 
-pub async fn perform_noise_responder_handshake(
-    reader: &mut OwnedReadHalf,
-    writer: &mut OwnedWriteHalf,
-    local_private: &[u8],
-) -> tokio::io::Result<TransportState> {
+/// Run the responder side of the XX handshake. Our identity is sent in
+/// message 2 and the initiator's is verified from message 3.
+pub async fn perform_noise_responder_handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    static_keypair: &Keypair,
+    identity: &identity::Keypair,
+) -> Result<(PeerId, NoiseTransport), SecurityError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
     println!("[responder_handshake] Entered responder Noise handshake");
-    let builder = noise_builder(local_private);
-    let mut noise = builder.build_responder().unwrap();
+    let builder = noise_builder(&static_keypair.private)?;
+    let mut noise = builder.build_responder()?;
 
     let mut buf = [0u8; 65535];
-    let mut msg = vec![0u8; 1024];
 
-    let n = reader.read(&mut msg).await.unwrap();
+    let msg = read_handshake_message(reader).await?;
     println!(
         "[responder_handshake] <- Received first message ({} bytes)",
-        n
+        msg.len()
     );
-    noise.read_message(&msg[..n], &mut buf).unwrap();
+    noise.read_message(&msg, &mut buf)?;
 
-    let len = noise.write_message(&[], &mut buf).unwrap();
+    let payload = encode_handshake_payload(identity, &static_keypair.public);
+    let len = noise.write_message(&payload, &mut buf)?;
     println!(
         "[responder_handshake] -> Sending response message ({} bytes)",
         len
     );
-    writer.write_all(&buf[..len]).await?;
+    write_handshake_message(writer, &buf[..len]).await?;
 
-    let n = reader.read(&mut msg).await.unwrap();
+    let msg = read_handshake_message(reader).await?;
     println!(
         "[responder_handshake] <- Received final message ({} bytes)",
-        n
+        msg.len()
     );
-    noise.read_message(&msg[..n], &mut buf).unwrap();
+    let payload_len = noise.read_message(&msg, &mut buf)?;
+    let remote_peer = verify_handshake_payload(&buf[..payload_len], &noise)?;
+    println!("[responder_handshake] Verified remote peer {remote_peer}");
 
     println!("[responder_handshake] Handshake complete, entering transport mode");
-    Ok(noise.into_transport_mode().unwrap())
+    Ok((remote_peer, NoiseTransport::from_handshake(noise)?))
 }
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use common::{EncryptedStream, identity::Keypair, protobuf};
+use negotiation::{MULTISTREAM_PROTOCOL, encode_message, read_message};
+use security::{generate_static_keypair, negotiate_security_protocol};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, duplex, split};
+
+fn frame(msg: &[u8]) -> Vec<u8> {
+    [&(msg.len() as u16).to_be_bytes()[..], msg].concat()
+}
+
+/// A dialer that packs its multistream proposal together with Noise message 1,
+/// and message 3 together with its first transport frame, the way a fast
+/// peer's writes can land in a single TCP segment.
+#[tokio::test]
+async fn listener_keeps_bytes_that_arrive_with_the_proposal() {
+    let (dialer, listener) = duplex(64 * 1024);
+    let listener_id = Keypair::generate_ed25519();
+    let expected_remote = Keypair::generate_ed25519();
+
+    let remote_id = expected_remote.clone();
+    let server = tokio::spawn(async move {
+        let (reader, mut writer) = split(listener);
+        let mut reader = BufReader::new(reader);
+        let (peer, noise) = negotiate_security_protocol(
+            &mut reader,
+            &mut writer,
+            false,
+            &HashMap::from([("security", vec!["/noise"])]),
+            &listener_id,
+            Some(&remote_id.peer_id()),
+        )
+        .await
+        .unwrap();
+        let stream = EncryptedStream::new(noise, reader, writer);
+        (peer, stream.recv().await.unwrap())
+    });
+
+    let (mut r, mut w) = split(dialer);
+    let static_key = generate_static_keypair().unwrap();
+    let mut noise = snow::Builder::new("Noise_XX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .local_private_key(&static_key.private)
+        .unwrap()
+        .build_initiator()
+        .unwrap();
+    let mut buf = vec![0u8; 65535];
+
+    let len = noise.write_message(&[], &mut buf).unwrap();
+    let mut burst = encode_message(MULTISTREAM_PROTOCOL.as_bytes());
+    burst.extend(encode_message(b"/noise"));
+    burst.extend(frame(&buf[..len]));
+    w.write_all(&burst).await.unwrap();
+
+    assert_eq!(
+        read_message(&mut r).await.unwrap(),
+        MULTISTREAM_PROTOCOL.as_bytes()
+    );
+    assert_eq!(read_message(&mut r).await.unwrap(), b"/noise");
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len).await.unwrap();
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut msg).await.unwrap();
+    noise.read_message(&msg, &mut buf).unwrap();
+
+    let signed = [&b"noise-libp2p-static-key:"[..], &static_key.public].concat();
+    let mut payload = Vec::new();
+    protobuf::put_bytes_field(&mut payload, 1, &expected_remote.public().encode_protobuf());
+    protobuf::put_bytes_field(&mut payload, 2, &expected_remote.sign(&signed));
+    let len = noise.write_message(&payload, &mut buf).unwrap();
+    let mut burst = frame(&buf[..len]);
+
+    let mut noise = noise.into_transport_mode().unwrap();
+    let len = noise.write_message(b"hello", &mut buf).unwrap();
+    burst.extend(frame(&buf[..len]));
+    w.write_all(&burst).await.unwrap();
+
+    let (peer, first) = server.await.unwrap();
+    assert_eq!(peer, expected_remote.peer_id());
+    assert_eq!(first, b"hello");
+}
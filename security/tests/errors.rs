@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use common::{
+    identity::{Keypair, PeerId},
+    noise::NoiseTransport,
+};
+use negotiation::{NegotiationError, StreamIo, select_protocol};
+use security::{
+    HANDSHAKE_TIMEOUT, SecurityError, generate_static_keypair, negotiate_security_protocol,
+    perform_noise_initiator_handshake,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, duplex, split},
+    time::Instant,
+};
+
+fn protocols(security: &[&'static str]) -> HashMap<&'static str, Vec<&'static str>> {
+    HashMap::from([("security", security.to_vec())])
+}
+
+async fn secure(
+    stream: DuplexStream,
+    is_initiator: bool,
+    supported: &HashMap<&'static str, Vec<&'static str>>,
+) -> Result<(PeerId, NoiseTransport), SecurityError> {
+    let (reader, mut writer) = split(stream);
+    let mut reader = BufReader::new(reader);
+    negotiate_security_protocol(
+        &mut reader,
+        &mut writer,
+        is_initiator,
+        supported,
+        &Keypair::generate_ed25519(),
+        None,
+    )
+    .await
+}
+
+/// Agree on `proto` as the listener, then hand the raw pipe back.
+async fn agree(stream: DuplexStream, proto: &'static str) -> DuplexStream {
+    let (mut r, mut w) = split(stream);
+    select_protocol(&mut StreamIo::new(&mut r, &mut w), false, &[proto])
+        .await
+        .unwrap();
+    r.unsplit(w)
+}
+
+#[tokio::test]
+async fn missing_security_section_is_unsupported() {
+    let (a, _b) = duplex(1024);
+    let err = secure(a, true, &HashMap::new()).await.unwrap_err();
+    assert!(
+        matches!(err, SecurityError::UnsupportedProtocol(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn agreed_protocol_without_an_implementation_is_unsupported() {
+    let (a, b) = duplex(1024);
+    let listener = tokio::spawn(agree(b, "/tls/1.0.0"));
+    let err = secure(a, true, &protocols(&["/tls/1.0.0"]))
+        .await
+        .unwrap_err();
+    match err {
+        SecurityError::UnsupportedProtocol(proto) => assert_eq!(proto, "/tls/1.0.0"),
+        other => panic!("expected UnsupportedProtocol, got {other:?}"),
+    }
+    listener.await.unwrap();
+}
+
+#[tokio::test]
+async fn garbage_during_negotiation_is_a_typed_error() {
+    let (a, mut b) = duplex(1024);
+    b.write_all(b"\x04junk").await.unwrap();
+    let err = secure(a, false, &protocols(&["/noise"])).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            SecurityError::Negotiation(NegotiationError::MalformedMessage(_))
+        ),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn empty_handshake_message_is_malformed() {
+    let (a, b) = duplex(64 * 1024);
+    let listener = tokio::spawn(async move {
+        let mut peer = agree(b, "/noise").await;
+        let mut first = [0u8; 34];
+        peer.read_exact(&mut first).await.unwrap();
+        peer.write_all(&[0x00, 0x00]).await.unwrap();
+        peer
+    });
+    let err = secure(a, true, &protocols(&["/noise"])).await.unwrap_err();
+    assert!(matches!(err, SecurityError::MalformedMessage(_)), "{err:?}");
+    drop(listener.await.unwrap());
+}
+
+#[tokio::test]
+async fn handshake_payload_without_identity_is_malformed() {
+    let (peer, ours) = duplex(64 * 1024);
+    let (mut r, mut w) = split(ours);
+    let key = generate_static_keypair().unwrap();
+    let identity = Keypair::generate_ed25519();
+    let initiator = tokio::spawn(async move {
+        perform_noise_initiator_handshake(&mut r, &mut w, &key, &identity).await
+    });
+
+    // A responder that completes message 2 with an empty payload.
+    let (mut pr, mut pw) = split(peer);
+    let responder_static = generate_static_keypair().unwrap();
+    let mut noise = snow::Builder::new("Noise_XX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .local_private_key(&responder_static.private)
+        .unwrap()
+        .build_responder()
+        .unwrap();
+    let mut buf = vec![0u8; 65535];
+    let mut frame = [0u8; 34];
+    pr.read_exact(&mut frame).await.unwrap();
+    noise.read_message(&frame[2..], &mut buf).unwrap();
+    let len = noise.write_message(&[], &mut buf).unwrap();
+    pw.write_all(&(len as u16).to_be_bytes()).await.unwrap();
+    pw.write_all(&buf[..len]).await.unwrap();
+
+    let err = initiator.await.unwrap().unwrap_err();
+    assert!(matches!(err, SecurityError::MalformedMessage(_)), "{err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn stalled_handshake_times_out() {
+    let (a, b) = duplex(64 * 1024);
+    // The peer agrees on Noise but never answers the first handshake message.
+    let listener = tokio::spawn(agree(b, "/noise"));
+    let started = Instant::now();
+    let err = secure(a, true, &protocols(&["/noise"])).await.unwrap_err();
+    assert!(matches!(err, SecurityError::Timeout), "{err:?}");
+    assert!(started.elapsed() >= HANDSHAKE_TIMEOUT);
+    drop(listener.await.unwrap());
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use common::{
+    identity::{Keypair, PeerId},
+    noise::NoiseTransport,
+};
+use security::{SecurityError, negotiate_security_protocol};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+};
+
+fn supported_protocols() -> HashMap<&'static str, Vec<&'static str>> {
+    HashMap::from([("security", vec!["/noise"])])
+}
+
+/// Dial a freshly spawned listener whose identity is `server_id`, expecting
+/// the remote to be `expected`.
+async fn dial(
+    server_id: Keypair,
+    expected: Option<&PeerId>,
+) -> Result<(PeerId, NoiseTransport), SecurityError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        // The outcome on the listener side depends on when the dialer hangs up.
+        let _ = negotiate_security_protocol(
+            &mut reader,
+            &mut writer,
+            false,
+            &supported_protocols(),
+            &server_id,
+            None,
+        )
+        .await;
+    });
+
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+    negotiate_security_protocol(
+        &mut reader,
+        &mut writer,
+        true,
+        &supported_protocols(),
+        &Keypair::generate_ed25519(),
+        expected,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn dial_succeeds_when_remote_is_the_expected_peer() {
+    let server_id = Keypair::generate_ed25519();
+    let expected = server_id.peer_id();
+
+    let (remote, _) = dial(server_id, Some(&expected)).await.unwrap();
+    assert_eq!(remote, expected);
+}
+
+#[tokio::test]
+async fn dial_fails_when_remote_is_a_different_peer() {
+    let server_id = Keypair::generate_ed25519();
+    let actual_id = server_id.peer_id();
+    let expected = Keypair::generate_ed25519().peer_id();
+
+    match dial(server_id, Some(&expected)).await {
+        Err(SecurityError::PeerIdMismatch {
+            expected: e,
+            actual: a,
+        }) => {
+            assert_eq!(e, expected);
+            assert_eq!(a, actual_id);
+        }
+        other => panic!("expected PeerIdMismatch, got {:?}", other.map(|(p, _)| p)),
+    }
+}
+
+#[tokio::test]
+async fn dial_without_expectation_accepts_any_peer() {
+    let server_id = Keypair::generate_secp256k1();
+    let actual_id = server_id.peer_id();
+
+    let (remote, _) = dial(server_id, None).await.unwrap();
+    assert_eq!(remote, actual_id);
+}
@@ -0,0 +1,267 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use common::{
+    identity::{Keypair, PeerId},
+    noise::NoiseTransport,
+    protobuf,
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use security::{
+    SecurityError, generate_static_keypair, perform_noise_initiator_handshake,
+    perform_noise_responder_handshake,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+type Secured = Result<(PeerId, NoiseTransport), SecurityError>;
+
+/// Wraps one end of a duplex pipe and re-chunks traffic at random: writes are
+/// held back and forwarded in arbitrary pieces (merging several writes or
+/// splitting one), and reads hand out arbitrarily small slices.
+struct ChoppyIo {
+    inner: DuplexStream,
+    rng: StdRng,
+    pending: Vec<u8>,
+}
+
+impl ChoppyIo {
+    fn new(inner: DuplexStream, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: StdRng::seed_from_u64(seed),
+            pending: Vec::new(),
+        }
+    }
+
+    fn poll_forward(&mut self, cx: &mut Context<'_>, all: bool) -> Poll<std::io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = if all {
+                self.pending.len()
+            } else {
+                self.rng.random_range(1..=self.pending.len())
+            };
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.pending[..n]) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending.drain(..written);
+            if !all {
+                break;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for ChoppyIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let limit = this.rng.random_range(1..=buf.remaining().max(1));
+        let mut small = vec![0u8; limit];
+        let mut small_buf = ReadBuf::new(&mut small);
+        match Pin::new(&mut this.inner).poll_read(cx, &mut small_buf) {
+            Poll::Ready(Ok(())) => {
+                buf.put_slice(small_buf.filled());
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl AsyncWrite for ChoppyIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = this.rng.random_range(1..=data.len().max(1)).min(data.len());
+        this.pending.extend_from_slice(&data[..n]);
+        // Sometimes forward part of what we hold, sometimes keep merging.
+        if this.rng.random_bool(0.5) {
+            let _ = this.poll_forward(cx, false)?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_forward(cx, true) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_forward(cx, true) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+async fn handshake<S>(
+    initiator_io: S,
+    initiator_id: &Keypair,
+    responder_io: S,
+    responder_id: &Keypair,
+) -> (Secured, Secured)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut ir, mut iw) = tokio::io::split(initiator_io);
+    let (mut rr, mut rw) = tokio::io::split(responder_io);
+    let initiator_key = generate_static_keypair().unwrap();
+    let responder_key = generate_static_keypair().unwrap();
+
+    let responder_id = responder_id.clone();
+    let responder = tokio::spawn(async move {
+        perform_noise_responder_handshake(&mut rr, &mut rw, &responder_key, &responder_id).await
+    });
+    let initiator =
+        perform_noise_initiator_handshake(&mut ir, &mut iw, &initiator_key, initiator_id).await;
+    (initiator, responder.await.unwrap())
+}
+
+fn assert_transports_talk(mut a: NoiseTransport, mut b: NoiseTransport) {
+    let mut ct = [0u8; 1024];
+    let mut pt = [0u8; 1024];
+
+    let n = a.send.encrypt(b"ping", &mut ct).unwrap();
+    let m = b.recv.decrypt(&ct[..n], &mut pt).unwrap();
+    assert_eq!(&pt[..m], b"ping");
+
+    let n = b.send.encrypt(b"pong", &mut ct).unwrap();
+    let m = a.recv.decrypt(&ct[..n], &mut pt).unwrap();
+    assert_eq!(&pt[..m], b"pong");
+}
+
+#[tokio::test]
+async fn handshake_over_plain_pipe_authenticates_both_peers() {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_secp256k1());
+    let (initiator, responder) = handshake(a, &alice, b, &bob).await;
+
+    let (seen_by_alice, alice_transport) = initiator.unwrap();
+    let (seen_by_bob, bob_transport) = responder.unwrap();
+    assert_eq!(seen_by_alice, bob.peer_id());
+    assert_eq!(seen_by_bob, alice.peer_id());
+    assert_transports_talk(alice_transport, bob_transport);
+}
+
+#[tokio::test]
+async fn handshake_survives_random_fragmentation_and_coalescing() {
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    for seed in 0..64 {
+        // A tiny pipe capacity adds backpressure on top of the re-chunking.
+        let (a, b) = tokio::io::duplex(7);
+        let a = ChoppyIo::new(a, seed);
+        let b = ChoppyIo::new(b, seed.wrapping_mul(31).wrapping_add(7));
+        let (initiator, responder) = handshake(a, &alice, b, &bob).await;
+        let (_, initiator) =
+            initiator.unwrap_or_else(|e| panic!("seed {seed}: initiator failed: {e}"));
+        let (_, responder) =
+            responder.unwrap_or_else(|e| panic!("seed {seed}: responder failed: {e}"));
+        assert_transports_talk(initiator, responder);
+    }
+}
+
+#[tokio::test]
+async fn handshake_messages_are_length_prefixed() {
+    let (mut peer, ours) = tokio::io::duplex(64 * 1024);
+    let (mut r, mut w) = tokio::io::split(ours);
+    let key = generate_static_keypair().unwrap();
+    let identity = Keypair::generate_ed25519();
+
+    let initiator = tokio::spawn(async move {
+        perform_noise_initiator_handshake(&mut r, &mut w, &key, &identity).await
+    });
+
+    // The first XX message is a bare 32-byte ephemeral key behind a u16 BE length.
+    let mut first = [0u8; 34];
+    peer.read_exact(&mut first).await.unwrap();
+    assert_eq!(&first[..2], &[0x00, 0x20]);
+
+    drop(peer);
+    assert!(matches!(
+        initiator.await.unwrap(),
+        Err(SecurityError::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn truncated_handshake_message_is_an_error() {
+    let (mut peer, ours) = tokio::io::duplex(64 * 1024);
+    let (mut r, mut w) = tokio::io::split(ours);
+    let key = generate_static_keypair().unwrap();
+    let identity = Keypair::generate_ed25519();
+
+    // Announce 100 bytes but deliver only 3 before hanging up.
+    peer.write_all(&[0x00, 0x64, 1, 2, 3]).await.unwrap();
+    drop(peer);
+
+    let result = perform_noise_responder_handshake(&mut r, &mut w, &key, &identity).await;
+    assert!(matches!(result, Err(SecurityError::Io(_))));
+}
+
+async fn write_frame(io: &mut DuplexStream, msg: &[u8]) {
+    io.write_all(&(msg.len() as u16).to_be_bytes())
+        .await
+        .unwrap();
+    io.write_all(msg).await.unwrap();
+}
+
+async fn read_frame(io: &mut DuplexStream) -> Vec<u8> {
+    let mut len = [0u8; 2];
+    io.read_exact(&mut len).await.unwrap();
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    io.read_exact(&mut msg).await.unwrap();
+    msg
+}
+
+#[tokio::test]
+async fn rejects_identity_signature_over_a_different_static_key() {
+    let (mut peer, ours) = tokio::io::duplex(64 * 1024);
+    let (mut r, mut w) = tokio::io::split(ours);
+    let key = generate_static_keypair().unwrap();
+    let identity = Keypair::generate_ed25519();
+    let initiator = tokio::spawn(async move {
+        perform_noise_initiator_handshake(&mut r, &mut w, &key, &identity).await
+    });
+
+    // A responder that signs some other static key than the one it uses.
+    let params = "Noise_XX_25519_ChaChaPoly_SHA256";
+    let responder_static = generate_static_keypair().unwrap();
+    let other_static = generate_static_keypair().unwrap();
+    let mut noise = snow::Builder::new(params.parse().unwrap())
+        .local_private_key(&responder_static.private)
+        .unwrap()
+        .build_responder()
+        .unwrap();
+
+    let remote_identity = Keypair::generate_ed25519();
+    let signed = [&b"noise-libp2p-static-key:"[..], &other_static.public].concat();
+    let mut payload = Vec::new();
+    protobuf::put_bytes_field(&mut payload, 1, &remote_identity.public().encode_protobuf());
+    protobuf::put_bytes_field(&mut payload, 2, &remote_identity.sign(&signed));
+
+    let mut buf = vec![0u8; 65535];
+    noise
+        .read_message(&read_frame(&mut peer).await, &mut buf)
+        .unwrap();
+    let len = noise.write_message(&payload, &mut buf).unwrap();
+    write_frame(&mut peer, &buf[..len]).await;
+
+    assert!(matches!(
+        initiator.await.unwrap(),
+        Err(SecurityError::InvalidSignature)
+    ));
+}
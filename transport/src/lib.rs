@@ -0,0 +1,69 @@
+//! Transports carry the raw byte streams a connection is built on. Dialing
+//! or accepting yields such a stream, which `upgrade` then secures with Noise
+//! and multiplexes, the same way whatever transport produced it.
+
+use std::{future::Future, io};
+
+use common::multiaddr::Multiaddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+mod memory;
+mod tcp;
+mod unix;
+mod upgrade;
+mod ws;
+
+pub use memory::{LinkConfig, MemoryListener, MemoryTransport};
+pub use tcp::{TcpListener, TcpTransport};
+pub use unix::{UnixListener, UnixTransport};
+pub use upgrade::{Muxed, UpgradeError, supported_protocols, upgrade};
+pub use ws::{WS_HANDSHAKE_TIMEOUT, WsListener, WsStream, WsTransport};
+
+#[derive(thiserror::Error, Debug)]
+pub enum TransportError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("address not supported by this transport: {0}")]
+    UnsupportedAddress(Multiaddr),
+}
+
+/// A way of reaching peers and being reached by them.
+pub trait Transport: Send + Sync + 'static {
+    /// The raw connection, before any upgrade.
+    type Output: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+    type Listener: Listener<Output = Self::Output>;
+
+    /// Open a connection to `addr`. A trailing `/p2p/<id>` is left for the
+    /// security upgrade to check.
+    fn dial(
+        &self,
+        addr: &Multiaddr,
+    ) -> impl Future<Output = Result<Self::Output, TransportError>> + Send;
+
+    /// Start accepting connections on `addr`.
+    fn listen_on(
+        &self,
+        addr: &Multiaddr,
+    ) -> impl Future<Output = Result<Self::Listener, TransportError>> + Send;
+}
+
+/// The accepting end returned by `Transport::listen_on`.
+pub trait Listener: Send + 'static {
+    type Output;
+
+    /// The address actually bound, e.g. with the port a `/tcp/0` request got.
+    fn local_addr(&self) -> Multiaddr;
+
+    /// Wait for the next inbound connection.
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = Result<Inbound<Self::Output>, TransportError>> + Send;
+}
+
+/// A connection accepted by a `Listener`.
+#[derive(Debug)]
+pub struct Inbound<S> {
+    pub stream: S,
+    pub local_addr: Multiaddr,
+    pub remote_addr: Multiaddr,
+}
@@ -1,235 +1,247 @@
-use common::EncryptedStream;
-use muxer::Muxer;
-use negotiation::negotiate_protocol;
-use security::negotiate_security_protocol;
-use std::{collections::HashSet, env, net::SocketAddr, sync::Arc};
+use common::{
+    identity::{Keypair, PeerId},
+    multiaddr::{Multiaddr, Protocol},
+};
+use muxer::{StreamId, StreamMuxer, Substream};
+use negotiation::{Negotiated, Version};
+use std::{collections::HashMap, env, net::Ipv4Addr, path::Path, sync::Arc};
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    sync::mpsc,
+};
+use transport::{
+    Inbound, Listener, Muxed, TcpTransport, Transport, UnixTransport, WsTransport,
+    supported_protocols, upgrade,
 };
 
-use std::collections::HashMap;
-
-const SERVER_ADDR: &str = "127.0.0.1:8080";
+/// `/ip4/127.0.0.1/tcp/8080`, used when no address is given.
+fn default_addr() -> Multiaddr {
+    Multiaddr::empty()
+        .with(Protocol::Ip4(Ipv4Addr::LOCALHOST))
+        .with(Protocol::Tcp(8080))
+}
 
 #[tokio::main]
 async fn main() {
     let mut args: Vec<String> = env::args().collect();
     println!("[main] Args: {:?}", args);
 
-    if args.len() < 3 {
-        if args.len() == 2 {
-            args.push(SERVER_ADDR.to_string());
-        } else {
-            eprintln!("Usage: {} [server|client] <addr>", args[0]);
-            std::process::exit(1);
+    let expected_peer = match take_flag(&mut args, "--peer") {
+        Some(peer) => match peer.parse::<PeerId>() {
+            Ok(peer) => Some(peer),
+            Err(e) => {
+                eprintln!("Invalid --peer {peer}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let identity = match take_flag(&mut args, "--key") {
+        Some(path) => match Keypair::load_or_generate(Path::new(&path)) {
+            Ok(identity) => {
+                println!("[main] Using node key from {path}");
+                identity
+            }
+            Err(e) => {
+                eprintln!("Unable to load node key {path}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => Keypair::generate_ed25519(),
+    };
+    println!("[main] Local peer id: {}", identity.peer_id());
+
+    // permission bits, in octal, for the socket file of a `/unix` listener
+    let unix = match take_flag(&mut args, "--socket-mode") {
+        Some(mode) => match u32::from_str_radix(&mode, 8) {
+            Ok(mode) => UnixTransport::with_permissions(mode),
+            Err(e) => {
+                eprintln!("Invalid --socket-mode {mode}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => UnixTransport::new(),
+    };
+
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: {} [server|client] <multiaddr> [--key <path>] [--peer <peer-id>] [--socket-mode <octal>]",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+    let addr = match args.get(2) {
+        Some(addr) => match addr.parse::<Multiaddr>() {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("Invalid address {addr}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => default_addr(),
+    };
+    // a dialed `/p2p/<id>` names the peer as well as `--peer` does
+    let expected_peer = expected_peer.or_else(|| addr.peer_id().cloned());
+
+    // the transport follows from the address
+    let is_ws = addr.iter().any(|protocol| matches!(protocol, Protocol::Ws));
+    let mode = args[1].to_lowercase();
+    match addr.iter().next() {
+        Some(Protocol::Unix(_)) => run(unix, &mode, &addr, identity, expected_peer).await,
+        _ if is_ws => {
+            let transport = WsTransport::new(TcpTransport);
+            run(transport, &mode, &addr, identity, expected_peer).await
         }
+        _ => run(TcpTransport, &mode, &addr, identity, expected_peer).await,
     }
+}
 
-    match args[1].to_lowercase().as_str() {
-        "server" => run_server(&args[2]).await,
-        "client" => run_client(&args[2]).await,
+async fn run<T: Transport>(
+    transport: T,
+    mode: &str,
+    addr: &Multiaddr,
+    identity: Keypair,
+    expected_peer: Option<PeerId>,
+) {
+    match mode {
+        "server" => run_server(transport, addr, identity).await,
+        "client" => run_client(transport, addr, identity, expected_peer).await,
         _ => eprintln!("Invalid argument, expected: server|client"),
     }
 }
 
-async fn run_server(addr: &str) {
-    let stream = TcpListener::bind(addr)
+/// Remove `--name <value>` from `args`, returning the value if present.
+fn take_flag(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|a| a == name)?;
+    if pos + 1 >= args.len() {
+        eprintln!("Missing value for {name}");
+        std::process::exit(1);
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+async fn run_server<T: Transport>(transport: T, addr: &Multiaddr, identity: Keypair) {
+    let mut listener = transport
+        .listen_on(addr)
         .await
         .expect("Unable to bind to the address");
 
-    println!("[server] Listening on {addr}");
+    // a `/unix` path runs to the end of the text, so no `/p2p` after it
+    let mut local_addr = listener.local_addr();
+    if !matches!(local_addr.iter().last(), Some(Protocol::Unix(_))) {
+        local_addr.push(Protocol::P2p(identity.peer_id()));
+    }
+    println!("[server] Listening on {local_addr}");
 
     loop {
-        let (socket, addr) = stream.accept().await.expect("accept failed");
-        println!("[server] Accepted connection from {addr}");
-        tokio::spawn(async move { handle_connection(socket, addr).await });
+        let inbound = match listener.accept().await {
+            Ok(inbound) => inbound,
+            Err(e) => {
+                eprintln!("[server] accept failed: {e}");
+                continue;
+            }
+        };
+        println!("[server] Accepted connection from {}", inbound.remote_addr);
+        let identity = identity.clone();
+        tokio::spawn(async move { handle_connection(inbound, identity).await });
     }
 }
 
-async fn run_client(addr: &str) {
-    let stream = TcpStream::connect(addr)
+async fn run_client<T: Transport>(
+    transport: T,
+    addr: &Multiaddr,
+    identity: Keypair,
+    expected_peer: Option<PeerId>,
+) {
+    let stream = transport
+        .dial(addr)
         .await
         .expect("Unable to connect to the address");
 
     println!("[client] Connected to {addr}");
 
-    let (reader, mut writer) = stream.into_split();
-    let mut socket_reader = BufReader::new(reader);
-
-    println!("[client] Starting security negotiation...");
-    let transport = negotiate_security_protocol(
-        &mut socket_reader,
-        &mut writer,
-        true,
-        &supported_protocols(),
-    )
-    .await;
-    println!("[client] Security negotiation complete");
-
-    let reader = socket_reader.into_inner();
-
-    let mut stream = EncryptedStream {
-        noise: Mutex::new(transport),
-        reader: Mutex::new(reader),
-        writer: Mutex::new(writer),
+    let (remote_peer, muxed) = match upgrade(stream, true, &identity, expected_peer.as_ref()).await
+    {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            eprintln!("[client] Upgrade failed: {e}");
+            return;
+        }
     };
+    println!("[client] Connection to {remote_peer} ready");
 
-    println!("[client] Starting multiplexing protocol negotiation...");
-    let mux_protocol = negotiate_protocol(
-        &mut stream,
-        true,
-        &supported_protocols().get("multiplexing").unwrap(),
-    )
-    .await
-    .unwrap();
-    println!("[client] Protocol negotiation complete");
-
-    println!("[client] Starting protocol negotiation with {addr}");
-    match mux_protocol.as_str() {
-        "/mplex" => {
-            // match negotiate_protocol(
-            //     &mut stream,
-            //     true,
-            //     &supported_protocols().get("protocol").unwrap(),
-            // )
-            // .await
-            // {
-            //     Ok(protocol) => {
-            //         println!(
-            //             "[client] Protocol negotiation complete with {addr} and protocol: {protocol}"
-            //         );
-            // assume `arc_enc` is Arc<EncryptedStream> you constructed earlier:
-            let arc_enc = Arc::new(stream);
-            let mux = Muxer::new(arc_enc.clone(), true); // initiator = true
-            mux.start_reader();
-
-            // call the interactive loop:
-            interactive_client_loop(mux).await;
-            //         }
-            //         Err(_) => {
-            //             std::process::exit(1);
-            //         }
-            //     }
-        }
-        "/yamux" => {
-            println!("[client] Unimplemented protocl");
-            std::process::exit(1);
-        }
-        _ => {
-            eprintln!("[client] no matching protocol found");
-            std::process::exit(1);
-        }
+    // application protocols are negotiated per stream, see `/open`
+    let result = match muxed {
+        Muxed::Mplex(mux) => interactive_client_loop(mux).await,
+        Muxed::Yamux(mux) => interactive_client_loop(mux).await,
+    };
+    if let Err(e) = result {
+        eprintln!("[client] interactive loop error: {e}");
     }
 }
 
-async fn handle_connection(socket: TcpStream, addr: SocketAddr) {
+async fn handle_connection<S>(inbound: Inbound<S>, identity: Keypair)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let addr = inbound.remote_addr;
     println!("[server] Handling connection from {addr}");
-    let (reader, mut writer) = socket.into_split();
-    let mut stream_reader = BufReader::new(reader);
-
-    println!("[server] Starting security negotiation with {addr}");
-    let transport = negotiate_security_protocol(
-        &mut stream_reader,
-        &mut writer,
-        false,
-        &supported_protocols(),
-    )
-    .await;
-    println!("[server] Security negotiation complete with {addr}");
-
-    let reader = stream_reader.into_inner();
-
-    let mut stream = EncryptedStream {
-        noise: Mutex::new(transport),
-        reader: Mutex::new(reader),
-        writer: Mutex::new(writer),
+
+    let (remote_peer, muxed) = match upgrade(inbound.stream, false, &identity, None).await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            eprintln!("[server] Upgrade of {addr} failed: {e}");
+            return;
+        }
     };
+    println!("[server] Connection from {addr} ready, remote peer {remote_peer}");
 
-    println!("[server] Starting multiplexing protocol negotiation...");
-    let mux_protocol = negotiate_protocol(
-        &mut stream,
-        false,
-        &supported_protocols().get("multiplexing").unwrap(),
-    )
-    .await
-    .unwrap();
-    println!("[server] Protocol negotiation complete");
-
-    println!("[server] Starting protocol negotiation with {addr}");
-    match mux_protocol.as_str() {
-        "/mplex" => {
-            // match negotiate_protocol(
-            //     &mut stream,
-            //     false,
-            //     &supported_protocols().get("protocol").unwrap(),
-            // )
-            // .await
-            // {
-            //     Ok(protocol) => {
-            //         println!(
-            //             "[server] Protocol negotiation complete with {addr} and protocol :{protocol}"
-            //         );
-            //         ping_server(&mut stream, addr).await;
-            //     }
-            //     Err(_) => {
-            //         std::process::exit(1);
-            //     }
-            // }
-
-            let arc_stream = Arc::new(stream);
-            let mux = Muxer::new(arc_stream.clone(), false); // false = responder (even ids)
-            mux.start_reader();
-
-            // accept loop
-            tokio::spawn({
-                let mux = mux.clone();
-                async move {
-                    loop {
-                        if let Some((stream_id, proto, mut rx)) = mux.accept_stream().await {
-                            tokio::spawn({
-                                let mux = mux.clone();
-                                async move {
-                                    println!("Incoming stream {} proto={}", stream_id, proto);
-                                    while let Some(bytes) = rx.recv().await {
-                                        let s = String::from_utf8_lossy(&bytes);
-                                        if s.trim().starts_with("PING") {
-                                            let reply = s.replace("PING", "PONG");
-                                            mux.send_data(stream_id, reply.as_bytes())
-                                                .await
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                            });
-                        } else {
-                            break;
-                        }
-                    }
+    // application protocols are negotiated per stream, see `serve_streams`
+    match muxed {
+        Muxed::Mplex(mux) => tokio::spawn(serve_streams(mux)),
+        Muxed::Yamux(mux) => tokio::spawn(serve_streams(mux)),
+    };
+}
+
+/// Accept loop: negotiate each inbound stream, then answer every `PING`
+/// line on it.
+async fn serve_streams<M: StreamMuxer>(mux: Arc<M>) {
+    while let Some(substream) = mux.accept_stream().await {
+        tokio::spawn(async move {
+            let stream_id = substream.id();
+            let protocols = supported_protocols();
+            let stream = match substream.select_inbound(&protocols["protocol"]).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("[server] negotiation on {stream_id} failed: {e}");
+                    return;
                 }
-            });
-        }
-        "/yamux" => {
-            println!("[server] Unimplemented protocl");
-            std::process::exit(1);
-        }
-        _ => {
-            eprintln!("[server] no matching protocol found");
-            std::process::exit(1);
-        }
+            };
+            println!("Incoming stream {} proto={}", stream_id, stream.protocol());
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().starts_with("PING")
+                    && let Err(e) = send_line(&mut writer, &line.replace("PING", "PONG")).await
+                {
+                    eprintln!("[server] reply on {stream_id} failed: {e}");
+                    break;
+                }
+            }
+        });
     }
 }
 
-fn supported_protocols() -> HashMap<&'static str, Vec<&'static str>> {
-    HashMap::from([
-        ("security", vec!["/noise/xx", "/tls{unimplemented}"]),
-        ("protocol", vec!["/ping/1.0.0"]),
-        ("multiplexing", vec!["/yamux{unimplemented}", "/mplex"]),
-    ])
+/// Write one newline-terminated message and push it out.
+async fn send_line<W: AsyncWrite + Unpin>(writer: &mut W, msg: &str) -> tokio::io::Result<()> {
+    writer.write_all(format!("{msg}\n").as_bytes()).await?;
+    writer.flush().await
 }
 
-pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
+pub async fn interactive_client_loop<M: StreamMuxer>(mux: Arc<M>) -> tokio::io::Result<()> {
     println!("Interactive client ready. Commands:");
     println!("  /open <protocol>      e.g. /open /ping/1.0.0");
     println!("  /send <id> <message>");
@@ -237,13 +249,33 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
     println!("  /list");
     println!("  /quit");
 
-    // keep a local set of open stream ids so we can list and validate
-    let mut open_streams: HashSet<u32> = HashSet::new();
+    // the write halves of our open streams; their read halves feed printers
+    let mut open_streams: HashMap<StreamId, WriteHalf<Negotiated<Substream<M>>>> = HashMap::new();
+    // printers hand their read half back when the stream failed, e.g. when
+    // the peer refused a lazily proposed protocol, so it can be reset
+    let (failed_tx, mut failed_rx) =
+        mpsc::unbounded_channel::<(StreamId, ReadHalf<Negotiated<Substream<M>>>)>();
 
     let stdin = BufReader::new(tokio::io::stdin());
     let mut lines = stdin.lines();
 
-    while let Some(line) = lines.next_line().await? {
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            Some((stream_id, reader)) = failed_rx.recv() => {
+                if let Some(writer) = open_streams.remove(&stream_id) {
+                    let mut stream = reader.unsplit(writer);
+                    match stream.get_mut().reset().await {
+                        Ok(()) => println!("[client] reset stream {}", stream_id),
+                        Err(e) => eprintln!("[client] reset error: {}", e),
+                    }
+                }
+                continue;
+            }
+        };
         let line = line.trim();
         if line.is_empty() {
             continue;
@@ -260,18 +292,38 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
                     continue;
                 }
 
-                // open the stream
-                match mux.open_stream(proto).await {
-                    Ok((stream_id, rx)) => {
+                // open the stream and propose the protocol without waiting
+                // for the answer; a refusal shows up in the printer task,
+                // which hands the stream back to be reset
+                let opened = match mux.open_stream().await {
+                    Ok(substream) => substream
+                        .select_outbound(&[proto], Version::V1Lazy)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match opened {
+                    Ok(stream) => {
+                        let stream_id = stream.get_ref().id();
                         println!("[client] Opened stream id={}", stream_id);
-                        open_streams.insert(stream_id);
+                        let (reader, writer) = tokio::io::split(stream);
+                        open_streams.insert(stream_id, writer);
 
                         // spawn a printer task to show responses arriving on this stream
+                        let failed_tx = failed_tx.clone();
                         tokio::spawn(async move {
-                            let mut rx = rx;
-                            while let Some(bytes) = rx.recv().await {
-                                let s = String::from_utf8_lossy(&bytes);
-                                println!("[s{}] <- {}", stream_id, s.trim_end());
+                            let mut lines = BufReader::new(reader).lines();
+                            loop {
+                                match lines.next_line().await {
+                                    Ok(Some(line)) => println!("[s{}] <- {}", stream_id, line),
+                                    Ok(None) => break,
+                                    Err(e) => {
+                                        println!("[s{}] error: {}", stream_id, e);
+                                        let reader = lines.into_inner().into_inner();
+                                        let _ = failed_tx.send((stream_id, reader));
+                                        break;
+                                    }
+                                }
                             }
                             println!("[s{}] receiver closed", stream_id);
                         });
@@ -290,17 +342,12 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
                                 Some(mut ping_line) => {
                                     ping_line = ping_line.trim().to_string();
                                     if !ping_line.is_empty() {
-                                        let msg = format!("PING {}\n", ping_line);
-                                        if let Err(e) =
-                                            mux.send_data(stream_id, msg.as_bytes()).await
-                                        {
-                                            eprintln!("[client] send_data error: {}", e);
+                                        let msg = format!("PING {}", ping_line);
+                                        let writer = open_streams.get_mut(&stream_id).unwrap();
+                                        if let Err(e) = send_line(writer, &msg).await {
+                                            eprintln!("[client] send error: {}", e);
                                         } else {
-                                            println!(
-                                                "[client] -> sent on s{}: {}",
-                                                stream_id,
-                                                msg.trim_end()
-                                            );
+                                            println!("[client] -> sent on s{}: {}", stream_id, msg);
                                         }
                                     } else {
                                         println!("[client] no message entered; skip sending");
@@ -327,20 +374,20 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
                     println!("Usage: /send <id> <message>");
                     continue;
                 }
-                let sid: u32 = match id_str.parse() {
-                    Ok(s) => s,
+                // we only ever address the streams we opened ourselves
+                let sid = match id_str.parse() {
+                    Ok(num) => StreamId { num, local: true },
                     Err(_) => {
                         println!("Invalid stream id: {}", id_str);
                         continue;
                     }
                 };
-                if !open_streams.contains(&sid) {
+                let Some(writer) = open_streams.get_mut(&sid) else {
                     println!("Stream {} not known/open", sid);
                     continue;
-                }
-                let payload = format!("{}\n", msg);
-                if let Err(e) = mux.send_data(sid, payload.as_bytes()).await {
-                    eprintln!("[client] send_data error: {}", e);
+                };
+                if let Err(e) = send_line(writer, msg).await {
+                    eprintln!("[client] send error: {}", e);
                 } else {
                     println!("[client] -> sent on s{}: {}", sid, msg);
                 }
@@ -352,22 +399,22 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
                     println!("Usage: /close <id>");
                     continue;
                 }
-                let sid: u32 = match id_str.parse() {
-                    Ok(s) => s,
+                // we only ever address the streams we opened ourselves
+                let sid = match id_str.parse() {
+                    Ok(num) => StreamId { num, local: true },
                     Err(_) => {
                         println!("Invalid stream id: {}", id_str);
                         continue;
                     }
                 };
-                if !open_streams.contains(&sid) {
+                let Some(mut writer) = open_streams.remove(&sid) else {
                     println!("Stream {} not known/open", sid);
                     continue;
-                }
-                if let Err(e) = mux.close_stream(sid).await {
-                    eprintln!("[client] close_stream error: {}", e);
+                };
+                if let Err(e) = writer.shutdown().await {
+                    eprintln!("[client] close error: {}", e);
                 } else {
                     println!("[client] closed stream {}", sid);
-                    open_streams.remove(&sid);
                 }
             }
 
@@ -375,7 +422,8 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
                 if open_streams.is_empty() {
                     println!("No open streams");
                 } else {
-                    println!("Open streams: {:?}", open_streams);
+                    let ids: Vec<_> = open_streams.keys().map(|id| id.to_string()).collect();
+                    println!("Open streams: {}", ids.join(", "));
                 }
             }
 
@@ -395,81 +443,3 @@ pub async fn interactive_client_loop(mux: Arc<Muxer>) -> tokio::io::Result<()> {
 
     Ok(())
 }
-
-async fn mux_client(stream: EncryptedStream) {
-    let reader = BufReader::new(tokio::io::stdin());
-    let mut lines = reader.lines();
-    let arc_enc = Arc::new(stream);
-
-    while let Ok(Some(line)) = lines.next_line().await {
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.is_empty() {
-            continue;
-        }
-
-        match parts[0] {
-            "/open" => {
-                if parts.len() < 2 {
-                    println!("[Mux_client] Usage: /open <protocol>");
-                    continue;
-                }
-                println!("[Mux_client] entered open");
-                let mux = Muxer::new(arc_enc.clone(), true); // true = initiator (odd ids)
-                mux.start_reader();
-
-                // open stream 1
-                let (s1, mut r1) = mux.open_stream("/ping/1.0.0").await.unwrap();
-                tokio::spawn({
-                    let mux = mux.clone();
-                    async move {
-                        for i in 0..10 {
-                            let msg = format!("PING A-{}\n", i);
-                            mux.send_data(s1, msg.as_bytes()).await.unwrap();
-                            if let Some(bytes) = r1.recv().await {
-                                println!("[client][s1] <- {}", String::from_utf8_lossy(&bytes));
-                            }
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        }
-                        mux.close_stream(s1).await.unwrap();
-                    }
-                });
-
-                // open stream 3
-                let (s2, mut r2) = mux.open_stream("/ping/1.0.0").await.unwrap();
-                tokio::spawn({
-                    let mux = mux.clone();
-                    async move {
-                        for i in 0..10 {
-                            let msg = format!("PING B-{}\n", i);
-                            mux.send_data(s2, msg.as_bytes()).await.unwrap();
-                            if let Some(bytes) = r2.recv().await {
-                                println!("[client][s2] <- {}", String::from_utf8_lossy(&bytes));
-                            }
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        }
-                        mux.close_stream(s2).await.unwrap();
-                    }
-                });
-            }
-            "/close" => {
-                if parts.len() < 2 {
-                    println!("[Mux_client] Usage: /close <id>");
-                    continue;
-                }
-            }
-            "/send" => {
-                if parts.len() < 3 {
-                    println!("[Mux_client] Usage: /send <id> <msg>");
-                    continue;
-                }
-            }
-            "/quit" => {
-                println!("[Mux_client]Exiting client .....");
-                break;
-            }
-            _ => {
-                println!("[Mux_client]Unknown command");
-            }
-        }
-    }
-}
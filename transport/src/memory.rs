@@ -0,0 +1,255 @@
+//! In-process connections addressed as `/memory/<n>`, for tests. A dial is
+//! answered by the listener registered under the same number in this
+//! process; the two ends are joined by duplex pipes, optionally through a
+//! link that delays, throttles, fragments or cuts the traffic.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use common::multiaddr::{Multiaddr, Protocol};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc,
+    task::{JoinError, JoinHandle},
+    time::{Instant, sleep, sleep_until},
+};
+
+use crate::{Inbound, Listener, Transport, TransportError};
+
+/// Capacity of each pipe, in bytes.
+const PIPE_CAPACITY: usize = 64 * 1024;
+/// Connections a listener holds before `dial` is refused.
+const BACKLOG: usize = 32;
+
+/// Listeners by port. Dialers look up the port here.
+static LISTENERS: LazyLock<Mutex<HashMap<u64, mpsc::Sender<Inbound<DuplexStream>>>>> =
+    LazyLock::new(Default::default);
+/// Source of the ports handed out for `/memory/0` and to dialers.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1 << 32);
+
+/// Faults applied to both directions of a connection. The default is a
+/// perfect link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkConfig {
+    /// How long each write takes to reach the other end.
+    pub latency: Duration,
+    /// Bytes per second; data is held back for the time sending it takes,
+    /// and queues behind what is still being sent.
+    pub bandwidth: Option<u64>,
+    /// Deliver data in pieces of at most this many bytes.
+    pub max_fragment: Option<usize>,
+    /// Cut the connection once this many bytes have gone one way: the
+    /// receiver gets exactly that many and then end of stream, and from
+    /// then on writes fail at both ends.
+    pub drop_after: Option<u64>,
+}
+
+impl LinkConfig {
+    fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Connects to listeners in the same process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryTransport {
+    link: LinkConfig,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Dial through a faulty link instead of a perfect one.
+    pub fn with_link(link: LinkConfig) -> Self {
+        Self { link }
+    }
+}
+
+/// The accepting end of a `/memory/<n>` address; the port is released
+/// when it is dropped.
+#[derive(Debug)]
+pub struct MemoryListener {
+    port: u64,
+    incoming: mpsc::Receiver<Inbound<DuplexStream>>,
+}
+
+impl Transport for MemoryTransport {
+    type Output = DuplexStream;
+    type Listener = MemoryListener;
+
+    async fn dial(&self, addr: &Multiaddr) -> Result<DuplexStream, TransportError> {
+        let port = memory_port(addr)?;
+        let listener = LISTENERS.lock().unwrap().get(&port).cloned();
+        let Some(listener) = listener else {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        };
+
+        let (local, remote) = connect(self.link);
+        let inbound = Inbound {
+            stream: remote,
+            local_addr: memory_addr(port),
+            remote_addr: memory_addr(NEXT_PORT.fetch_add(1, Ordering::Relaxed)),
+        };
+        if listener.try_send(inbound).is_err() {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        }
+        println!("[memory] Connected to /memory/{port}");
+        Ok(local)
+    }
+
+    async fn listen_on(&self, addr: &Multiaddr) -> Result<MemoryListener, TransportError> {
+        let mut port = memory_port(addr)?;
+        let (tx, incoming) = mpsc::channel(BACKLOG);
+        let mut listeners = LISTENERS.lock().unwrap();
+        if port == 0 {
+            port = loop {
+                let candidate = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+                if !listeners.contains_key(&candidate) {
+                    break candidate;
+                }
+            };
+        }
+        match listeners.entry(port) {
+            Entry::Occupied(_) => Err(io::Error::from(io::ErrorKind::AddrInUse).into()),
+            Entry::Vacant(entry) => {
+                entry.insert(tx);
+                println!("[memory] Listening on /memory/{port}");
+                Ok(MemoryListener { port, incoming })
+            }
+        }
+    }
+}
+
+impl Listener for MemoryListener {
+    type Output = DuplexStream;
+
+    fn local_addr(&self) -> Multiaddr {
+        memory_addr(self.port)
+    }
+
+    async fn accept(&mut self) -> Result<Inbound<DuplexStream>, TransportError> {
+        // the sender lives in the registry until we are dropped
+        Ok(self
+            .incoming
+            .recv()
+            .await
+            .expect("listener still registered"))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().remove(&self.port);
+    }
+}
+
+fn memory_port(addr: &Multiaddr) -> Result<u64, TransportError> {
+    let components: Vec<_> = addr.iter().collect();
+    match components.as_slice() {
+        [Protocol::Memory(port)] | [Protocol::Memory(port), Protocol::P2p(_)] => Ok(*port),
+        _ => Err(TransportError::UnsupportedAddress(addr.clone())),
+    }
+}
+
+fn memory_addr(port: u64) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Memory(port))
+}
+
+/// A pair of connected streams, joined through `link` unless it is perfect.
+fn connect(link: LinkConfig) -> (DuplexStream, DuplexStream) {
+    if link.is_perfect() {
+        return tokio::io::duplex(PIPE_CAPACITY);
+    }
+    let (dialer, dialer_link) = tokio::io::duplex(PIPE_CAPACITY);
+    let (listener, listener_link) = tokio::io::duplex(PIPE_CAPACITY);
+    let (from_dialer, to_dialer) = tokio::io::split(dialer_link);
+    let (from_listener, to_listener) = tokio::io::split(listener_link);
+    tokio::spawn(async move {
+        let mut outbound = tokio::spawn(relay(from_dialer, to_listener, link));
+        let mut inbound = tokio::spawn(relay(from_listener, to_dialer, link));
+        tokio::select! {
+            cut = &mut outbound => finish(cut, inbound).await,
+            cut = &mut inbound => finish(cut, outbound).await,
+        }
+    });
+    (dialer, listener)
+}
+
+/// One direction is done; a cut takes down the other too, a clean close
+/// leaves it running.
+async fn finish(cut: Result<bool, JoinError>, other: JoinHandle<bool>) {
+    if cut.unwrap_or(true) {
+        other.abort();
+    } else {
+        let _ = other.await;
+    }
+}
+
+/// Copy one direction through the link. Returns whether the link was cut.
+async fn relay(
+    mut from: ReadHalf<DuplexStream>,
+    mut to: WriteHalf<DuplexStream>,
+    link: LinkConfig,
+) -> bool {
+    // reading runs ahead of delivery so latency overlaps instead of adding
+    // up, but by no more than a pipe's worth, so a fast writer still blocks
+    let chunk = link
+        .max_fragment
+        .unwrap_or(PIPE_CAPACITY)
+        .clamp(1, PIPE_CAPACITY);
+    let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(PIPE_CAPACITY / chunk);
+    let read = async move {
+        let mut budget = link.drop_after.unwrap_or(u64::MAX);
+        let mut buf = vec![0u8; chunk];
+        while budget > 0 {
+            let n = match from.read(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(n) => n.min(usize::try_from(budget).unwrap_or(usize::MAX)),
+            };
+            budget -= n as u64;
+            if tx
+                .send((Instant::now() + link.latency, buf[..n].to_vec()))
+                .await
+                .is_err()
+            {
+                return false;
+            }
+        }
+        true
+    };
+    let deliver = async {
+        while let Some((due, data)) = rx.recv().await {
+            sleep_until(due).await;
+            // the last byte arrives once the whole chunk has been sent
+            if let Some(rate) = link.bandwidth {
+                sleep(Duration::from_secs_f64(
+                    data.len() as f64 / rate.max(1) as f64,
+                ))
+                .await;
+            }
+            if to.write_all(&data).await.is_err() {
+                break;
+            }
+            // let the reader see each fragment on its own
+            tokio::task::yield_now().await;
+        }
+        let _ = to.shutdown().await;
+    };
+    let (cut, ()) = tokio::join!(read, deliver);
+    if cut {
+        println!(
+            "[memory] Link cut after {} bytes",
+            link.drop_after.unwrap_or(0)
+        );
+    }
+    cut
+}
@@ -0,0 +1,109 @@
+//! TCP, via tokio's sockets.
+
+use std::net::{IpAddr, SocketAddr};
+
+use common::multiaddr::{Multiaddr, Protocol};
+use tokio::net::TcpStream;
+
+use crate::{Inbound, Listener, Transport, TransportError};
+
+/// Dials and listens on `/ip4`, `/ip6`, `/dns4` and `/dns6` addresses
+/// followed by `/tcp/<port>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+/// A bound TCP socket accepting connections.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: tokio::net::TcpListener,
+    local_addr: Multiaddr,
+}
+
+impl Transport for TcpTransport {
+    type Output = TcpStream;
+    type Listener = TcpListener;
+
+    async fn dial(&self, addr: &Multiaddr) -> Result<TcpStream, TransportError> {
+        let mut last_err = None;
+        for socket_addr in resolve(addr).await? {
+            match TcpStream::connect(socket_addr).await {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    println!("[tcp] Connected to {socket_addr}");
+                    return Ok(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .map(TransportError::Io)
+            .unwrap_or_else(|| TransportError::UnsupportedAddress(addr.clone())))
+    }
+
+    async fn listen_on(&self, addr: &Multiaddr) -> Result<TcpListener, TransportError> {
+        let socket_addr = resolve(addr)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| TransportError::UnsupportedAddress(addr.clone()))?;
+        let inner = tokio::net::TcpListener::bind(socket_addr).await?;
+        let local_addr = to_multiaddr(inner.local_addr()?);
+        println!("[tcp] Listening on {local_addr}");
+        Ok(TcpListener { inner, local_addr })
+    }
+}
+
+impl Listener for TcpListener {
+    type Output = TcpStream;
+
+    fn local_addr(&self) -> Multiaddr {
+        self.local_addr.clone()
+    }
+
+    async fn accept(&mut self) -> Result<Inbound<TcpStream>, TransportError> {
+        let (stream, remote) = self.inner.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok(Inbound {
+            stream,
+            local_addr: self.local_addr.clone(),
+            remote_addr: to_multiaddr(remote),
+        })
+    }
+}
+
+/// The socket addresses `addr` stands for; names are looked up and only
+/// the answers of the requested family kept.
+async fn resolve(addr: &Multiaddr) -> Result<Vec<SocketAddr>, TransportError> {
+    let unsupported = || TransportError::UnsupportedAddress(addr.clone());
+    let mut components = addr.iter();
+    let host = components.next().ok_or_else(unsupported)?;
+    let port = match components.next() {
+        Some(Protocol::Tcp(port)) => *port,
+        _ => return Err(unsupported()),
+    };
+    if !matches!(components.as_slice(), [] | [Protocol::P2p(_)]) {
+        return Err(unsupported());
+    }
+
+    let (name, want_v4) = match host {
+        Protocol::Ip4(ip) => return Ok(vec![SocketAddr::new(IpAddr::V4(*ip), port)]),
+        Protocol::Ip6(ip) => return Ok(vec![SocketAddr::new(IpAddr::V6(*ip), port)]),
+        Protocol::Dns4(name) => (name, true),
+        Protocol::Dns6(name) => (name, false),
+        _ => return Err(unsupported()),
+    };
+    Ok(tokio::net::lookup_host((name.as_str(), port))
+        .await?
+        .filter(|a| a.is_ipv4() == want_v4)
+        .collect())
+}
+
+fn to_multiaddr(addr: SocketAddr) -> Multiaddr {
+    let host = match addr.ip() {
+        IpAddr::V4(ip) => Protocol::Ip4(ip),
+        IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    Multiaddr::empty()
+        .with(host)
+        .with(Protocol::Tcp(addr.port()))
+}
@@ -0,0 +1,162 @@
+//! Unix domain sockets, addressed as `/unix/<path>`, for peers on the same
+//! host.
+
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use common::multiaddr::{Multiaddr, Protocol};
+use tokio::net::UnixStream;
+
+use crate::{Inbound, Listener, Transport, TransportError};
+
+/// Dials and listens on socket files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnixTransport {
+    mode: Option<u32>,
+}
+
+impl UnixTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give socket files created by `listen_on` these permission bits,
+    /// e.g. `0o660` to admit only the owner and group.
+    pub fn with_permissions(mode: u32) -> Self {
+        Self { mode: Some(mode) }
+    }
+}
+
+/// A bound socket accepting connections; the socket file is removed when
+/// it is dropped.
+#[derive(Debug)]
+pub struct UnixListener {
+    inner: tokio::net::UnixListener,
+    path: PathBuf,
+    local_addr: Multiaddr,
+}
+
+impl Transport for UnixTransport {
+    type Output = UnixStream;
+    type Listener = UnixListener;
+
+    async fn dial(&self, addr: &Multiaddr) -> Result<UnixStream, TransportError> {
+        let path = socket_path(addr)?;
+        let stream = UnixStream::connect(path).await?;
+        println!("[unix] Connected to {}", path.display());
+        Ok(stream)
+    }
+
+    async fn listen_on(&self, addr: &Multiaddr) -> Result<UnixListener, TransportError> {
+        let path = socket_path(addr)?;
+        remove_stale_socket(path).await?;
+        let inner = match self.mode {
+            Some(mode) => bind_with_mode(path, mode)?,
+            None => tokio::net::UnixListener::bind(path)?,
+        };
+        println!("[unix] Listening on {}", path.display());
+        Ok(UnixListener {
+            inner,
+            path: path.to_path_buf(),
+            local_addr: addr.clone(),
+        })
+    }
+}
+
+impl Listener for UnixListener {
+    type Output = UnixStream;
+
+    fn local_addr(&self) -> Multiaddr {
+        self.local_addr.clone()
+    }
+
+    /// Dialing sockets are usually unnamed; those are reported under the
+    /// listener's own address.
+    async fn accept(&mut self) -> Result<Inbound<UnixStream>, TransportError> {
+        let (stream, remote) = self.inner.accept().await?;
+        let remote_addr = match remote.as_pathname() {
+            Some(path) => Multiaddr::empty().with(Protocol::Unix(path.display().to_string())),
+            None => self.local_addr.clone(),
+        };
+        Ok(Inbound {
+            stream,
+            local_addr: self.local_addr.clone(),
+            remote_addr,
+        })
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn socket_path(addr: &Multiaddr) -> Result<&Path, TransportError> {
+    let components: Vec<_> = addr.iter().collect();
+    match components.as_slice() {
+        [Protocol::Unix(path)] => Ok(Path::new(path)),
+        _ => Err(TransportError::UnsupportedAddress(addr.clone())),
+    }
+}
+
+/// Bind a socket at `path` that is never reachable with looser permissions
+/// than `mode`: it is bound inside a fresh directory only we can enter,
+/// given `mode` there, and then linked into place. Linking fails rather than
+/// replace a file that appeared at `path` in the meantime.
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<tokio::net::UnixListener> {
+    static STAGING: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, path.display().to_string()))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}-{}",
+        name.to_string_lossy(),
+        process::id(),
+        STAGING.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+
+    let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&staged, path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                io::Error::new(io::ErrorKind::AddrInUse, path.display().to_string())
+            }
+            _ => e,
+        })?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+/// Remove a socket file left behind by a listener that is gone, so that
+/// binding does not fail. A socket someone still answers on, or a file
+/// that is not a socket, is left alone and reported as `AddrInUse`.
+async fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let in_use = || io::Error::new(io::ErrorKind::AddrInUse, path.display().to_string());
+    if !metadata.file_type().is_socket() {
+        return Err(in_use());
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(in_use()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            println!("[unix] Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,90 @@
+//! Turning a raw connection into a secured, multiplexed one: multistream
+//! selects Noise, the handshake authenticates the peer, and a second
+//! negotiation over the encrypted channel picks the muxer.
+
+use std::{collections::HashMap, sync::Arc};
+
+use common::{
+    EncryptedStream,
+    identity::{Keypair, PeerId},
+};
+use muxer::{MPLEX_PROTOCOL, Muxer, YAMUX_PROTOCOL, Yamux};
+use negotiation::{NegotiationError, negotiate_protocol};
+use security::{SecurityError, negotiate_security_protocol};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpgradeError {
+    #[error("security upgrade failed: {0}")]
+    Security(#[from] SecurityError),
+    #[error("muxer negotiation failed: {0}")]
+    Negotiation(#[from] NegotiationError),
+    #[error("no muxer for negotiated protocol {0}")]
+    UnsupportedMuxer(String),
+}
+
+/// A started muxer of whichever kind was negotiated.
+#[derive(Clone)]
+pub enum Muxed {
+    Mplex(Arc<Muxer>),
+    Yamux(Arc<Yamux>),
+}
+
+pub fn supported_protocols() -> HashMap<&'static str, Vec<&'static str>> {
+    HashMap::from([
+        ("security", vec!["/noise"]),
+        ("protocol", vec!["/ping/1.0.0"]),
+        ("multiplexing", vec![YAMUX_PROTOCOL, MPLEX_PROTOCOL]),
+    ])
+}
+
+/// Secure and multiplex `io`. The dialer passes `is_initiator` and, if it
+/// knows whom it meant to reach, `expected_peer`. Returns the remote's
+/// verified `PeerId` with its muxer already running.
+pub async fn upgrade<S>(
+    io: S,
+    is_initiator: bool,
+    identity: &Keypair,
+    expected_peer: Option<&PeerId>,
+) -> Result<(PeerId, Muxed), UpgradeError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let protocols = supported_protocols();
+    let (reader, mut writer) = tokio::io::split(io);
+    let mut reader = BufReader::new(reader);
+
+    println!("[upgrade] Starting security negotiation, initiator={is_initiator}");
+    let (remote_peer, noise) = negotiate_security_protocol(
+        &mut reader,
+        &mut writer,
+        is_initiator,
+        &protocols,
+        identity,
+        expected_peer,
+    )
+    .await?;
+    println!("[upgrade] Security negotiation complete, remote peer {remote_peer}");
+
+    let mut stream = EncryptedStream::new(noise, reader, writer);
+
+    println!("[upgrade] Starting multiplexing protocol negotiation...");
+    let mux_protocol =
+        negotiate_protocol(&mut stream, is_initiator, &protocols["multiplexing"]).await?;
+
+    let muxed = match mux_protocol.as_str() {
+        MPLEX_PROTOCOL => {
+            let mux = Muxer::new(stream);
+            mux.start_reader();
+            Muxed::Mplex(mux)
+        }
+        YAMUX_PROTOCOL => {
+            let mux = Yamux::new(stream, is_initiator);
+            mux.start();
+            Muxed::Yamux(mux)
+        }
+        other => return Err(UpgradeError::UnsupportedMuxer(other.to_string())),
+    };
+    println!("[upgrade] Connection to {remote_peer} upgraded with {mux_protocol}");
+    Ok((remote_peer, muxed))
+}
@@ -0,0 +1,227 @@
+//! WebSocket, addressed as `<inner address>/ws`, e.g. `/ip4/127.0.0.1/tcp/80/ws`.
+//! Binary messages carry the byte stream; each write is sent as one message
+//! and reads hand out message payloads as they arrive.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use common::multiaddr::{Multiaddr, Protocol};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinSet,
+};
+use tokio_tungstenite::{
+    WebSocketStream, accept_async, client_async,
+    tungstenite::{self, Message},
+};
+
+use crate::{Inbound, Listener, TcpTransport, Transport, TransportError};
+
+/// How long an accepted connection may take to complete its handshake.
+pub const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the WebSocket handshake over connections of another transport,
+/// TCP unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WsTransport<T = TcpTransport> {
+    inner: T,
+}
+
+impl<T> WsTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+/// Accepts connections of the inner transport and completes their
+/// WebSocket handshake. Handshakes run concurrently in their own tasks, so a
+/// client that never sends its upgrade request holds up no one else.
+#[derive(Debug)]
+pub struct WsListener<L: Listener> {
+    inner: L,
+    handshakes: JoinSet<Result<Inbound<WsStream<L::Output>>, TransportError>>,
+}
+
+/// A WebSocket connection read and written as a plain byte stream.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// What is left of the last message read.
+    read_buf: Bytes,
+}
+
+impl<T: Transport> Transport for WsTransport<T> {
+    type Output = WsStream<T::Output>;
+    type Listener = WsListener<T::Listener>;
+
+    async fn dial(&self, addr: &Multiaddr) -> Result<Self::Output, TransportError> {
+        let inner_addr = strip_ws(addr)?;
+        let io = self.inner.dial(&inner_addr).await?;
+        let url = format!("ws://{}/", authority(&inner_addr));
+        let (inner, _) = client_async(url.as_str(), io).await.map_err(into_io)?;
+        println!("[ws] Connected to {url}");
+        Ok(WsStream::new(inner))
+    }
+
+    async fn listen_on(&self, addr: &Multiaddr) -> Result<Self::Listener, TransportError> {
+        let inner = self.inner.listen_on(&strip_ws(addr)?).await?;
+        Ok(WsListener {
+            inner,
+            handshakes: JoinSet::new(),
+        })
+    }
+}
+
+impl<L> Listener for WsListener<L>
+where
+    L: Listener,
+    L::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = WsStream<L::Output>;
+
+    fn local_addr(&self) -> Multiaddr {
+        self.inner.local_addr().with(Protocol::Ws)
+    }
+
+    /// Returns connections in the order their handshakes complete. One whose
+    /// handshake fails or times out is reported as an error; the listener
+    /// itself stays usable.
+    async fn accept(&mut self) -> Result<Inbound<Self::Output>, TransportError> {
+        loop {
+            tokio::select! {
+                biased;
+                Some(done) = self.handshakes.join_next() => {
+                    return done.map_err(io::Error::other)?;
+                }
+                inbound = self.inner.accept() => {
+                    self.handshakes.spawn(handshake(inbound?));
+                }
+            }
+        }
+    }
+}
+
+/// Complete the server side of the WebSocket handshake on an accepted
+/// connection, giving up after `WS_HANDSHAKE_TIMEOUT`.
+async fn handshake<S>(inbound: Inbound<S>) -> Result<Inbound<WsStream<S>>, TransportError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let inner = tokio::time::timeout(WS_HANDSHAKE_TIMEOUT, accept_async(inbound.stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+        .map_err(into_io)?;
+    Ok(Inbound {
+        stream: WsStream::new(inner),
+        local_addr: inbound.local_addr.with(Protocol::Ws),
+        remote_addr: inbound.remote_addr.with(Protocol::Ws),
+    })
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_buf.is_empty() {
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read_buf = data,
+                // end of stream
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(tungstenite::Error::ConnectionClosed)) => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text message on a binary stream",
+                    )));
+                }
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            }
+        }
+        let n = this.read_buf.len().min(buf.remaining());
+        buf.put_slice(&this.read_buf[..n]);
+        this.read_buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let inner = &mut self.get_mut().inner;
+        ready!(inner.poll_ready_unpin(cx)).map_err(into_io)?;
+        inner
+            .start_send_unpin(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(into_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_flush_unpin(cx).map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_close_unpin(cx).map_err(into_io)
+    }
+}
+
+/// The address to hand the inner transport: `addr` without its `/ws` and
+/// any `/p2p/<id>` after it.
+fn strip_ws(addr: &Multiaddr) -> Result<Multiaddr, TransportError> {
+    let mut inner = addr.clone();
+    if let Some(Protocol::P2p(_)) = inner.iter().last() {
+        inner.pop();
+    }
+    match inner.pop() {
+        Some(Protocol::Ws) => Ok(inner),
+        _ => Err(TransportError::UnsupportedAddress(addr.clone())),
+    }
+}
+
+/// `host:port` for the request line and `Host` header.
+fn authority(addr: &Multiaddr) -> String {
+    let mut components = addr.iter();
+    let host = match components.next() {
+        Some(Protocol::Ip4(ip)) => ip.to_string(),
+        Some(Protocol::Ip6(ip)) => format!("[{ip}]"),
+        Some(Protocol::Dns4(name) | Protocol::Dns6(name)) => name.clone(),
+        // not a network address, e.g. `/memory/<n>`
+        _ => return "localhost".to_string(),
+    };
+    match components.next() {
+        Some(Protocol::Tcp(port)) => format!("{host}:{port}"),
+        _ => host,
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        other => io::Error::other(other),
+    }
+}
@@ -0,0 +1,334 @@
+use std::{collections::HashMap, io::ErrorKind, sync::Arc, time::Duration};
+
+use common::{
+    EncryptedStream,
+    identity::Keypair,
+    multiaddr::{Multiaddr, Protocol},
+};
+use muxer::{MPLEX_PROTOCOL, Muxer, StreamMuxer};
+use negotiation::{Version, negotiate_protocol};
+use security::negotiate_security_protocol;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    time::Instant,
+};
+use transport::{
+    LinkConfig, Listener, MemoryTransport, Muxed, Transport, TransportError, UpgradeError,
+    supported_protocols, upgrade,
+};
+
+const PING: &str = "/ping/1.0.0";
+
+fn any_port() -> Multiaddr {
+    "/memory/0".parse().unwrap()
+}
+
+/// Answer one ping stream by echoing what it carries until it closes.
+async fn echo<M: StreamMuxer>(mux: Arc<M>) {
+    let substream = mux.accept_stream().await.unwrap();
+    let stream = substream.select_inbound(&[PING]).await.unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    writer.shutdown().await.unwrap();
+}
+
+/// Send `msg` over a new ping stream and return what comes back.
+async fn ping<M: StreamMuxer>(mux: Arc<M>, msg: &[u8]) -> Vec<u8> {
+    let substream = mux.open_stream().await.unwrap();
+    let stream = substream
+        .select_outbound(&[PING], Version::V1Lazy)
+        .await
+        .unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (_, echoed) = tokio::join!(
+        async {
+            writer.write_all(msg).await.unwrap();
+            writer.shutdown().await.unwrap();
+        },
+        async {
+            let mut echoed = Vec::new();
+            reader.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        }
+    );
+    echoed
+}
+
+async fn serve(muxed: Muxed) {
+    match muxed {
+        Muxed::Mplex(mux) => echo(mux).await,
+        Muxed::Yamux(mux) => echo(mux).await,
+    }
+}
+
+async fn ping_muxed(muxed: Muxed, msg: &[u8]) -> Vec<u8> {
+    match muxed {
+        Muxed::Mplex(mux) => ping(mux, msg).await,
+        Muxed::Yamux(mux) => ping(mux, msg).await,
+    }
+}
+
+#[tokio::test]
+async fn listens_dials_and_releases_ports() {
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+    assert!(!matches!(addr.iter().next(), Some(Protocol::Memory(0))));
+
+    let again = MemoryTransport::new().listen_on(&addr).await;
+    assert!(matches!(again, Err(TransportError::Io(e)) if e.kind() == ErrorKind::AddrInUse));
+
+    let mut dialed = MemoryTransport::new().dial(&addr).await.unwrap();
+    let mut inbound = listener.accept().await.unwrap();
+    assert_eq!(inbound.local_addr, addr);
+    assert_ne!(inbound.remote_addr, addr);
+    dialed.write_all(b"hi").await.unwrap();
+    let mut buf = [0u8; 2];
+    inbound.stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hi");
+
+    drop(listener);
+    let refused = MemoryTransport::new().dial(&addr).await;
+    assert!(
+        matches!(refused, Err(TransportError::Io(e)) if e.kind() == ErrorKind::ConnectionRefused)
+    );
+    MemoryTransport::new().listen_on(&addr).await.unwrap();
+
+    let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+    assert!(matches!(
+        MemoryTransport::new().dial(&tcp).await,
+        Err(TransportError::UnsupportedAddress(_))
+    ));
+}
+
+#[tokio::test]
+async fn full_stack_over_memory() {
+    let (server_key, client_key) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let addr = listener
+        .local_addr()
+        .with(Protocol::P2p(server_key.peer_id()));
+
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        let (peer, muxed) = upgrade(inbound.stream, false, &server_key, None)
+            .await
+            .unwrap();
+        serve(muxed).await;
+        peer
+    });
+
+    let io = MemoryTransport::new().dial(&addr).await.unwrap();
+    let (_, muxed) = upgrade(io, true, &client_key, addr.peer_id())
+        .await
+        .unwrap();
+    assert_eq!(ping_muxed(muxed, b"hello").await, b"hello");
+    assert_eq!(server.await.unwrap(), client_key.peer_id());
+}
+
+#[tokio::test]
+async fn mplex_stack_over_a_fragmenting_link() {
+    let link = LinkConfig {
+        max_fragment: Some(3),
+        ..LinkConfig::default()
+    };
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+
+    /// Security, then mplex as the only muxer on offer.
+    async fn mplex<S>(io: S, is_initiator: bool) -> Arc<Muxer>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, mut writer) = tokio::io::split(io);
+        let mut reader = BufReader::new(reader);
+        let identity = Keypair::generate_ed25519();
+        let (_, noise) = negotiate_security_protocol(
+            &mut reader,
+            &mut writer,
+            is_initiator,
+            &supported_protocols(),
+            &identity,
+            None,
+        )
+        .await
+        .unwrap();
+        let mut stream = EncryptedStream::new(noise, reader, writer);
+        let agreed = negotiate_protocol(&mut stream, is_initiator, &[MPLEX_PROTOCOL])
+            .await
+            .unwrap();
+        assert_eq!(agreed, MPLEX_PROTOCOL);
+        let mux = Muxer::new(stream);
+        mux.start_reader();
+        mux
+    }
+
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        echo(mplex(inbound.stream, false).await).await;
+    });
+    let io = MemoryTransport::with_link(link).dial(&addr).await.unwrap();
+    let msg: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    assert_eq!(ping(mplex(io, true).await, &msg).await, msg);
+    server.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn latency_and_bandwidth_delay_delivery() {
+    let link = LinkConfig {
+        latency: Duration::from_millis(100),
+        bandwidth: Some(10_000),
+        ..LinkConfig::default()
+    };
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let mut dialed = MemoryTransport::with_link(link)
+        .dial(&listener.local_addr())
+        .await
+        .unwrap();
+    let mut inbound = listener.accept().await.unwrap().stream;
+
+    // 100ms on the wire, then 100ms to send 1000 bytes
+    let start = Instant::now();
+    dialed.write_all(&[7u8; 1000]).await.unwrap();
+    let mut buf = vec![0u8; 2000];
+    inbound.read_exact(&mut buf[..1000]).await.unwrap();
+    assert_eq!(start.elapsed(), Duration::from_millis(200));
+
+    // however the link splits 2000 bytes, the last of them lands 200ms
+    // after the first could
+    dialed.write_all(&[7u8; 2000]).await.unwrap();
+    inbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(start.elapsed(), Duration::from_millis(500));
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_link_pushes_back_on_the_writer() {
+    let link = LinkConfig {
+        latency: Duration::from_millis(100),
+        bandwidth: Some(10_000),
+        ..LinkConfig::default()
+    };
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let mut dialed = MemoryTransport::with_link(link)
+        .dial(&listener.local_addr())
+        .await
+        .unwrap();
+    let _unread = listener.accept().await.unwrap().stream;
+
+    // Nobody reads, so only the pipes and the link's own queue, each 64 KiB,
+    // can take data; the writer then stalls instead of the link buffering
+    // everything it is given.
+    let mut written = 0;
+    let chunk = [0u8; 4096];
+    while written < 4 * 1024 * 1024 {
+        match tokio::time::timeout(Duration::from_secs(60), dialed.write_all(&chunk)).await {
+            Ok(result) => result.unwrap(),
+            Err(_) => break,
+        }
+        written += chunk.len();
+    }
+    assert!(written <= 5 * 64 * 1024, "link took {written} bytes");
+}
+
+#[tokio::test]
+async fn cut_link_delivers_exactly_the_budget() {
+    let link = LinkConfig {
+        drop_after: Some(10),
+        ..LinkConfig::default()
+    };
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let mut dialed = MemoryTransport::with_link(link)
+        .dial(&listener.local_addr())
+        .await
+        .unwrap();
+    let mut inbound = listener.accept().await.unwrap().stream;
+
+    dialed.write_all(&[1u8; 20]).await.unwrap();
+    let mut received = Vec::new();
+    inbound.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, [1u8; 10]);
+
+    let mut buf = [0u8; 1];
+    assert_eq!(dialed.read(&mut buf).await.unwrap(), 0);
+    assert!(dialed.write_all(b"more").await.is_err());
+}
+
+#[tokio::test]
+async fn cut_link_fails_the_upgrade() {
+    let link = LinkConfig {
+        drop_after: Some(100),
+        ..LinkConfig::default()
+    };
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        upgrade(inbound.stream, false, &Keypair::generate_ed25519(), None)
+            .await
+            .err()
+    });
+
+    let io = MemoryTransport::with_link(link).dial(&addr).await.unwrap();
+    let client = upgrade(io, true, &Keypair::generate_ed25519(), None).await;
+    assert!(matches!(client, Err(UpgradeError::Security(_))));
+    assert!(server.await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn every_advertised_security_protocol_completes() {
+    for proto in &supported_protocols()["security"] {
+        let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let inbound = listener.accept().await.unwrap();
+            let _ = upgrade(inbound.stream, false, &Keypair::generate_ed25519(), None).await;
+        });
+
+        // a peer offering only this protocol must get through the handshake
+        let io = MemoryTransport::new().dial(&addr).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(io);
+        let only = HashMap::from([("security", vec![*proto])]);
+        let secured = negotiate_security_protocol(
+            &mut BufReader::new(reader),
+            &mut writer,
+            true,
+            &only,
+            &Keypair::generate_ed25519(),
+            None,
+        )
+        .await;
+        assert!(secured.is_ok(), "{proto}: {:?}", secured.err());
+    }
+}
+
+#[tokio::test]
+async fn many_connections_in_parallel() {
+    let server_key = Keypair::generate_ed25519();
+    let mut listener = MemoryTransport::new().listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+    tokio::spawn(async move {
+        loop {
+            let inbound = listener.accept().await.unwrap();
+            let identity = server_key.clone();
+            tokio::spawn(async move {
+                let (_, muxed) = upgrade(inbound.stream, false, &identity, None)
+                    .await
+                    .unwrap();
+                serve(muxed).await;
+            });
+        }
+    });
+
+    let clients = (0..16u8).map(|i| {
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            let io = MemoryTransport::new().dial(&addr).await.unwrap();
+            let (_, muxed) = upgrade(io, true, &Keypair::generate_ed25519(), None)
+                .await
+                .unwrap();
+            assert_eq!(ping_muxed(muxed, &[i; 64]).await, [i; 64]);
+        })
+    });
+    for client in clients.collect::<Vec<_>>() {
+        client.await.unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use common::{
+    identity::Keypair,
+    multiaddr::{Multiaddr, Protocol},
+};
+use negotiation::Version;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use transport::{Listener, Muxed, TcpTransport, Transport, TransportError, UpgradeError, upgrade};
+
+const PING: &str = "/ping/1.0.0";
+
+fn any_port() -> Multiaddr {
+    "/ip4/127.0.0.1/tcp/0".parse().unwrap()
+}
+
+#[tokio::test]
+async fn listener_reports_both_addresses() {
+    let mut listener = TcpTransport.listen_on(&any_port()).await.unwrap();
+    let local = listener.local_addr();
+    assert!(
+        matches!(local.iter().nth(1), Some(Protocol::Tcp(port)) if *port != 0),
+        "port was not resolved: {local}"
+    );
+
+    let dialed = TcpTransport.dial(&local).await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+    assert_eq!(inbound.local_addr, local);
+    let port = dialed.local_addr().unwrap().port();
+    assert_eq!(
+        inbound.remote_addr.to_string(),
+        format!("/ip4/127.0.0.1/tcp/{port}")
+    );
+}
+
+#[tokio::test]
+async fn dials_names_and_ignores_a_trailing_peer_id() {
+    let mut listener = TcpTransport.listen_on(&any_port()).await.unwrap();
+    let Some(Protocol::Tcp(port)) = listener.local_addr().iter().nth(1).cloned() else {
+        panic!("no tcp port");
+    };
+    let peer = Keypair::generate_ed25519().peer_id();
+    let addr: Multiaddr = format!("/dns4/localhost/tcp/{port}/p2p/{peer}")
+        .parse()
+        .unwrap();
+    TcpTransport.dial(&addr).await.unwrap();
+    listener.accept().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_addresses_it_cannot_serve() {
+    for addr in [
+        "/ip4/127.0.0.1/udp/1",
+        "/ip4/127.0.0.1/tcp/1/ws",
+        "/unix/tmp/x",
+    ] {
+        let addr: Multiaddr = addr.parse().unwrap();
+        assert!(matches!(
+            TcpTransport.dial(&addr).await,
+            Err(TransportError::UnsupportedAddress(a)) if a == addr
+        ));
+    }
+}
+
+#[tokio::test]
+async fn dialing_a_closed_port_fails() {
+    let listener = TcpTransport.listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+    drop(listener);
+    assert!(matches!(
+        TcpTransport.dial(&addr).await,
+        Err(TransportError::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn upgraded_connection_carries_negotiated_streams() {
+    let (server_key, client_key) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let mut listener = TcpTransport.listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+    let server_peer = server_key.peer_id();
+
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        let (peer, muxed) = upgrade(inbound.stream, false, &server_key, None)
+            .await
+            .unwrap();
+        let Muxed::Yamux(mux) = muxed else {
+            panic!("expected yamux, the first preference");
+        };
+        let substream = mux.accept_stream().await.unwrap();
+        let mut stream = substream.select_inbound(&[PING]).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+        peer
+    });
+
+    let io = TcpTransport.dial(&addr).await.unwrap();
+    let (seen_server, muxed) = upgrade(io, true, &client_key, None).await.unwrap();
+    let Muxed::Yamux(mux) = muxed else {
+        panic!("expected yamux, the first preference");
+    };
+    let substream = mux.open_stream().await.unwrap();
+    let mut stream = substream
+        .select_outbound(&[PING], Version::V1)
+        .await
+        .unwrap();
+    assert_eq!(stream.protocol(), PING);
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let seen_client = server.await.unwrap();
+    assert_eq!(seen_client, client_key.peer_id());
+    assert_eq!(seen_server, server_peer);
+}
+
+#[tokio::test]
+async fn upgrade_rejects_an_unexpected_peer() {
+    let (server_key, client_key) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let mut listener = TcpTransport.listen_on(&any_port()).await.unwrap();
+    let addr = listener.local_addr();
+
+    tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        let _ = upgrade(inbound.stream, false, &server_key, None).await;
+    });
+
+    let io = TcpTransport.dial(&addr).await.unwrap();
+    let wrong = Keypair::generate_ed25519().peer_id();
+    let err = upgrade(io, true, &client_key, Some(&wrong))
+        .await
+        .err()
+        .expect("upgrade should fail");
+    assert!(matches!(err, UpgradeError::Security(_)), "{err}");
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use common::{identity::Keypair, multiaddr::Multiaddr};
+use negotiation::Version;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use transport::{Listener, Muxed, Transport, TransportError, UnixTransport, upgrade};
+
+const PING: &str = "/ping/1.0.0";
+
+/// A fresh path under the temp dir for this test's socket.
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("p2p-{}-{name}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn unix_addr(path: &Path) -> Multiaddr {
+    format!("/unix{}", path.display()).parse().unwrap()
+}
+
+fn is_addr_in_use<T>(result: Result<T, TransportError>) -> bool {
+    matches!(result, Err(TransportError::Io(e)) if e.kind() == ErrorKind::AddrInUse)
+}
+
+#[tokio::test]
+async fn upgraded_connection_over_a_socket_file() {
+    let path = socket_path("upgrade");
+    let addr = unix_addr(&path);
+    let mut listener = UnixTransport::new().listen_on(&addr).await.unwrap();
+    assert_eq!(listener.local_addr(), addr);
+
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        assert_eq!(inbound.local_addr, unix_addr(&path));
+        let (_, muxed) = upgrade(inbound.stream, false, &Keypair::generate_ed25519(), None)
+            .await
+            .unwrap();
+        let Muxed::Yamux(mux) = muxed else {
+            panic!("expected yamux");
+        };
+        let substream = mux.accept_stream().await.unwrap();
+        let mut stream = substream.select_inbound(&[PING]).await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+        // keep the listener, and so the socket file, until the reply is out
+        drop(listener);
+    });
+
+    let io = UnixTransport::new().dial(&addr).await.unwrap();
+    let (_, muxed) = upgrade(io, true, &Keypair::generate_ed25519(), None)
+        .await
+        .unwrap();
+    let Muxed::Yamux(mux) = muxed else {
+        panic!("expected yamux");
+    };
+    let substream = mux.open_stream().await.unwrap();
+    let mut stream = substream
+        .select_outbound(&[PING], Version::V1Lazy)
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn stale_socket_is_replaced() {
+    let path = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists(), "binding leaves the socket file behind");
+
+    let mut listener = UnixTransport::new()
+        .listen_on(&unix_addr(&path))
+        .await
+        .unwrap();
+    UnixTransport::new().dial(&unix_addr(&path)).await.unwrap();
+    listener.accept().await.unwrap();
+
+    drop(listener);
+    assert!(!path.exists(), "the listener removes its socket file");
+}
+
+#[tokio::test]
+async fn live_socket_and_other_files_are_left_alone() {
+    let path = socket_path("live");
+    let _listener = UnixTransport::new()
+        .listen_on(&unix_addr(&path))
+        .await
+        .unwrap();
+    assert!(is_addr_in_use(
+        UnixTransport::new().listen_on(&unix_addr(&path)).await
+    ));
+    assert!(path.exists());
+
+    let path = socket_path("regular");
+    fs::write(&path, b"not a socket").unwrap();
+    assert!(is_addr_in_use(
+        UnixTransport::new().listen_on(&unix_addr(&path)).await
+    ));
+    assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn applies_socket_permissions() {
+    let path = socket_path("mode");
+    let _listener = UnixTransport::with_permissions(0o600)
+        .listen_on(&unix_addr(&path))
+        .await
+        .unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[tokio::test]
+async fn restricted_socket_is_bound_without_leftovers() {
+    let dir = std::env::temp_dir().join(format!("p2p-{}-staging", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let path = dir.join("node.sock");
+
+    let transport = UnixTransport::with_permissions(0o600);
+    let mut listener = transport.listen_on(&unix_addr(&path)).await.unwrap();
+    let entries: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["node.sock"]);
+
+    // It answers under its final name, not the one it was bound with.
+    let mut dialed = transport.dial(&unix_addr(&path)).await.unwrap();
+    let mut inbound = listener.accept().await.unwrap().stream;
+    dialed.write_all(b"hi").await.unwrap();
+    let mut buf = [0u8; 2];
+    inbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hi");
+
+    // A second listener on the same path is refused and leaves nothing behind.
+    assert!(is_addr_in_use(transport.listen_on(&unix_addr(&path)).await));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    drop(listener);
+    fs::remove_dir(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn rejects_other_addresses() {
+    let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+    assert!(matches!(
+        UnixTransport::new().dial(&addr).await,
+        Err(TransportError::UnsupportedAddress(_))
+    ));
+}
@@ -0,0 +1,178 @@
+use std::io::ErrorKind;
+
+use common::{
+    identity::Keypair,
+    multiaddr::{Multiaddr, Protocol},
+};
+use futures_util::{SinkExt, StreamExt};
+use negotiation::Version;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::{client_async, tungstenite::Message};
+use transport::{
+    Listener, Muxed, TcpTransport, Transport, TransportError, WS_HANDSHAKE_TIMEOUT, WsTransport,
+    upgrade,
+};
+
+const PING: &str = "/ping/1.0.0";
+
+fn any_port() -> Multiaddr {
+    "/ip4/127.0.0.1/tcp/0/ws".parse().unwrap()
+}
+
+/// The listener's address without `/ws`, for dialing it with plain TCP.
+fn tcp_part(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    assert_eq!(addr.pop(), Some(Protocol::Ws));
+    addr
+}
+
+#[tokio::test]
+async fn upgraded_connection_over_websocket() {
+    let server_key = Keypair::generate_ed25519();
+    let mut listener = WsTransport::<TcpTransport>::default()
+        .listen_on(&any_port())
+        .await
+        .unwrap();
+    let addr = listener
+        .local_addr()
+        .with(Protocol::P2p(server_key.peer_id()));
+
+    let server = tokio::spawn(async move {
+        let inbound = listener.accept().await.unwrap();
+        assert_eq!(inbound.local_addr.iter().last(), Some(&Protocol::Ws));
+        assert_eq!(inbound.remote_addr.iter().last(), Some(&Protocol::Ws));
+        let (_, muxed) = upgrade(inbound.stream, false, &server_key, None)
+            .await
+            .unwrap();
+        let Muxed::Yamux(mux) = muxed else {
+            panic!("expected yamux");
+        };
+        let substream = mux.accept_stream().await.unwrap();
+        let stream = substream.select_inbound(&[PING]).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+
+    let io = WsTransport::new(TcpTransport).dial(&addr).await.unwrap();
+    let (_, muxed) = upgrade(io, true, &Keypair::generate_ed25519(), addr.peer_id())
+        .await
+        .unwrap();
+    let Muxed::Yamux(mux) = muxed else {
+        panic!("expected yamux");
+    };
+    let substream = mux.open_stream().await.unwrap();
+    let stream = substream
+        .select_outbound(&[PING], Version::V1Lazy)
+        .await
+        .unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let msg: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let (_, echoed) = tokio::join!(
+        async {
+            writer.write_all(&msg).await.unwrap();
+            writer.shutdown().await.unwrap();
+        },
+        async {
+            let mut echoed = Vec::new();
+            reader.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        }
+    );
+    assert_eq!(echoed, msg);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn speaks_binary_messages_to_plain_clients() {
+    let mut listener = WsTransport::new(TcpTransport)
+        .listen_on(&any_port())
+        .await
+        .unwrap();
+    let tcp = TcpTransport
+        .dial(&tcp_part(&listener.local_addr()))
+        .await
+        .unwrap();
+    let (client, accepted) = tokio::join!(client_async("ws://localhost/", tcp), listener.accept());
+    let (mut client, _) = client.unwrap();
+    let mut stream = accepted.unwrap().stream;
+
+    // reads run across message boundaries
+    client.send(Message::binary(&b"hel"[..])).await.unwrap();
+    client
+        .send(Message::Ping(Default::default()))
+        .await
+        .unwrap();
+    client.send(Message::binary(&b"lo"[..])).await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    stream.write_all(b"back").await.unwrap();
+    stream.flush().await.unwrap();
+    let reply = loop {
+        match client.next().await.unwrap().unwrap() {
+            Message::Binary(data) => break data,
+            Message::Pong(_) => continue,
+            other => panic!("unexpected {other:?}"),
+        }
+    };
+    assert_eq!(&reply[..], b"back");
+
+    client.send(Message::text("nope")).await.unwrap();
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    client.close(None).await.unwrap();
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn failed_handshake_leaves_the_listener_usable() {
+    let mut listener = WsTransport::new(TcpTransport)
+        .listen_on(&any_port())
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+
+    let mut garbage = TcpTransport.dial(&tcp_part(&addr)).await.unwrap();
+    garbage.write_all(b"not http\r\n\r\n").await.unwrap();
+    assert!(listener.accept().await.is_err());
+
+    let ws = WsTransport::new(TcpTransport);
+    let (dialed, accepted) = tokio::join!(ws.dial(&addr), listener.accept());
+    dialed.unwrap();
+    accepted.unwrap();
+}
+
+#[tokio::test]
+async fn stalled_handshake_does_not_block_other_connections() {
+    let mut listener = WsTransport::new(TcpTransport)
+        .listen_on(&any_port())
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+
+    // Connected, but never sends the upgrade request.
+    let _silent = TcpTransport.dial(&tcp_part(&addr)).await.unwrap();
+    tokio::task::yield_now().await;
+
+    let ws = WsTransport::new(TcpTransport);
+    let accepted = tokio::time::timeout(WS_HANDSHAKE_TIMEOUT / 5, async {
+        let (dialed, accepted) = tokio::join!(ws.dial(&addr), listener.accept());
+        dialed.unwrap();
+        accepted.unwrap()
+    })
+    .await
+    .expect("accept waited on the silent connection");
+    assert_eq!(accepted.local_addr, addr);
+}
+
+#[tokio::test]
+async fn rejects_addresses_without_ws() {
+    let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+    assert!(matches!(
+        WsTransport::new(TcpTransport).dial(&addr).await,
+        Err(TransportError::UnsupportedAddress(_))
+    ));
+}